@@ -1,4 +1,4 @@
-use clap::{CommandFactory, ValueEnum};
+use clap::CommandFactory;
 use clap_complete::{generate_to, Shell};
 use std::cell::OnceCell;
 use std::env;
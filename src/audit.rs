@@ -0,0 +1,184 @@
+use redb::{Database, ReadableTable};
+use thiserror::Error;
+
+use crate::pkg::Installed;
+use crate::ALL_PKGS;
+
+/// Commands that access the network.
+const NETWORK_COMMANDS: [&str; 6] = ["curl", "wget", "nc", "ssh", "scp", "rsync"];
+/// Commands that download something.
+const DOWNLOAD_COMMANDS: [&str; 2] = ["curl", "wget"];
+/// Shells that execute their stdin.
+const SHELLS: [&str; 4] = ["sh", "bash", "zsh", "fish"];
+/// Commands whose last argument is written to.
+const WRITE_COMMANDS: [&str; 5] = ["cp", "mv", "install", "ln", "tee"];
+
+/// A potentially dangerous operation in a script.
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq)]
+pub enum Risk {
+    #[error("pipes a download into a shell")]
+    PipeToShell,
+    #[error("uses sudo")]
+    Sudo,
+    #[error("accesses the network")]
+    Network,
+    #[error("writes outside of ${{binary}}")]
+    WritesOutside,
+}
+
+/// A risk found in a script of an installed package.
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("{script} script of {package} {risk}")]
+pub struct Finding {
+    pub package: String,
+    pub script: &'static str,
+    pub risk: Risk,
+}
+
+/// Whether `path` is outside of the binaries directory.
+fn is_outside(path: &str) -> bool {
+    let path = path.trim_matches(['"', '\'']);
+    (path.starts_with('/') || path.starts_with('~')) && !path.starts_with("/dev/")
+}
+
+/// Scan a shell script for potentially dangerous operations using simple heuristics.
+pub fn scan_script(script: &str) -> Vec<Risk> {
+    let mut risks = Vec::new();
+    let mut add = |risk| {
+        if !risks.contains(&risk) {
+            risks.push(risk);
+        }
+    };
+
+    for line in script.split(['\n', ';', '&']) {
+        let mut previous: Option<&str> = None;
+        for stage in line.split('|') {
+            let mut words: Vec<&str> = stage.split_whitespace().collect();
+            if words.first() == Some(&"sudo") {
+                add(Risk::Sudo);
+                words.remove(0);
+            }
+            let Some(&command) = words.first() else {
+                previous = None;
+                continue;
+            };
+
+            if NETWORK_COMMANDS.contains(&command) {
+                add(Risk::Network);
+            }
+            if SHELLS.contains(&command)
+                && previous.is_some_and(|prev| DOWNLOAD_COMMANDS.contains(&prev))
+            {
+                add(Risk::PipeToShell);
+            }
+
+            for (i, word) in words.iter().enumerate() {
+                let target = match *word {
+                    ">" | ">>" => words.get(i + 1).copied(),
+                    word if word.starts_with('>') => Some(word.trim_start_matches('>')),
+                    _ => None,
+                };
+                if target.is_some_and(is_outside) {
+                    add(Risk::WritesOutside);
+                }
+            }
+            if WRITE_COMMANDS.contains(&command)
+                && words.len() > 1
+                && words.last().is_some_and(|last| is_outside(last))
+            {
+                add(Risk::WritesOutside);
+            }
+
+            previous = Some(command);
+        }
+    }
+
+    risks
+}
+
+/// Scan the scripts of all installed packages in `ALL_PKGS`.
+pub fn audit(db: &Database) -> Result<Vec<Finding>, redb::Error> {
+    let read_txn = db.begin_read()?;
+    let read_table = read_txn.open_table(ALL_PKGS)?;
+
+    let mut findings = Vec::new();
+    for pkg in read_table.iter()? {
+        let (key, value) = pkg?;
+        let pkg = value.value();
+        if pkg.installed == Installed::False {
+            continue;
+        }
+
+        for (script, content) in [
+            ("build", &pkg.build),
+            ("install", &pkg.install),
+            ("post_install", &pkg.post_install),
+            ("uninstall", &pkg.uninstall),
+        ] {
+            for risk in scan_script(content) {
+                findings.push(Finding {
+                    package: key.value().to_owned(),
+                    script,
+                    risk,
+                });
+            }
+        }
+    }
+
+    Ok(findings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Db;
+    use crate::tests::{test_db, test_package};
+
+    #[test]
+    fn test_scan_script() {
+        assert!(scan_script("mv ${source}/topgrade ${binary}").is_empty());
+        assert!(scan_script("make > /dev/null").is_empty());
+        assert_eq!(scan_script("sudo make install"), [Risk::Sudo]);
+        assert_eq!(
+            scan_script("cp ${source}/foo ~/.local/bin && echo done >> /etc/foo"),
+            [Risk::WritesOutside]
+        );
+    }
+
+    #[test]
+    fn test_audit() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = test_db(tmpdir.path());
+
+        for (name, install) in [
+            ("safe", "mv ${source}/safe ${binary}"),
+            ("unsafe", "curl -sSf https://example.com/install.sh | sh"),
+        ] {
+            let mut pkg = test_package(name, "1.0.0");
+            pkg.source.install = install.to_owned();
+            pkg.local.installed = Installed::Manually(pkg.info.version.clone());
+            db.set(ALL_PKGS, name, pkg).unwrap();
+        }
+
+        let findings = audit(&db).unwrap();
+        assert_eq!(
+            findings,
+            [
+                Finding {
+                    package: "unsafe".to_owned(),
+                    script: "install",
+                    risk: Risk::Network,
+                },
+                Finding {
+                    package: "unsafe".to_owned(),
+                    script: "install",
+                    risk: Risk::PipeToShell,
+                },
+            ]
+        );
+        assert_eq!(
+            findings[1].to_string(),
+            "install script of unsafe pipes a download into a shell"
+        );
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use redb::{Database, ReadableTable};
+
+use crate::config::Config;
+use crate::pkg::Package;
+use crate::{DynResult, ALL_PKGS, INSTALLED_PKGS};
+
+/// Get all entries of the directory `path`. A missing directory has no entries.
+fn entries(path: impl AsRef<Path>) -> io::Result<Vec<PathBuf>> {
+    if !path.as_ref().exists() {
+        return Ok(Vec::new());
+    }
+
+    fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect()
+}
+
+/// Which caches to act on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Caches {
+    /// Downloaded tarballs in the sources directory.
+    pub sources: bool,
+    /// Decompressed and built packages in the builds directory.
+    pub builds: bool,
+}
+
+impl Caches {
+    pub const ALL: Self = Self {
+        sources: true,
+        builds: true,
+    };
+
+    /// The directories of the selected caches.
+    fn dirs<'a>(&self, conf: &'a Config) -> Vec<&'a Path> {
+        let mut dirs = Vec::new();
+        if self.sources {
+            dirs.push(conf.sources_path());
+        }
+        if self.builds {
+            dirs.push(conf.builds_path());
+        }
+        dirs
+    }
+}
+
+/// Get all cached tarballs and builds of the selected `kinds`.
+pub fn caches(conf: &Config, kinds: Caches) -> io::Result<Vec<PathBuf>> {
+    let mut caches = Vec::new();
+    for dir in kinds.dirs(conf) {
+        caches.extend(entries(dir)?);
+    }
+    caches.sort();
+    Ok(caches)
+}
+
+/// Recreate the directories of the selected `kinds`, so that installing doesn't fail if they were removed.
+pub fn recreate_dirs(conf: &Config, kinds: Caches) -> io::Result<()> {
+    for dir in kinds.dirs(conf) {
+        fs::create_dir_all(dir)?;
+    }
+    Ok(())
+}
+
+/// Get the cached tarballs and builds of the selected `kinds` that belong neither to the current version of any
+/// package in `ALL_PKGS` nor to an installed version, which may be older if it is pinned or downgraded.
+pub fn orphaned_caches(db: &Database, conf: &Config, kinds: Caches) -> DynResult<Vec<PathBuf>> {
+    let read_txn = db.begin_read()?;
+
+    let mut current = HashSet::new();
+    for table in [ALL_PKGS, INSTALLED_PKGS] {
+        for pkg in read_txn.open_table(table)?.iter()? {
+            let pkg = Package::try_from(pkg?.1.value())?;
+            current.insert(conf.tarball_path(&pkg.info));
+            current.insert(conf.build_path(&pkg.info));
+            current.insert(conf.signature_path(&pkg.info));
+            for (index, patch) in pkg.source.patches.iter().flatten().enumerate() {
+                current.insert(conf.patch_path(&pkg.info, index, patch));
+            }
+        }
+    }
+
+    Ok(caches(conf, kinds)?
+        .into_iter()
+        .filter(|path| !current.contains(path))
+        .collect())
+}
+
+/// Get the size of the file or directory at `path` in bytes.
+fn size(path: impl AsRef<Path>) -> io::Result<u64> {
+    let metadata = fs::symlink_metadata(&path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+
+    let mut size = 0;
+    for entry in entries(path)? {
+        size += self::size(entry)?;
+    }
+    Ok(size)
+}
+
+/// Remove the files and directories `paths`. Returns the reclaimed space in bytes.
+/// Symlinks are removed themselves, never their targets.
+pub fn remove(paths: &[PathBuf]) -> io::Result<u64> {
+    let mut reclaimed = 0;
+    for path in paths {
+        reclaimed += size(path)?;
+        if fs::symlink_metadata(path)?.is_dir() {
+            fs::remove_dir_all(path)?;
+        } else {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(reclaimed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Db;
+    use crate::pkg::Installed;
+    use crate::tests::{test_config, test_db, test_package};
+
+    #[test]
+    fn test_orphaned_caches() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let conf = test_config(tmpdir.path());
+        let db = test_db(tmpdir.path());
+
+        let old = test_package("foo", "1.0.0");
+        let current = test_package("foo", "2.0.0");
+        db.set(ALL_PKGS, "foo", current.clone()).unwrap();
+        // An older version is installed and pinned.
+        let mut installed = test_package("foo", "1.5.0");
+        installed.local.installed = Installed::Manually(installed.info.version.clone());
+        installed.local.pinned = true;
+        db.set(INSTALLED_PKGS, "foo", installed.clone()).unwrap();
+
+        fs::create_dir_all(conf.sources_path()).unwrap();
+        fs::create_dir_all(conf.build_path(&installed.info)).unwrap();
+        fs::write(conf.tarball_path(&old.info), "old").unwrap();
+        fs::write(conf.tarball_path(&installed.info), "installed").unwrap();
+        fs::write(conf.tarball_path(&current.info), "current").unwrap();
+
+        let orphaned = orphaned_caches(&db, &conf, Caches::ALL).unwrap();
+        assert_eq!(orphaned, [conf.tarball_path(&old.info)]);
+
+        assert_eq!(remove(&orphaned).unwrap(), 3);
+        assert!(!conf.tarball_path(&old.info).exists());
+        assert!(conf.tarball_path(&installed.info).exists());
+        assert!(conf.build_path(&installed.info).exists());
+        assert!(conf.tarball_path(&current.info).exists());
+    }
+
+    #[test]
+    fn test_clean_kinds() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let conf = test_config(tmpdir.path());
+        let tarball = conf.sources_path().join("foo_1.0.0.tar.gz");
+        let build = conf.builds_path().join("foo_1.0.0");
+        fs::create_dir_all(&build).unwrap();
+        fs::create_dir_all(conf.sources_path()).unwrap();
+        fs::write(&tarball, "tarball").unwrap();
+        fs::write(build.join("foo"), "binary").unwrap();
+
+        let sources = Caches {
+            sources: true,
+            builds: false,
+        };
+        assert_eq!(caches(&conf, sources).unwrap(), [tarball.clone()]);
+        assert_eq!(
+            caches(&conf, Caches::ALL).unwrap(),
+            [build.clone(), tarball]
+        );
+
+        // Only the contents of the directories are removed.
+        let all = caches(&conf, Caches::ALL).unwrap();
+        assert_eq!(remove(&all).unwrap(), 13);
+        assert!(caches(&conf, Caches::ALL).unwrap().is_empty());
+
+        fs::remove_dir(conf.builds_path()).unwrap();
+        recreate_dirs(&conf, Caches::ALL).unwrap();
+        assert!(conf.builds_path().is_dir());
+    }
+}
@@ -1,6 +1,6 @@
 use std::path::PathBuf;
 
-use clap::{Args, Parser, Subcommand};
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -12,10 +12,49 @@ pub struct Cli {
     #[cfg(debug_assertions)]
     #[arg(short, long)]
     pub debug: bool,
+    /// Print more log messages (-v for info, -vv for debug, -vvv for trace)
+    #[arg(short, long, action = ArgAction::Count)]
+    pub verbose: u8,
+    /// When to use colored output
+    #[arg(long, value_enum, default_value_t = ColorMode::Auto)]
+    pub color: ColorMode,
+    /// Never use colored output (same as `--color never`)
+    #[arg(long, conflicts_with = "color")]
+    pub no_color: bool,
+    /// Output format of `list` and `search`
+    #[arg(long, value_enum, default_value_t = OutputFormat::Plain)]
+    pub format: OutputFormat,
+    /// Check the database for consistency before running the command
+    #[arg(long)]
+    pub check_integrity: bool,
+    /// Answer all questions with yes (required if stdin isn't interactive)
+    #[arg(short, long, visible_alias = "no-confirm")]
+    pub yes: bool,
+    /// Don't print status messages and progress bars
+    #[arg(long)]
+    pub quiet: bool,
     #[command(subcommand)]
     pub command: Commands,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ColorMode {
+    /// Color if the output is a terminal
+    Auto,
+    /// Always color
+    Always,
+    /// Never color
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable lines
+    Plain,
+    /// JSON for scripts
+    Json,
+}
+
 #[derive(Subcommand)]
 pub enum Commands {
     /// Install a package
@@ -24,40 +63,152 @@ pub enum Commands {
     Add(AddArgs),
     /// Remove a package
     Remove(RemoveArgs),
+    /// Remove automatically installed packages no installed package depends on anymore
+    Autoremove,
     /// Update all packages
     Update(UpdateArgs),
+    /// List installed packages with a newer version available
+    Outdated(OutdatedArgs),
+    /// Download, build and install installed packages again, even if they are up-to-date
+    Reinstall(ReinstallArgs),
+    /// Install an older version of an installed package
+    Downgrade(DowngradeArgs),
+    /// Pin installed packages so that `update` skips them
+    Pin(PinArgs),
+    /// Allow updating pinned packages again
+    Unpin(PinArgs),
     /// Search for a package
     Search(SearchArgs),
     /// List installed packages
     List(ListArgs),
+    /// Show the details of a package
+    Info(InfoArgs),
+    /// List the packages depending on a package
+    Rdepends(RdependsArgs),
+    /// Check the database for problems
+    Doctor(DoctorArgs),
+    /// List installed packages whose scripts contain potentially dangerous operations
+    Audit,
+    /// Check a pkgfile for mistakes
+    Lint(LintArgs),
+    /// Write the manually installed and added packages to a manifest (JSON if the file ends in `.json`, TOML otherwise)
+    Export(ExportArgs),
+    /// Install all packages listed in a manifest written by `export`, or a list of package names
+    Import(ImportArgs),
+    /// Remove cached sources and builds
+    Clean(CleanArgs),
+    /// Print or install shell completions
+    Completions(CompletionsArgs),
     #[cfg(debug_assertions)]
     Config,
 }
 
 #[derive(Args)]
 pub struct InstallArgs {
-    /// Name of the pkgs
+    /// Name of the pkgs, optionally with a version (e.g. `foo@1.2.0`) or version requirement (e.g. `foo@>=1.2, <2`),
+    /// or paths to local pkgfiles (existing files or prefixed with `file:`)
     pub pkgs: Vec<String>,
-    /// Use local pkgfiles
+    /// Treat all arguments as local pkgfiles
     #[arg(short, long)]
     pub local: bool,
+    /// Fail if an install script doesn't install any files
+    #[arg(long)]
+    pub strict: bool,
+    /// Trust the sources of all packages without asking (`--yes` doesn't). They aren't recorded as trusted
+    #[arg(long)]
+    pub trust: bool,
+    /// Skip the build step, even for packages with a build command (for debugging)
+    #[arg(long)]
+    pub no_build: bool,
+    /// Ask which package to install if several provide a dependency
+    #[arg(short, long)]
+    pub interactive: bool,
+    /// Print the build and install commands that would run instead of installing
+    #[arg(long)]
+    pub print_script: bool,
+    /// Overwrite existing files (also ones installed by other packages) instead of aborting
+    #[arg(long)]
+    pub overwrite: bool,
+    /// Only install the dependencies of the packages, not the packages themselves
+    #[arg(long)]
+    pub only_dependencies: bool,
+    /// Number of jobs build commands should use, exported as `$jobs` (defaults to the number of CPUs)
+    #[arg(short, long, value_name = "N")]
+    pub jobs: Option<usize>,
+    #[command(flatten)]
+    pub download: DownloadArgs,
 }
 
 #[derive(Args)]
 pub struct AddArgs {
     /// Path of the pkgfiles.
     pub pkgs: Vec<PathBuf>,
+    /// Register existing binaries (or directories of binaries) as installed packages instead
+    #[arg(short, long)]
+    pub register: bool,
+    /// Version to record for registered binaries
+    #[arg(long, default_value = "0.0.0", requires = "register")]
+    pub pkg_version: String,
+    /// Also install the packages
+    #[arg(short, long, conflicts_with = "register")]
+    pub install: bool,
 }
 
 #[derive(Args)]
 pub struct RemoveArgs {
     /// Name of the packages
     pub pkgs: Vec<String>,
+    /// Remove every trace of the packages: config files, cached sources and builds, and the database entry
+    #[arg(long)]
+    pub purge: bool,
+    /// Remove the packages even if installed packages depend on them
+    #[arg(short, long)]
+    pub force: bool,
 }
 #[derive(Args)]
 pub struct UpdateArgs {
     /// Name of the packages
     pub pkgs: Option<Vec<String>>,
+    /// Only update packages whose available version changed since the last update
+    #[arg(short, long)]
+    pub incremental: bool,
+    #[command(flatten)]
+    pub download: DownloadArgs,
+}
+
+#[derive(Args)]
+pub struct OutdatedArgs {
+    /// Name of the packages
+    pub pkgs: Option<Vec<String>>,
+    /// Only list packages whose available version changed since the last update
+    #[arg(short, long)]
+    pub incremental: bool,
+}
+
+#[derive(Args)]
+pub struct ReinstallArgs {
+    /// Name of the packages
+    #[arg(required = true)]
+    pub pkgs: Vec<String>,
+    #[command(flatten)]
+    pub download: DownloadArgs,
+}
+
+#[derive(Args)]
+pub struct DowngradeArgs {
+    /// Name of the package
+    pub pkg: String,
+    /// Version to install, has to be older than the installed one
+    pub version: String,
+    #[command(flatten)]
+    pub download: DownloadArgs,
+}
+
+#[derive(Args)]
+pub struct PinArgs {
+    /// Name of the packages
+    #[arg(required = true)]
+    pub pkgs: Vec<String>,
 }
 
 #[derive(Args)]
@@ -67,6 +218,112 @@ pub struct SearchArgs {
     /// Only search installed packages
     #[arg(short, long)]
     pub installed: bool,
+    /// Search the descriptions instead of the names
+    #[arg(short, long)]
+    pub description_only: bool,
+    /// How to sort the results
+    #[arg(short, long, value_enum, default_value_t = SearchSort::Score)]
+    pub sort: SearchSort,
+    /// Only print the names of the matching packages
+    #[arg(short = 'q', long)]
+    pub names_only: bool,
+    /// Also search the descriptions and provided packages, not just the names
+    #[arg(long, conflicts_with = "description_only")]
+    pub search_description: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SearchSort {
+    /// Sort by match score (best match last)
+    Score,
+    /// Sort alphabetically by name
+    Name,
+    /// Sort by version
+    Version,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ListSort {
+    /// Sort alphabetically by name
+    Name,
+    /// Sort by version (oldest first)
+    Version,
+    /// Sort by when the packages were installed (most recent last)
+    Date,
+}
+
+#[derive(Args)]
+pub struct DoctorArgs {
+    /// Repair packages whose installation state is inconsistent
+    #[arg(short, long)]
+    pub repair: bool,
+}
+
+#[derive(Args)]
+pub struct LintArgs {
+    /// Path of the pkgfile
+    pub file: PathBuf,
+}
+
+#[derive(Args)]
+pub struct ExportArgs {
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+#[derive(Args)]
+pub struct ImportArgs {
+    /// Path of the manifest
+    pub file: PathBuf,
+    #[command(flatten)]
+    pub download: DownloadArgs,
+}
+
+#[derive(Args)]
+pub struct CleanArgs {
+    /// Only remove caches of versions no package refers to anymore
+    #[arg(short, long)]
+    pub orphaned_sources: bool,
+    /// Remove the downloaded sources
+    #[arg(short, long)]
+    pub sources: bool,
+    /// Remove the builds
+    #[arg(short, long)]
+    pub builds: bool,
+    /// Remove the sources and builds (the default)
+    #[arg(short, long, conflicts_with_all = ["sources", "builds"])]
+    pub all: bool,
+}
+
+#[derive(Args)]
+pub struct CompletionsArgs {
+    /// Shell to generate the completions for (detected from `SHELL` if omitted)
+    #[arg(value_enum)]
+    pub shell: Option<clap_complete::Shell>,
+    /// Install (or refresh) the completions in the shell's completion directory instead of printing them
+    #[arg(short, long)]
+    pub install: bool,
+    /// Directory to install the completions to
+    #[arg(short, long, requires = "install")]
+    pub dir: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct InfoArgs {
+    /// Name of the package
+    pub pkg: String,
+    /// List the files installed by the package instead
+    #[arg(long)]
+    pub installed_files: bool,
+}
+
+#[derive(Args)]
+pub struct RdependsArgs {
+    /// Name of the package
+    pub pkg: String,
+    /// Also list packages that aren't installed
+    #[arg(short, long)]
+    pub all: bool,
 }
 
 #[derive(Args)]
@@ -74,4 +331,37 @@ pub struct ListArgs {
     /// List all packages (whether installed or not)
     #[arg(short, long)]
     pub all: bool,
+    /// Print the packages as JSON (same as `--format json`)
+    #[arg(long)]
+    pub json: bool,
+    /// How to sort the packages. Sorting by date also shows when they were installed
+    #[arg(short, long, value_enum, default_value_t = ListSort::Name)]
+    pub sort: ListSort,
+    /// Sort the packages by when they were installed and show the dates (same as `--sort date`)
+    #[arg(long, conflicts_with = "sort")]
+    pub sort_by_date: bool,
+    /// Only list the automatically installed packages `autoremove` would remove
+    #[arg(long, conflicts_with = "all")]
+    pub orphans: bool,
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+/// Shared options for commands that download packages. They take precedence over the config.
+#[derive(Args)]
+pub struct DownloadArgs {
+    /// Seconds after which a download attempt is aborted
+    #[arg(long, value_name = "SECS")]
+    pub timeout: Option<u64>,
+    /// How often a failed download is retried
+    #[arg(long, value_name = "N")]
+    pub retries: Option<usize>,
+}
+
+/// Shared option for commands that dump data.
+#[derive(Args)]
+pub struct OutputArgs {
+    /// Write to a file instead of stdout
+    #[arg(short, long, value_name = "FILE")]
+    pub output: Option<PathBuf>,
 }
@@ -0,0 +1,79 @@
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use clap::CommandFactory;
+use clap_complete::{generate, generate_to, Shell};
+use directories::BaseDirs;
+
+use crate::cli::Cli;
+
+/// Derive the shell from the path of its executable, e.g. the value of `SHELL`.
+pub fn shell_from_path(path: impl AsRef<Path>) -> Option<Shell> {
+    let name = path.as_ref().file_stem().and_then(OsStr::to_str)?;
+    match name {
+        "bash" => Some(Shell::Bash),
+        "elvish" => Some(Shell::Elvish),
+        "fish" => Some(Shell::Fish),
+        "powershell" | "powershell_ise" | "pwsh" => Some(Shell::PowerShell),
+        "zsh" => Some(Shell::Zsh),
+        _ => None,
+    }
+}
+
+/// Detect the invoking shell from the `SHELL` environment variable.
+pub fn detect_shell() -> Option<Shell> {
+    shell_from_path(env::var_os("SHELL")?)
+}
+
+/// Get the directory the completions for `shell` are loaded from for the current user.
+/// Returns `None` if the shell doesn't have a standard completion directory.
+pub fn completion_dir(shell: Shell) -> Option<PathBuf> {
+    let dirs = BaseDirs::new()?;
+    match shell {
+        Shell::Bash => Some(dirs.data_dir().join("bash-completion").join("completions")),
+        Shell::Fish => Some(dirs.config_dir().join("fish").join("completions")),
+        Shell::Zsh => Some(dirs.home_dir().join(".zfunc")),
+        Shell::Elvish => Some(dirs.config_dir().join("elvish").join("lib")),
+        _ => None,
+    }
+}
+
+/// Print the completions for `shell` to stdout.
+pub fn print_completions(shell: Shell) {
+    generate(shell, &mut Cli::command(), "mercurium", &mut io::stdout());
+}
+
+/// Write the completions for `shell` into `dir`, replacing existing ones.
+/// Returns the path of the written file.
+pub fn install_completions(shell: Shell, dir: impl AsRef<Path>) -> io::Result<PathBuf> {
+    fs::create_dir_all(&dir)?;
+    generate_to(shell, &mut Cli::command(), "mercurium", dir.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_from_path() {
+        assert_eq!(shell_from_path("/bin/bash"), Some(Shell::Bash));
+        assert_eq!(shell_from_path("/usr/bin/zsh"), Some(Shell::Zsh));
+        assert_eq!(shell_from_path("/usr/local/bin/fish"), Some(Shell::Fish));
+        assert_eq!(shell_from_path("pwsh.exe"), Some(Shell::PowerShell));
+        assert_eq!(shell_from_path("/bin/sh"), None);
+        assert_eq!(shell_from_path(""), None);
+    }
+
+    #[test]
+    fn test_install_completions() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let dir = tmpdir.path().join("completions");
+
+        let path = install_completions(Shell::Fish, &dir).unwrap();
+        assert_eq!(path, dir.join("mercurium.fish"));
+        assert!(fs::read_to_string(path).unwrap().contains("mercurium"));
+    }
+}
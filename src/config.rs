@@ -1,15 +1,66 @@
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
-use std::{fs, io};
+use std::time::Duration;
+use std::{env, fs, io, thread};
 
 use directories::{BaseDirs, ProjectDirs};
 use log::{error, info};
 use serde::Deserialize;
 
+use crate::pkg::{PackageInfo, Source};
+
 /// The configuration.
-#[derive(Clone, Debug, Default, Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 pub struct Config {
     /// The different directories to act on.
     pub directories: ConfigDirs,
+    /// Environment variables passed to all build and install commands.
+    /// They override the inherited environment and are overridden by the `env` of a package.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// URL prefixes of sources whose packages are trusted without asking. They match whole path segments,
+    /// so `https://github.com/foo` covers `https://github.com/foo/bar`, but not `https://github.com/foobar`.
+    #[serde(default)]
+    pub trusted: Vec<String>,
+    /// Names of packages `update` skips unless they are named explicitly. `*` matches any characters, e.g. `linux-*`.
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    /// Maximum number of packages built at the same time, also exported as `$jobs` to build commands.
+    /// Defaults to the number of CPUs.
+    pub jobs: Option<usize>,
+    /// Create missing directories. If disabled, they have to exist already.
+    #[serde(default = "default_create_dirs")]
+    pub create_dirs: bool,
+    /// Seconds after which a download attempt is aborted and retried. Downloads never time out by default.
+    #[serde(alias = "download_timeout_secs")]
+    pub timeout: Option<u64>,
+    /// Abort the installation if a post-install command fails instead of only printing a warning.
+    #[serde(default)]
+    pub strict_post_install: bool,
+    /// How often a failed download is retried before trying the next mirror. Defaults to 2.
+    pub retries: Option<usize>,
+    /// Proxy for HTTP downloads. Defaults to the `http_proxy` environment variable.
+    pub http_proxy: Option<String>,
+    /// Proxy for HTTPS downloads. Defaults to the `https_proxy` environment variable.
+    pub https_proxy: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            directories: ConfigDirs::default(),
+            env: BTreeMap::new(),
+            trusted: Vec::new(),
+            ignore: Vec::new(),
+            jobs: None,
+            create_dirs: default_create_dirs(),
+            timeout: None,
+            strict_post_install: false,
+            retries: None,
+            http_proxy: None,
+            https_proxy: None,
+        }
+    }
 }
 
 impl Config {
@@ -31,14 +82,64 @@ impl Config {
             Config::default()
         };
 
-        fs::create_dir_all(out.sources_path())?;
-        fs::create_dir_all(out.builds_path())?;
-        fs::create_dir_all(out.binaries_path())?;
-        fs::create_dir_all(out.packages_path())?;
+        for dir in [
+            out.sources_path(),
+            out.builds_path(),
+            out.binaries_path(),
+            out.packages_path(),
+        ] {
+            if out.create_dirs {
+                fs::create_dir_all(dir)?;
+            } else if !dir.is_dir() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("directory {} doesn't exist", dir.to_string_lossy()),
+                ));
+            }
+        }
 
         Ok(out)
     }
 
+    /// Maximum number of packages built at the same time.
+    pub fn jobs(&self) -> usize {
+        self.jobs
+            .or_else(|| thread::available_parallelism().ok().map(usize::from))
+            .unwrap_or(1)
+            .max(1)
+    }
+
+    /// Whether the package `name` matches a pattern of `ignore`.
+    pub fn ignores(&self, name: &str) -> bool {
+        self.ignore
+            .iter()
+            .any(|pattern| wildcard_matches(pattern, name))
+    }
+
+    /// Time after which a download attempt is aborted.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout.map(Duration::from_secs)
+    }
+
+    /// How often a failed download is retried.
+    pub fn retries(&self) -> usize {
+        self.retries.unwrap_or(2)
+    }
+
+    /// Proxy for HTTP downloads.
+    pub fn http_proxy(&self) -> Option<String> {
+        self.http_proxy
+            .clone()
+            .or_else(|| env_proxy(&["http_proxy", "HTTP_PROXY"]))
+    }
+
+    /// Proxy for HTTPS downloads.
+    pub fn https_proxy(&self) -> Option<String> {
+        self.https_proxy
+            .clone()
+            .or_else(|| env_proxy(&["https_proxy", "HTTPS_PROXY"]))
+    }
+
     /// Path to download source files to.
     pub fn sources_path(&self) -> &Path {
         &self.directories.sources
@@ -58,6 +159,46 @@ impl Config {
     pub fn packages_path(&self) -> &Path {
         &self.directories.packages
     }
+
+    /// Path of the lockfile recording the installed packages.
+    pub fn lockfile_path(&self) -> PathBuf {
+        self.packages_path().join("mercurium.lock")
+    }
+
+    /// Path the source tarball of a package is downloaded to.
+    pub fn tarball_path(&self, info: &PackageInfo) -> PathBuf {
+        self.sources_path()
+            .join(format!("{}_{}.tar.gz", info.name, info.version))
+    }
+
+    /// Path of the downloaded detached signature of a package's tarball.
+    pub fn signature_path(&self, info: &PackageInfo) -> PathBuf {
+        self.sources_path()
+            .join(format!("{}_{}.tar.gz.minisig", info.name, info.version))
+    }
+
+    /// Path the install command of a package installs to, before the files are moved to the binaries.
+    pub fn staging_path(&self, info: &PackageInfo) -> PathBuf {
+        self.builds_path()
+            .join(format!("{}_{}.staging", info.name, info.version))
+    }
+
+    /// Path of the `index`-th patch `patch` of a package.
+    /// Remote patches are downloaded next to the tarball, local ones are used in place.
+    pub fn patch_path(&self, info: &PackageInfo, index: usize, patch: &str) -> PathBuf {
+        if Source::is_remote(patch) {
+            self.sources_path()
+                .join(format!("{}_{}_{index}.patch", info.name, info.version))
+        } else {
+            PathBuf::from(patch)
+        }
+    }
+
+    /// Path the source of a package is decompressed to and built in.
+    pub fn build_path(&self, info: &PackageInfo) -> PathBuf {
+        self.builds_path()
+            .join(format!("{}_{}", info.name, info.version))
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -83,6 +224,38 @@ impl Default for ConfigDirs {
     }
 }
 
+/// Whether `name` matches `pattern`, in which `*` matches any (possibly empty) sequence of characters.
+fn wildcard_matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // No `*` in the pattern.
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// Get the first non-empty of the environment variables `vars`.
+fn env_proxy(vars: &[&str]) -> Option<String> {
+    vars.iter()
+        .filter_map(|var| env::var(var).ok())
+        .find(|proxy| !proxy.is_empty())
+}
+
+fn default_create_dirs() -> bool {
+    true
+}
+
 fn default_sources() -> PathBuf {
     let dir = ProjectDirs::from("de", "mercurium", "mercurium")
         .unwrap()
@@ -134,9 +307,88 @@ mod tests {
             builds = \"/Users/chrobin/Documents/rust/mercurium/tests/builds\"
             binaries = \"/Users/chrobin/Documents/rust/mercurium/tests/binaries\"
             packages = \"/Users/chrobin/Documents/rust/mercurium/tests\"
+
+            [env]
+            PREFIX = \"/opt\"
         ";
 
         let conf: Config = toml::from_str(conf).unwrap();
+        assert_eq!(conf.env["PREFIX"], "/opt");
+        assert!(conf.create_dirs);
         dbg!(conf);
     }
+
+    #[test]
+    fn test_ignores() {
+        let conf = Config {
+            ignore: vec!["linux-*".to_owned(), "foo".to_owned(), "*-git*".to_owned()],
+            ..Default::default()
+        };
+        assert!(conf.ignores("linux-lts"));
+        assert!(conf.ignores("linux-"));
+        assert!(!conf.ignores("linux"));
+        assert!(conf.ignores("foo"));
+        assert!(!conf.ignores("foobar"));
+        assert!(conf.ignores("bar-git"));
+        assert!(conf.ignores("bar-git-nightly"));
+        assert!(!conf.ignores("bar"));
+
+        assert!(wildcard_matches("a*b*c", "abc"));
+        assert!(wildcard_matches("a*b*c", "axxbyyc"));
+        assert!(!wildcard_matches("a*bc", "abc-bc-x"));
+        assert!(!wildcard_matches("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn test_proxy() {
+        env::set_var("MERCURIUM_TEST_PROXY_EMPTY", "");
+        env::set_var("MERCURIUM_TEST_PROXY", "http://proxy.example.com:3128");
+        assert_eq!(
+            env_proxy(&["MERCURIUM_TEST_PROXY_EMPTY", "MERCURIUM_TEST_PROXY"]).as_deref(),
+            Some("http://proxy.example.com:3128")
+        );
+        assert_eq!(env_proxy(&["MERCURIUM_TEST_PROXY_UNSET"]), None);
+
+        let conf: Config = toml::from_str(
+            "
+            https_proxy = \"http://configured.example.com:3128\"
+
+            [directories]
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            conf.https_proxy().as_deref(),
+            Some("http://configured.example.com:3128")
+        );
+    }
+
+    #[test]
+    fn load_config_without_create_dirs() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let dir = tmpdir.path().to_string_lossy();
+        let path = tmpdir.path().join("config.toml");
+        fs::write(
+            &path,
+            format!(
+                "
+                create_dirs = false
+
+                [directories]
+                sources = \"{dir}\"
+                builds = \"{dir}\"
+                binaries = \"{dir}/~/bin\"
+                packages = \"{dir}\"
+                "
+            ),
+        )
+        .unwrap();
+
+        let err = Config::load(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(!tmpdir.path().join("~").exists());
+
+        fs::create_dir_all(tmpdir.path().join("~").join("bin")).unwrap();
+        assert!(Config::load(&path).is_ok());
+    }
 }
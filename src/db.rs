@@ -1,10 +1,18 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Display;
+use std::hash::Hash;
+use std::path::PathBuf;
 use std::str::FromStr;
 
-use redb::{Database, Range, ReadableTable, RedbValue, TableDefinition};
+use log::info;
+use redb::{
+    Database, Range, ReadableTable, RedbValue, TableDefinition, TypeName, WriteTransaction,
+};
 use semver::Version;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
-use crate::pkg::{Installed, Local, Package, PackageInfo, Source};
+use crate::pkg::{Installed, Local, Package, PackageInfo, Source, Vcs};
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize, RedbValue)]
 pub struct DbPackage {
@@ -16,13 +24,46 @@ pub struct DbPackage {
     pub description: String,
     pub dependencies: Vec<String>,
     pub build_dependencies: Vec<String>,
-    pub provides: String,
+    pub provides: Vec<String>,
     pub url: String,
+    pub vcs: Option<Vcs>,
+    pub rev: String,
+    pub mirrors: Vec<String>,
     pub checksum: String,
+    pub signature: String,
+    pub signing_key: String,
+    pub strip_components: Option<usize>,
+    pub patches: Vec<String>,
+    pub prepare: String,
     pub build: String,
     pub install: String,
+    pub post_install: String,
+    pub uninstall: String,
+    pub executable: Vec<String>,
+    pub config_files: Vec<String>,
+    pub env: BTreeMap<String, String>,
     pub installed: Installed,
     pub added: bool,
+    pub files: Vec<String>,
+    pub last_installed: Installed,
+    pub installed_at: Option<i64>,
+    pub pinned: bool,
+}
+
+/// A row of the database with a version that can't be parsed, e.g. because it was edited by hand.
+#[derive(Debug, Error)]
+#[error("package {name} has the invalid version {version}: {source}")]
+pub struct InvalidVersion {
+    pub name: String,
+    pub version: String,
+    pub source: semver::Error,
+}
+
+/// Invalid rows are reported like other database corruption.
+impl From<InvalidVersion> for redb::Error {
+    fn from(value: InvalidVersion) -> Self {
+        redb::Error::Corrupted(value.to_string())
+    }
 }
 
 fn string_to_option(container: String) -> Option<String> {
@@ -41,8 +82,18 @@ fn vec_to_option<T>(container: Vec<T>) -> Option<Vec<T>> {
     }
 }
 
-impl From<DbPackage> for Package {
-    fn from(value: DbPackage) -> Self {
+fn map_to_option<K, V>(container: BTreeMap<K, V>) -> Option<BTreeMap<K, V>> {
+    if container.is_empty() {
+        None
+    } else {
+        Some(container)
+    }
+}
+
+impl TryFrom<DbPackage> for Package {
+    type Error = InvalidVersion;
+
+    fn try_from(value: DbPackage) -> Result<Self, Self::Error> {
         let DbPackage {
             name,
             version,
@@ -54,24 +105,62 @@ impl From<DbPackage> for Package {
             build_dependencies,
             provides,
             url,
+            vcs,
+            rev,
+            mirrors,
             checksum,
+            signature,
+            signing_key,
+            strip_components,
+            patches,
+            prepare,
             build,
             install,
+            post_install,
+            uninstall,
+            executable,
+            config_files,
+            env,
             installed,
             added,
+            files,
+            last_installed,
+            installed_at,
+            pinned,
         } = value;
 
-        let version = Version::from_str(&version).expect("invalid version forma");
+        let version = match Version::from_str(&version) {
+            Ok(parsed) => parsed,
+            Err(source) => {
+                return Err(InvalidVersion {
+                    name,
+                    version,
+                    source,
+                })
+            }
+        };
         let repository = string_to_option(repository);
         let authors = vec_to_option(authors);
         let description = string_to_option(description);
         let dependencies = vec_to_option(dependencies);
         let build_dependencies = vec_to_option(build_dependencies);
-        let provides = string_to_option(provides);
+        let provides = vec_to_option(provides);
+        let rev = string_to_option(rev);
+        let mirrors = vec_to_option(mirrors);
         let checksum = string_to_option(checksum);
+        let signature = string_to_option(signature);
+        let signing_key = string_to_option(signing_key);
+        let patches = vec_to_option(patches);
+        let prepare = string_to_option(prepare);
         let build = string_to_option(build);
-
-        Self {
+        let post_install = string_to_option(post_install);
+        let uninstall = string_to_option(uninstall);
+        let executable = vec_to_option(executable);
+        let config_files = vec_to_option(config_files);
+        let env = map_to_option(env);
+        let files = files.into_iter().map(PathBuf::from).collect();
+
+        Ok(Self {
             info: PackageInfo {
                 name,
                 version,
@@ -85,12 +174,32 @@ impl From<DbPackage> for Package {
             },
             source: Source {
                 url,
+                vcs,
+                rev,
+                mirrors,
                 checksum,
+                signature,
+                signing_key,
+                strip_components,
+                patches,
+                prepare,
                 build,
                 install,
+                post_install,
+                uninstall,
+                executable,
+                config_files,
+                env,
             },
-            local: Local { installed, added },
-        }
+            local: Local {
+                installed,
+                added,
+                files,
+                last_installed,
+                installed_at,
+                pinned,
+            },
+        })
     }
 }
 
@@ -112,11 +221,32 @@ impl From<Package> for DbPackage {
             source:
                 Source {
                     url,
+                    vcs,
+                    rev,
+                    mirrors,
                     checksum,
+                    signature,
+                    signing_key,
+                    strip_components,
+                    patches,
+                    prepare,
                     build,
                     install,
+                    post_install,
+                    uninstall,
+                    executable,
+                    config_files,
+                    env,
+                },
+            local:
+                Local {
+                    installed,
+                    added,
+                    files,
+                    last_installed,
+                    installed_at,
+                    pinned,
                 },
-            local: Local { installed, added },
         } = value;
 
         let version = version.to_string();
@@ -126,8 +256,138 @@ impl From<Package> for DbPackage {
         let dependencies = dependencies.unwrap_or_default();
         let build_dependencies = build_dependencies.unwrap_or_default();
         let provides = provides.unwrap_or_default();
+        let rev = rev.unwrap_or_default();
+        let mirrors = mirrors.unwrap_or_default();
         let checksum = checksum.unwrap_or_default();
+        let signature = signature.unwrap_or_default();
+        let signing_key = signing_key.unwrap_or_default();
+        let patches = patches.unwrap_or_default();
+        let prepare = prepare.unwrap_or_default();
         let build = build.unwrap_or_default();
+        let post_install = post_install.unwrap_or_default();
+        let uninstall = uninstall.unwrap_or_default();
+        let executable = executable.unwrap_or_default();
+        let config_files = config_files.unwrap_or_default();
+        let env = env.unwrap_or_default();
+        let files = files
+            .into_iter()
+            .map(|file| file.to_string_lossy().into_owned())
+            .collect();
+
+        Self {
+            name,
+            version,
+            license,
+            repository,
+            authors,
+            description,
+            dependencies,
+            build_dependencies,
+            provides,
+            url,
+            vcs,
+            rev,
+            mirrors,
+            checksum,
+            signature,
+            signing_key,
+            strip_components,
+            patches,
+            prepare,
+            build,
+            install,
+            post_install,
+            uninstall,
+            executable,
+            config_files,
+            env,
+            installed,
+            added,
+            files,
+            last_installed,
+            installed_at,
+            pinned,
+        }
+    }
+}
+
+/// The layout of [`DbPackage`] at schema version 1, before packages could provide several
+/// virtual packages and before any of the later fields existed. Only used to migrate old rows,
+/// so it must never change.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct LegacyDbPackage {
+    pub name: String,
+    pub version: String,
+    pub license: String,
+    pub repository: String,
+    pub authors: Vec<String>,
+    pub description: String,
+    pub dependencies: Vec<String>,
+    pub build_dependencies: Vec<String>,
+    pub provides: String,
+    pub url: String,
+    pub checksum: String,
+    pub build: String,
+    pub install: String,
+    pub installed: Installed,
+    pub added: bool,
+}
+
+/// Encoded like the derived implementation of [`DbPackage`] and stored under its type name,
+/// so that tables written by old versions of mercurium can be opened with this type.
+impl RedbValue for LegacyDbPackage {
+    type SelfType<'a> = Self;
+    type AsBytes<'a> = Vec<u8>;
+
+    fn fixed_width() -> Option<usize> {
+        None
+    }
+
+    fn from_bytes<'a>(data: &'a [u8]) -> Self::SelfType<'a>
+    where
+        Self: 'a,
+    {
+        bincode::deserialize(data).expect("invalid legacy package row")
+    }
+
+    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
+    where
+        Self: 'a,
+        Self: 'b,
+    {
+        bincode::serialize(value).unwrap()
+    }
+
+    fn type_name() -> TypeName {
+        DbPackage::type_name()
+    }
+}
+
+impl From<LegacyDbPackage> for DbPackage {
+    fn from(value: LegacyDbPackage) -> Self {
+        let LegacyDbPackage {
+            name,
+            version,
+            license,
+            repository,
+            authors,
+            description,
+            dependencies,
+            build_dependencies,
+            provides,
+            url,
+            checksum,
+            build,
+            install,
+            installed,
+            added,
+        } = value;
+
+        let provides = if provides.is_empty() {
+            Vec::new()
+        } else {
+            vec![provides]
+        };
 
         Self {
             name,
@@ -140,20 +400,131 @@ impl From<Package> for DbPackage {
             build_dependencies,
             provides,
             url,
+            vcs: None,
+            rev: String::new(),
+            mirrors: Vec::new(),
             checksum,
+            signature: String::new(),
+            signing_key: String::new(),
+            strip_components: None,
+            patches: Vec::new(),
+            prepare: String::new(),
             build,
             install,
+            post_install: String::new(),
+            uninstall: String::new(),
+            executable: Vec::new(),
+            config_files: Vec::new(),
+            env: BTreeMap::new(),
             installed,
             added,
+            files: Vec::new(),
+            last_installed: Installed::False,
+            installed_at: None,
+            pinned: false,
         }
     }
 }
 
-pub trait Db<'a, 'b> {
+/// Key of version `version` of package `name` in a table storing several versions of each package.
+pub fn version_key(name: &str, version: impl Display) -> String {
+    format!("{name}\0{version}")
+}
+
+/// Get the versions of package `name` in `table` (keyed by [`version_key`]), oldest first.
+pub fn versions_in<V: RedbValue + 'static>(
+    table: &impl ReadableTable<&'static str, V>,
+    name: &str,
+) -> Result<Vec<Version>, redb::Error> {
+    let prefix = version_key(name, "");
+    let mut versions = Vec::new();
+    for row in table.range(prefix.as_str()..)? {
+        let (key, _) = row?;
+        let Some(version) = key
+            .value()
+            .strip_prefix(prefix.as_str())
+            .map(Version::parse)
+        else {
+            break;
+        };
+        if let Ok(version) = version {
+            versions.push(version);
+        }
+    }
+    versions.sort();
+
+    Ok(versions)
+}
+
+/// Key of the schema version in the meta table.
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// A migration upgrading the database to the schema version `version`, e.g. by rewriting the rows
+/// of `ALL_PKGS` after a field was added to [`DbPackage`]. Rows in the old format have to be read
+/// using a table definition with the old row type, like [`LegacyDbPackage`] for version 1.
+pub struct Migration {
+    pub version: u64,
+    pub run: fn(&WriteTransaction) -> Result<(), redb::Error>,
+}
+
+/// Run the `migrations` to versions newer than the schema version stored in `meta` in order,
+/// and store the schema version `version` afterwards. Everything happens in one transaction.
+///
+/// A database without a stored schema version is at version 1, the original layout of [`LegacyDbPackage`]:
+/// either it was written before versioning or it is new, in which case the migrations only see empty tables.
+/// Returns the previous schema version. If it is newer than `version`, the database is left untouched.
+pub fn migrate(
+    db: &Database,
+    meta: TableDefinition<&'static str, u64>,
+    version: u64,
+    migrations: &[Migration],
+) -> Result<u64, redb::Error> {
+    let write_txn = db.begin_write()?;
+    let previous = {
+        let meta_table = write_txn.open_table(meta)?;
+        let previous = meta_table.get(SCHEMA_VERSION_KEY)?;
+        previous.map_or(1, |previous| previous.value())
+    };
+    if previous > version {
+        write_txn.abort()?;
+        return Ok(previous);
+    }
+
+    for migration in migrations
+        .iter()
+        .filter(|migration| migration.version > previous && migration.version <= version)
+    {
+        info!(
+            "Migrating the database to schema version {}.",
+            migration.version
+        );
+        (migration.run)(&write_txn)?;
+    }
+    {
+        let mut meta_table = write_txn.open_table(meta)?;
+        meta_table.insert(SCHEMA_VERSION_KEY, version)?;
+    }
+    write_txn.commit()?;
+
+    Ok(previous)
+}
+
+/// A value stored in a table, converted to and from [`DbValue::Ext`] by [`Db`].
+pub trait DbValue: for<'x> RedbValue<SelfType<'x> = Self> + Sized + 'static {
+    /// The type the rest of mercurium works with.
+    type Ext: TryFrom<Self> + Into<Self>;
+}
+
+impl DbValue for DbPackage {
+    type Ext = Package;
+}
+
+/// Access to tables storing values of type `T`.
+pub trait Db<'a, 'b, T> {
     type Error;
-    type Key<'k>;
+    type Key<'k>: Copy + Eq + Hash;
     type Value;
-    type ExtValue: From<Self::Value> + Into<Self::Value>;
+    type ExtValue: TryFrom<Self::Value> + Into<Self::Value>;
     type Table;
     type Iterator;
 
@@ -164,11 +535,17 @@ pub trait Db<'a, 'b> {
         table: Self::Table,
         key: Self::Key<'a>,
     ) -> Result<Option<Self::ExtValue>, Self::Error>;
+    /// Get the values of all `keys` at once, keyed by the keys. Keys without a value are left out.
+    /// Every key is only looked up once, even if it is given several times.
     fn get_iter<I: IntoIterator<Item = Self::Key<'a>>>(
         &self,
         table: Self::Table,
         keys: I,
-    ) -> Result<Vec<Option<Self::ExtValue>>, Self::Error>;
+    ) -> Result<HashMap<Self::Key<'a>, Self::ExtValue>, Self::Error>;
+    /// Check whether `table` has a value for `key` without converting it.
+    fn contains(&self, table: Self::Table, key: Self::Key<'a>) -> Result<bool, Self::Error>;
+    /// Get the versions of package `name` in `table` (keyed by [`version_key`]), oldest first.
+    fn get_versions(&self, table: Self::Table, name: &str) -> Result<Vec<Version>, Self::Error>;
 
     fn set(
         &self,
@@ -196,12 +573,16 @@ pub trait Db<'a, 'b> {
         F: FnOnce(Option<Self::ExtValue>) -> Option<Self::ExtValue>;
 }
 
-impl<'a: 'b, 'b> Db<'a, 'b> for Database {
+impl<'a: 'b, 'b, T> Db<'a, 'b, T> for Database
+where
+    T: DbValue,
+    redb::Error: From<<T::Ext as TryFrom<T>>::Error>,
+{
     type Error = redb::Error;
     type Key<'k> = &'k str;
-    type Value = DbPackage;
-    type ExtValue = Package;
-    type Table = TableDefinition<'a, &'static str, DbPackage>;
+    type Value = T;
+    type ExtValue = T::Ext;
+    type Table = TableDefinition<'a, &'static str, T>;
     type Iterator = Range<'b, Self::Key<'static>, Self::Value>;
 
     fn init_table(&self, table: Self::Table) -> Result<(), Self::Error> {
@@ -221,31 +602,48 @@ impl<'a: 'b, 'b> Db<'a, 'b> for Database {
     ) -> Result<Option<Self::ExtValue>, Self::Error> {
         let read_txn = self.begin_read()?;
         let read_table = read_txn.open_table(table)?;
-        Ok(read_table
-            .get(key)
-            .map(|r| r.map(|o| Into::<Package>::into(o.value())))?)
+        let value = read_table.get(key)?;
+        Ok(value
+            .map(|value| T::Ext::try_from(value.value()))
+            .transpose()?)
     }
 
     fn get_iter<I: IntoIterator<Item = Self::Key<'a>>>(
         &self,
         table: Self::Table,
         keys: I,
-    ) -> Result<Vec<Option<Self::ExtValue>>, Self::Error> {
+    ) -> Result<HashMap<Self::Key<'a>, Self::ExtValue>, Self::Error> {
         let read_txn = self.begin_read()?;
         let read_table = read_txn.open_table(table)?;
 
-        let mut values: Vec<Option<Self::ExtValue>> = Vec::new();
+        let mut seen = HashSet::new();
+        let mut values = HashMap::new();
         for key in keys {
-            values.push(
-                read_table
-                    .get(key)
-                    .map(|r| r.map(|o| Into::<Package>::into(o.value())))?,
-            );
+            if !seen.insert(key) {
+                continue;
+            }
+            let value = read_table.get(key)?;
+            if let Some(value) = value {
+                values.insert(key, T::Ext::try_from(value.value())?);
+            }
         }
 
         Ok(values)
     }
 
+    fn contains(&self, table: Self::Table, key: Self::Key<'a>) -> Result<bool, Self::Error> {
+        let read_txn = self.begin_read()?;
+        let read_table = read_txn.open_table(table)?;
+        let value = read_table.get(key)?;
+        Ok(value.is_some())
+    }
+
+    fn get_versions(&self, table: Self::Table, name: &str) -> Result<Vec<Version>, Self::Error> {
+        let read_txn = self.begin_read()?;
+        let read_table = read_txn.open_table(table)?;
+        versions_in(&read_table, name)
+    }
+
     fn set(
         &self,
         table: Self::Table,
@@ -255,7 +653,7 @@ impl<'a: 'b, 'b> Db<'a, 'b> for Database {
         let write_txn = self.begin_write()?;
         {
             let mut write_table = write_txn.open_table(table)?;
-            write_table.insert(key, Into::<Self::Value>::into(value))?;
+            write_table.insert(key, Into::<T>::into(value))?;
         }
         write_txn.commit()?;
 
@@ -270,7 +668,7 @@ impl<'a: 'b, 'b> Db<'a, 'b> for Database {
         {
             let mut write_table = write_txn.open_table(table)?;
             for (key, value) in iter {
-                write_table.insert(key, Into::<Self::Value>::into(value))?;
+                write_table.insert(key, Into::<T>::into(value))?;
             }
         }
         write_txn.commit()?;
@@ -287,7 +685,7 @@ impl<'a: 'b, 'b> Db<'a, 'b> for Database {
         let val = {
             let mut write_table = write_txn.open_table(table)?;
             let val = write_table.remove(key)?;
-            val.map(|x| Into::<Self::ExtValue>::into(x.value()))
+            val.map(|x| T::Ext::try_from(x.value())).transpose()?
         };
         write_txn.commit()?;
 
@@ -305,11 +703,8 @@ impl<'a: 'b, 'b> Db<'a, 'b> for Database {
         {
             let mut write_table = write_txn.open_table(table)?;
             for key in keys {
-                values.push(
-                    write_table
-                        .remove(key)?
-                        .map(|x| Into::<Self::ExtValue>::into(x.value())),
-                );
+                let val = write_table.remove(key)?;
+                values.push(val.map(|x| T::Ext::try_from(x.value())).transpose()?);
             }
         }
         write_txn.commit()?;
@@ -324,9 +719,10 @@ impl<'a: 'b, 'b> Db<'a, 'b> for Database {
         let write_txn = self.begin_write()?;
         {
             let mut write_table = write_txn.open_table(table)?;
-            let value: Option<Package> = write_table.remove(key)?.map(|x| x.value().into());
+            let value = write_table.remove(key)?;
+            let value = value.map(|x| T::Ext::try_from(x.value())).transpose()?;
             if let Some(value) = func(value) {
-                write_table.insert(key, Into::<Self::Value>::into(value))?;
+                write_table.insert(key, Into::<T>::into(value))?;
             }
         };
         write_txn.commit()?;
@@ -341,7 +737,8 @@ mod tests {
 
     use super::*;
     // use crate::init_logging;
-    use crate::pkg::{Installed, Local, Package, PackageInfo, Source};
+    use crate::pkg::{Installed, Local, Package, PackageInfo, Source, Vcs};
+    use crate::pkgfile::PackageFile;
 
     #[test]
     fn test_redb() {
@@ -366,11 +763,24 @@ mod tests {
                         },
                         source: Source {
                             url: "https://github.com/topgrade-rs/topgrade/releases/download/v12.0.2/topgrade-v12.0.2-x86_64-apple-darwin.tar.gz".to_owned(),
+                            vcs: None,
+                            rev: None,
+                            mirrors: None,
                             checksum: Some("45dfddf13e8f5a5eb4a95dde6743f42f216ed6d3751d7430dae5f9e0dc54e67a400e6572789fb9984ff1c80bdee42a92112a76d5399436e857e723b653b366f1".to_owned()),
+                            signature: None,
+                            signing_key: None,
+                            strip_components: None,
+                            patches: None,
+                            prepare: None,
                             build: None,
                             install: "mv ${source}/topgrade ${binary}".to_owned(),
+                            post_install: None,
+                            uninstall: None,
+                            executable: None,
+                            config_files: None,
+                            env: None,
                         },
-                        local: Local { installed: Installed::False, added: true}
+                        local: Local { installed: Installed::False, added: true, files: Vec::new(), last_installed: Installed::False, installed_at: None, pinned: false }
                     };
 
         let write_txn = db.begin_write().unwrap();
@@ -394,4 +804,126 @@ mod tests {
             topgrade.into()
         );
     }
+
+    /// A value of a table that doesn't store packages.
+    #[derive(Debug, PartialEq)]
+    struct Jobs(u64);
+
+    impl TryFrom<u64> for Jobs {
+        type Error = redb::Error;
+
+        fn try_from(value: u64) -> Result<Self, Self::Error> {
+            Ok(Self(value))
+        }
+    }
+
+    impl From<Jobs> for u64 {
+        fn from(value: Jobs) -> Self {
+            value.0
+        }
+    }
+
+    impl DbValue for u64 {
+        type Ext = Jobs;
+    }
+
+    #[test]
+    fn test_other_value() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let table: TableDefinition<&str, u64> = TableDefinition::new("settings");
+        let db = Database::create(tmpdir.path().join("test.db")).unwrap();
+        db.init_table(table).unwrap();
+
+        assert_eq!(db.get(table, "jobs").unwrap(), None);
+        assert!(!db.contains(table, "jobs").unwrap());
+        db.set(table, "jobs", Jobs(4)).unwrap();
+        assert!(db.contains(table, "jobs").unwrap());
+        db.modify(table, "jobs", |jobs| jobs.map(|jobs| Jobs(jobs.0 * 2)))
+            .unwrap();
+        assert_eq!(db.get(table, "jobs").unwrap(), Some(Jobs(8)));
+        let values = db.get_iter(table, ["jobs", "missing", "jobs"]).unwrap();
+        assert_eq!(values, HashMap::from([("jobs", Jobs(8))]));
+        assert_eq!(db.remove(table, "jobs").unwrap(), Some(Jobs(8)));
+    }
+
+    #[test]
+    fn test_migrate() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let meta: TableDefinition<&str, u64> = TableDefinition::new("meta");
+        let log: TableDefinition<&str, u64> = TableDefinition::new("log");
+        let db = Database::create(tmpdir.path().join("test.db")).unwrap();
+
+        fn record(write_txn: &WriteTransaction, version: u64) -> Result<(), redb::Error> {
+            let mut table = write_txn.open_table(TableDefinition::<&str, u64>::new("log"))?;
+            let count = table.len()?;
+            table.insert(version.to_string().as_str(), count)?;
+            Ok(())
+        }
+        let migrations = [
+            Migration {
+                version: 2,
+                run: |write_txn| record(write_txn, 2),
+            },
+            Migration {
+                version: 3,
+                run: |write_txn| record(write_txn, 3),
+            },
+        ];
+
+        assert_eq!(migrate(&db, meta, 2, &migrations).unwrap(), 1);
+        assert_eq!(migrate(&db, meta, 3, &migrations).unwrap(), 2);
+        // Each migration ran once, in order.
+        let read_txn = db.begin_read().unwrap();
+        let log_table = read_txn.open_table(log).unwrap();
+        assert_eq!(log_table.get("2").unwrap().unwrap().value(), 0);
+        assert_eq!(log_table.get("3").unwrap().unwrap().value(), 1);
+        drop(log_table);
+        drop(read_txn);
+
+        // A database of a newer version isn't touched.
+        assert_eq!(migrate(&db, meta, 2, &migrations).unwrap(), 3);
+        assert_eq!(migrate(&db, meta, 3, &[]).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_invalid_version() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let table: TableDefinition<&str, DbPackage> = TableDefinition::new("test");
+        let db = Database::create(tmpdir.path().join("test.db")).unwrap();
+
+        let pkg = PackageFile::builder()
+            .name("foo")
+            .version(Version::from_str("1.0.0").unwrap())
+            .license("MIT")
+            .url("https://example.com/foo.tar.gz")
+            .install_command("true")
+            .build()
+            .unwrap();
+        let local = Local {
+            installed: Installed::False,
+            added: true,
+            files: Vec::new(),
+            last_installed: Installed::False,
+            installed_at: None,
+            pinned: false,
+        };
+        let mut row: DbPackage = Package::from_file(pkg, local).into();
+        row.version = "1.0".to_owned();
+
+        let err = Package::try_from(row.clone()).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("package foo has the invalid version 1.0: "));
+
+        let write_txn = db.begin_write().unwrap();
+        {
+            let mut write_table = write_txn.open_table(table).unwrap();
+            write_table.insert("foo", row).unwrap();
+        }
+        write_txn.commit().unwrap();
+        assert!(matches!(
+            db.get(table, "foo"),
+            Err(redb::Error::Corrupted(_))
+        ));
+    }
 }
@@ -0,0 +1,270 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use redb::{Database, ReadableTable, TableDefinition};
+use semver::Version;
+use thiserror::Error;
+
+use crate::db::DbPackage;
+use crate::pkg::{Dependency, Installed};
+use crate::{ALL_PKGS, INSTALLED_PKGS};
+
+/// An inconsistency in the package database.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum Problem {
+    #[error("package {name} in {table} has the invalid version {version}")]
+    InvalidVersion {
+        table: &'static str,
+        name: String,
+        version: String,
+    },
+    #[error("package {0} is in INSTALLED_PKGS, but not marked as installed")]
+    NotInstalled(String),
+    #[error("package {0} is in INSTALLED_PKGS, but missing from ALL_PKGS")]
+    MissingFromAll(String),
+    #[error("package {0} is marked as installed in ALL_PKGS, but missing from INSTALLED_PKGS")]
+    MissingFromInstalled(String),
+    #[error("package {0} has a different installation state in ALL_PKGS and INSTALLED_PKGS")]
+    StateMismatch(String),
+    #[error("package {name} in {table} depends on the unknown package {dependency}")]
+    UnknownDependency {
+        table: &'static str,
+        name: String,
+        dependency: String,
+    },
+}
+
+/// Differently named packages in `ALL_PKGS` with the same source URL or checksum.
+/// This is often a package that was added twice or renamed.
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("packages {} have the same {kind} {value}", .names.join(", "))]
+pub struct Duplicate {
+    pub names: Vec<String>,
+    /// `url` or `checksum`.
+    pub kind: &'static str,
+    pub value: String,
+}
+
+/// Read all rows of `table` without converting them, so that invalid rows can't cause panics.
+pub(crate) fn read_rows(
+    db: &Database,
+    table: TableDefinition<'_, &'static str, DbPackage>,
+) -> Result<BTreeMap<String, DbPackage>, redb::Error> {
+    let read_txn = db.begin_read()?;
+    let read_table = read_txn.open_table(table)?;
+
+    let mut rows = BTreeMap::new();
+    for row in read_table.iter()? {
+        let (key, value) = row?;
+        rows.insert(key.value().to_owned(), value.value());
+    }
+
+    Ok(rows)
+}
+
+/// Check every row of `ALL_PKGS` and `INSTALLED_PKGS` and report all problems found.
+pub fn check_integrity(db: &Database) -> Result<Vec<Problem>, redb::Error> {
+    let all = read_rows(db, ALL_PKGS)?;
+    let installed = read_rows(db, INSTALLED_PKGS)?;
+    let provided: Vec<&str> = all
+        .values()
+        .flat_map(|pkg| pkg.provides.iter().map(String::as_str))
+        .collect();
+    let mut problems = Vec::new();
+
+    for (table, rows) in [("ALL_PKGS", &all), ("INSTALLED_PKGS", &installed)] {
+        for (name, pkg) in rows {
+            if Version::from_str(&pkg.version).is_err() {
+                problems.push(Problem::InvalidVersion {
+                    table,
+                    name: name.clone(),
+                    version: pkg.version.clone(),
+                });
+            }
+
+            for dependency in &pkg.dependencies {
+                let dependency_name = Dependency::name_of(dependency);
+                if !all.contains_key(dependency_name) && !provided.contains(&dependency_name) {
+                    problems.push(Problem::UnknownDependency {
+                        table,
+                        name: name.clone(),
+                        dependency: dependency.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    for (name, pkg) in &installed {
+        if pkg.installed == Installed::False {
+            problems.push(Problem::NotInstalled(name.clone()));
+        }
+
+        match all.get(name) {
+            Some(all_pkg) if all_pkg.installed != pkg.installed => {
+                problems.push(Problem::StateMismatch(name.clone()))
+            }
+            Some(_) => (),
+            None => problems.push(Problem::MissingFromAll(name.clone())),
+        }
+    }
+
+    for (name, pkg) in &all {
+        if pkg.installed != Installed::False && !installed.contains_key(name) {
+            problems.push(Problem::MissingFromInstalled(name.clone()));
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Find packages in `ALL_PKGS` that share their source URL or checksum with other packages.
+pub fn duplicates(db: &Database) -> Result<Vec<Duplicate>, redb::Error> {
+    let all = read_rows(db, ALL_PKGS)?;
+
+    let mut by_source: BTreeMap<(&'static str, &str), Vec<String>> = BTreeMap::new();
+    for (name, pkg) in &all {
+        by_source
+            .entry(("url", &pkg.url))
+            .or_default()
+            .push(name.clone());
+        if !pkg.checksum.is_empty() {
+            by_source
+                .entry(("checksum", &pkg.checksum))
+                .or_default()
+                .push(name.clone());
+        }
+    }
+
+    let mut duplicates: Vec<Duplicate> = Vec::new();
+    for ((kind, value), names) in by_source {
+        // Packages with the same URL usually have the same checksum, too. Report them once.
+        if names.len() > 1 && !duplicates.iter().any(|dup| dup.names == names) {
+            duplicates.push(Duplicate {
+                names,
+                kind,
+                value: value.to_owned(),
+            });
+        }
+    }
+
+    Ok(duplicates)
+}
+
+/// Restore the invariant that a package is in `INSTALLED_PKGS` if and only if it is installed.
+/// Rows of `INSTALLED_PKGS` that aren't installed are dropped.
+/// These packages and packages marked as installed in `ALL_PKGS` but missing from `INSTALLED_PKGS` are marked as not installed.
+/// Returns the names of the repaired packages.
+pub fn repair(db: &Database) -> Result<Vec<String>, redb::Error> {
+    let mut repaired = Vec::new();
+
+    let write_txn = db.begin_write()?;
+    {
+        let mut installed_table = write_txn.open_table(INSTALLED_PKGS)?;
+        let mut all_table = write_txn.open_table(ALL_PKGS)?;
+
+        for row in installed_table.iter()? {
+            let (key, value) = row?;
+            if value.value().installed == Installed::False {
+                repaired.push(key.value().to_owned());
+            }
+        }
+        for name in &repaired {
+            installed_table.remove(name.as_str())?;
+        }
+
+        for row in all_table.iter()? {
+            let (key, value) = row?;
+            if value.value().installed != Installed::False
+                && installed_table.get(key.value())?.is_none()
+            {
+                repaired.push(key.value().to_owned());
+            }
+        }
+        for name in &repaired {
+            let pkg = all_table.get(name.as_str())?.map(|pkg| pkg.value());
+            if let Some(mut pkg) = pkg {
+                if pkg.installed != Installed::False {
+                    pkg.installed = Installed::False;
+                    all_table.insert(name.as_str(), pkg)?;
+                }
+            }
+        }
+    }
+    write_txn.commit()?;
+
+    Ok(repaired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Db;
+    use crate::tests::{test_db, test_package};
+
+    #[test]
+    fn test_check_integrity() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = test_db(tmpdir.path());
+
+        let mut healthy = test_package("healthy", "1.0.0");
+        healthy.local.installed = Installed::Manually(healthy.info.version.clone());
+        db.set(ALL_PKGS, "healthy", healthy.clone()).unwrap();
+        db.set(INSTALLED_PKGS, "healthy", healthy).unwrap();
+        assert!(check_integrity(&db).unwrap().is_empty());
+
+        let stale = test_package("stale", "1.0.0");
+        db.set(ALL_PKGS, "stale", stale.clone()).unwrap();
+        db.set(INSTALLED_PKGS, "stale", stale).unwrap();
+
+        let mut broken = test_package("broken", "1.0.0");
+        broken.info.dependencies = Some(vec!["missing".to_owned()]);
+        db.set(ALL_PKGS, "broken", broken).unwrap();
+
+        let problems = check_integrity(&db).unwrap();
+        assert_eq!(
+            problems,
+            vec![
+                Problem::UnknownDependency {
+                    table: "ALL_PKGS",
+                    name: "broken".to_owned(),
+                    dependency: "missing".to_owned(),
+                },
+                Problem::NotInstalled("stale".to_owned()),
+            ]
+        );
+
+        assert_eq!(repair(&db).unwrap(), vec!["stale".to_owned()]);
+        assert!(db.get(INSTALLED_PKGS, "stale").unwrap().is_none());
+        assert_eq!(check_integrity(&db).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = test_db(tmpdir.path());
+
+        let mut foo = test_package("foo", "1.0.0");
+        foo.source.checksum = Some("sha256:abcd".to_owned());
+        let mut renamed = test_package("foo-renamed", "1.0.0");
+        renamed.source.checksum = Some("sha256:abcd".to_owned());
+        db.set(ALL_PKGS, "foo", foo).unwrap();
+        db.set(ALL_PKGS, "foo-renamed", renamed).unwrap();
+        db.set(ALL_PKGS, "bar", test_package("bar", "1.0.0"))
+            .unwrap();
+
+        let duplicates = duplicates(&db).unwrap();
+        assert_eq!(
+            duplicates,
+            [Duplicate {
+                names: vec!["foo".to_owned(), "foo-renamed".to_owned()],
+                kind: "checksum",
+                value: "sha256:abcd".to_owned(),
+            }]
+        );
+        assert_eq!(
+            duplicates[0].to_string(),
+            "packages foo, foo-renamed have the same checksum sha256:abcd"
+        );
+    }
+}
@@ -0,0 +1,260 @@
+use std::fmt;
+use std::str::FromStr;
+
+use redb::Database;
+use reqwest::Url;
+use semver::Version;
+use thiserror::Error;
+
+use crate::doctor::read_rows;
+use crate::payload::ChecksumAlgo;
+use crate::pkg::Dependency;
+use crate::pkgfile::PackageFile;
+use crate::ALL_PKGS;
+
+/// A mistake in a pkgfile.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum Problem {
+    #[error("invalid TOML: {0}")]
+    Syntax(String),
+    #[error("{0}")]
+    Format(String),
+    #[error("invalid version {0}")]
+    InvalidVersion(String),
+    #[error("empty install command")]
+    EmptyInstall,
+    #[error("invalid url {0}")]
+    InvalidUrl(String),
+    #[error("invalid checksum: {0}")]
+    InvalidChecksum(String),
+    #[error("invalid dependency {0}")]
+    InvalidDependency(String),
+    #[error("unknown dependency {0}")]
+    UnknownDependency(String),
+}
+
+/// A problem found in a pkgfile, with the line it is on if known.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Lint {
+    /// The line of the problem, starting at 1.
+    pub line: Option<usize>,
+    pub problem: Problem,
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {line}: {}", self.problem),
+            None => write!(f, "{}", self.problem),
+        }
+    }
+}
+
+/// Get the line (starting at 1) of the byte `offset` in `content`.
+fn line_of_offset(content: &str, offset: usize) -> usize {
+    content[..offset.min(content.len())].matches('\n').count() + 1
+}
+
+/// Find the line (starting at 1) on which `key` of the table `section` is set.
+fn line_of_key(content: &str, section: &str, key: &str) -> Option<usize> {
+    let mut current = "";
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            current = header.trim();
+        } else if current == section
+            && line
+                .strip_prefix(key)
+                .is_some_and(|rest| rest.trim_start().starts_with('='))
+        {
+            return Some(i + 1);
+        }
+    }
+    None
+}
+
+/// Check whether `checksum` (see [`ChecksumAlgo::parse`]) is a hex digest of the right length.
+fn check_checksum(checksum: &str) -> Result<(), String> {
+    let (algo, digest) = ChecksumAlgo::parse(checksum).map_err(|err| err.to_string())?;
+    let digest = hex::decode(digest).map_err(|err| err.to_string())?;
+    let expected = algo.digest(&[]).len();
+    if digest.len() != expected {
+        return Err(format!(
+            "{algo:?} digest has {} instead of {expected} bytes",
+            digest.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Check the pkgfile `content` for mistakes that would only show up when installing it.
+/// Dependencies are looked up in `ALL_PKGS` of `db`.
+pub fn lint(content: &str, db: &Database) -> Result<Vec<Lint>, redb::Error> {
+    let table: toml::Table = match toml::from_str(content) {
+        Ok(table) => table,
+        Err(err) => {
+            return Ok(vec![Lint {
+                line: err.span().map(|span| line_of_offset(content, span.start)),
+                problem: Problem::Syntax(err.message().to_owned()),
+            }])
+        }
+    };
+
+    let mut lints = Vec::new();
+
+    // The version is checked on the raw table, so that the other checks still run if it is invalid.
+    let version = table
+        .get("package")
+        .and_then(|package| package.get("version"))
+        .and_then(toml::Value::as_str);
+    if let Some(version) = version.filter(|version| Version::from_str(version).is_err()) {
+        lints.push(Lint {
+            line: line_of_key(content, "package", "version"),
+            problem: Problem::InvalidVersion(version.to_owned()),
+        });
+    }
+
+    let file: PackageFile = match toml::from_str(content) {
+        Ok(file) => file,
+        Err(err) => {
+            // An invalid version already makes parsing fail, don't report it twice.
+            if lints.is_empty() {
+                lints.push(Lint {
+                    line: err.span().map(|span| line_of_offset(content, span.start)),
+                    problem: Problem::Format(err.message().to_owned()),
+                });
+            }
+            return Ok(lints);
+        }
+    };
+
+    if file.source.install.trim().is_empty() {
+        lints.push(Lint {
+            line: line_of_key(content, "source", "install"),
+            problem: Problem::EmptyInstall,
+        });
+    }
+
+    // Repositories may also be cloned over SSH, so only tarball URLs are checked.
+    let tarball = Some(&file.source.url).filter(|_| file.source.vcs.is_none());
+    for url in tarball
+        .into_iter()
+        .chain(file.source.mirrors.iter().flatten())
+    {
+        let valid = Url::parse(url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"));
+        if !valid {
+            let key = if *url == file.source.url {
+                "url"
+            } else {
+                "mirrors"
+            };
+            lints.push(Lint {
+                line: line_of_key(content, "source", key),
+                problem: Problem::InvalidUrl(url.clone()),
+            });
+        }
+    }
+
+    if let Some(checksum) = &file.source.checksum {
+        if let Err(reason) = check_checksum(checksum) {
+            lints.push(Lint {
+                line: line_of_key(content, "source", "checksum"),
+                problem: Problem::InvalidChecksum(reason),
+            });
+        }
+    }
+
+    let all = read_rows(db, ALL_PKGS)?;
+    let provided: Vec<&str> = all
+        .values()
+        .flat_map(|pkg| pkg.provides.iter().map(String::as_str))
+        .collect();
+    for (key, dependencies) in [
+        ("dependencies", &file.info.dependencies),
+        ("build_dependencies", &file.info.build_dependencies),
+    ] {
+        for dependency in dependencies.iter().flatten() {
+            let line = line_of_key(content, "package", key);
+            let name = Dependency::name_of(dependency);
+            if Dependency::from_str(dependency).is_err() {
+                lints.push(Lint {
+                    line,
+                    problem: Problem::InvalidDependency(dependency.clone()),
+                });
+            } else if name != file.info.name && !all.contains_key(name) && !provided.contains(&name)
+            {
+                lints.push(Lint {
+                    line,
+                    problem: Problem::UnknownDependency(name.to_owned()),
+                });
+            }
+        }
+    }
+
+    Ok(lints)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Db;
+
+    #[test]
+    fn test_lint() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = Database::create(tmpdir.path().join("packages.db")).unwrap();
+        db.init_table(ALL_PKGS).unwrap();
+
+        let valid = "
+            [package]
+            name = \"foo\"
+            version = \"1.0.0\"
+            license = \"MIT\"
+
+            [source]
+            url = \"https://example.com/foo.tar.gz\"
+            checksum = \"sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855\"
+            install = \"mv ${source}/foo ${binary}\"
+        ";
+        assert_eq!(lint(valid, &db).unwrap(), []);
+
+        let invalid = "
+            [package]
+            name = \"foo\"
+            version = \"1.0\"
+            license = \"MIT\"
+            dependencies = [\"bar >=1\"]
+
+            [source]
+            url = \"example.com/foo.tar.gz\"
+            checksum = \"sha256:e3b0c442\"
+            install = \" \"
+        ";
+        let lints = lint(invalid, &db).unwrap();
+        assert_eq!(
+            lints,
+            [Lint {
+                line: Some(4),
+                problem: Problem::InvalidVersion("1.0".to_owned()),
+            }]
+        );
+
+        let lints = lint(&invalid.replace("\"1.0\"", "\"1.0.0\""), &db).unwrap();
+        let problems: Vec<_> = lints.iter().map(|lint| &lint.problem).collect();
+        assert_eq!(
+            problems,
+            [
+                &Problem::EmptyInstall,
+                &Problem::InvalidUrl("example.com/foo.tar.gz".to_owned()),
+                &Problem::InvalidChecksum("Sha256 digest has 4 instead of 32 bytes".to_owned()),
+                &Problem::UnknownDependency("bar".to_owned()),
+            ]
+        );
+        assert_eq!(lints[0].to_string(), "line 11: empty install command");
+
+        let lints = lint("[package\nname = \"foo\"", &db).unwrap();
+        assert_eq!(lints.len(), 1);
+        assert_eq!(lints[0].line, Some(1));
+        assert!(matches!(lints[0].problem, Problem::Syntax(_)));
+    }
+}
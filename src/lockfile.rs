@@ -0,0 +1,139 @@
+use std::io;
+use std::path::Path;
+
+use log::warn;
+use redb::{Database, ReadableTable};
+use serde::{Deserialize, Serialize};
+
+use crate::output::write_output;
+use crate::pkg::Package;
+use crate::INSTALLED_PKGS;
+
+/// The version of the lockfile format written by this version of mercurium.
+/// Readers should reject lockfiles with a newer version.
+pub const LOCKFILE_VERSION: u64 = 1;
+
+/// The exact versions and sources of the installed packages, to reproduce an identical installation.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Lockfile {
+    /// The version of the format, see [`LOCKFILE_VERSION`].
+    pub version: u64,
+    #[serde(default, rename = "package")]
+    pub packages: Vec<LockedPackage>,
+}
+
+/// An installed package recorded in a [`Lockfile`].
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LockedPackage {
+    pub name: String,
+    pub version: String,
+    pub url: String,
+    /// The checked out revision if `url` is a repository.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rev: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+}
+
+impl Lockfile {
+    /// Collect the packages of `INSTALLED_PKGS`, sorted by name.
+    pub fn from_db(db: &Database) -> Result<Self, redb::Error> {
+        let read_txn = db.begin_read()?;
+        let read_table = read_txn.open_table(INSTALLED_PKGS)?;
+
+        let mut packages = Vec::new();
+        for pkg in read_table.iter()? {
+            let (_, value) = pkg?;
+            let pkg = Package::try_from(value.value())?;
+            packages.push(LockedPackage {
+                name: pkg.info.name,
+                version: pkg.info.version.to_string(),
+                url: pkg.source.url,
+                rev: pkg.source.rev,
+                checksum: pkg.source.checksum,
+            });
+        }
+
+        Ok(Self {
+            version: LOCKFILE_VERSION,
+            packages,
+        })
+    }
+
+    /// Write the lockfile to `path` as TOML, atomically (see [`write_output`]).
+    pub fn write(&self, path: &Path) -> Result<(), io::Error> {
+        write_output(Some(path), &toml::to_string(self).unwrap())
+    }
+}
+
+/// Regenerate the lockfile at `path` after `INSTALLED_PKGS` changed.
+/// Errors are only logged: the change is committed already, so it shouldn't be reported as failed.
+pub fn regenerate(db: &Database, path: &Path) {
+    let result = Lockfile::from_db(db)
+        .map_err(|err| err.to_string())
+        .and_then(|lockfile| lockfile.write(path).map_err(|err| err.to_string()));
+    if let Err(err) = result {
+        warn!(
+            "Couldn't write the lockfile {}: {err}",
+            path.to_string_lossy()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::db::Db;
+    use crate::pkg::Installed;
+    use crate::tests::{test_db, test_package};
+
+    #[test]
+    fn test_lockfile() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = test_db(tmpdir.path());
+
+        for (name, checksum) in [("foo", Some("sha256:0123")), ("bar", None)] {
+            let mut pkg = test_package(name, "1.2.0");
+            pkg.source.checksum = checksum.map(str::to_owned);
+            pkg.local.installed = Installed::Manually(pkg.info.version.clone());
+            db.set(INSTALLED_PKGS, name, pkg).unwrap();
+        }
+
+        let lockfile = Lockfile::from_db(&db).unwrap();
+        assert_eq!(lockfile.version, LOCKFILE_VERSION);
+        assert_eq!(
+            lockfile.packages,
+            [
+                LockedPackage {
+                    name: "bar".to_owned(),
+                    version: "1.2.0".to_owned(),
+                    url: "https://example.com/bar-1.2.0.tar.gz".to_owned(),
+                    rev: None,
+                    checksum: None,
+                },
+                LockedPackage {
+                    name: "foo".to_owned(),
+                    version: "1.2.0".to_owned(),
+                    url: "https://example.com/foo-1.2.0.tar.gz".to_owned(),
+                    rev: None,
+                    checksum: Some("sha256:0123".to_owned()),
+                },
+            ]
+        );
+
+        let path = tmpdir.path().join("mercurium.lock");
+        lockfile.write(&path).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with("version = 1\n"));
+        assert_eq!(toml::from_str::<Lockfile>(&contents).unwrap(), lockfile);
+
+        // Removed packages disappear from a regenerated lockfile.
+        db.remove(INSTALLED_PKGS, "foo").unwrap();
+        regenerate(&db, &path);
+        let contents = fs::read_to_string(&path).unwrap();
+        let regenerated = toml::from_str::<Lockfile>(&contents).unwrap();
+        assert_eq!(regenerated.packages, lockfile.packages[..1]);
+    }
+}
@@ -1,27 +1,45 @@
+use std::collections::{BTreeMap, HashSet};
 use std::fs;
+use std::io::{self, IsTerminal};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::OnceLock;
 use std::{error::Error, process::exit};
 
 use clap::Parser;
 use cli::*;
 use config::Config;
-use db::{Db, DbPackage};
+use db::{Db, DbPackage, LegacyDbPackage, Migration};
 use directories::ProjectDirs;
 use exitcode::ExitCode;
+use indicatif::HumanBytes;
+use inquire::{Confirm, InquireError, Select};
 use log::{info, warn, LevelFilter};
+use manifest::{Manifest, ManifestEntry};
 use nucleo_matcher::pattern::{CaseMatching, Pattern};
-use nucleo_matcher::Matcher;
-use payload::Payload;
+use nucleo_matcher::{Matcher, Utf32Str};
+use payload::{Outcome, Payload, Selection};
 use pkg::Package;
 use pkgfile::PackageFile;
-use redb::{Database, ReadableTable, TableDefinition};
+use redb::{Database, ReadableTable, TableDefinition, WriteTransaction};
+use semver::Version;
+use serde::Serialize;
 use simplelog::{ColorChoice, TermLogger, TerminalMode};
 
-use crate::pkg::Installed;
+use crate::pkg::{Dependency, Installed, Local, PackageInfo};
 
+mod audit;
+mod clean;
 mod cli;
+mod completions;
 mod config;
 mod db;
+mod doctor;
+mod lint;
+mod lockfile;
+mod manifest;
+mod output;
 mod payload;
 mod pkg;
 mod pkgfile;
@@ -29,14 +47,75 @@ mod pkgfile;
 static CONFIG: OnceLock<Config> = OnceLock::new();
 static ALL_PKGS: TableDefinition<&str, DbPackage> = TableDefinition::new("all_pkgs");
 static INSTALLED_PKGS: TableDefinition<&str, DbPackage> = TableDefinition::new("installed_pkgs");
+/// Every known version of each package, keyed by [`db::version_key`]. `ALL_PKGS` has the newest one.
+static PKG_VERSIONS: TableDefinition<&str, DbPackage> = TableDefinition::new("pkg_versions");
+/// The versions available in `ALL_PKGS` at the last sync (i.e. the last `update`).
+static SYNCED_VERSIONS: TableDefinition<&str, &str> = TableDefinition::new("synced_versions");
+/// The source URLs confirmed as trusted, with the hash of the commands they were confirmed for.
+static TRUSTED_SOURCES: TableDefinition<&str, &str> = TableDefinition::new("trusted_sources");
+/// Metadata of the database itself, like the schema version.
+static META: TableDefinition<&str, u64> = TableDefinition::new("meta");
+/// The current schema version of the database. Increase it together with adding a migration to `MIGRATIONS`.
+/// Version 1 is the layout before the schema was versioned, see [`LegacyDbPackage`].
+const SCHEMA_VERSION: u64 = 3;
+/// The migrations upgrading older databases to `SCHEMA_VERSION`.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 2,
+        run: upgrade_legacy_rows,
+    },
+    Migration {
+        version: 3,
+        run: record_pkg_versions,
+    },
+];
 static DB: OnceLock<Database> = OnceLock::new();
 static DEBUG: OnceLock<bool> = OnceLock::new();
+static COLOR: OnceLock<bool> = OnceLock::new();
+static FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+static ASSUME_YES: OnceLock<bool> = OnceLock::new();
+static QUIET: OnceLock<bool> = OnceLock::new();
 
 pub type DynResult<T> = Result<T, Box<dyn Error>>;
 
+/// Errors of the command handlers. [`read_args`] reports them to the user instead of panicking.
+#[derive(Debug, thiserror::Error)]
+pub enum AppError {
+    #[error("Couldn't load the config: {0}")]
+    Config(io::Error),
+    #[error("Couldn't access the database: {0}")]
+    Db(#[from] redb::Error),
+    #[error("Couldn't resolve the packages: {0}")]
+    Resolve(Box<dyn Error>),
+    #[error("Couldn't install the packages: {0}")]
+    Install(Box<dyn Error>),
+    #[error("Couldn't add package {name}: {err}")]
+    Add { name: String, err: Box<dyn Error> },
+    #[error("Couldn't remove package {name}: {err}")]
+    Remove { name: String, err: Box<dyn Error> },
+}
+
+impl AppError {
+    /// The exit code to exit with after reporting the error.
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            AppError::Config(_) => exitcode::CONFIG,
+            AppError::Db(_) => exitcode::IOERR,
+            AppError::Resolve(_) => exitcode::DATAERR,
+            AppError::Install(_) | AppError::Add { .. } | AppError::Remove { .. } => {
+                exitcode::SOFTWARE
+            }
+        }
+    }
+}
+
 pub fn init_logging() {
+    init_logging_at(LevelFilter::Trace);
+}
+
+pub fn init_logging_at(level: LevelFilter) {
     TermLogger::init(
-        LevelFilter::Trace,
+        level,
         simplelog::Config::default(),
         TerminalMode::Mixed,
         ColorChoice::Auto,
@@ -44,35 +123,119 @@ pub fn init_logging() {
     .unwrap();
 }
 
+/// Print `message` and exit with `exitcode`.
+/// Errors are printed to stderr, so that they don't end up in piped output.
 pub fn exit_with_message(message: impl AsRef<str>, exitcode: ExitCode) -> ! {
-    let mut prepend = String::new();
-    let mut append = String::new();
     if exitcode::is_error(exitcode) {
-        prepend.push_str("\x1b[31mError!\x1b[0m ");
-        append.push_str("\nAborting...");
+        eprintln!("\x1b[31mError!\x1b[0m {}\nAborting...", message.as_ref());
+    } else {
+        println!("{}", message.as_ref());
     }
-    println!("{prepend}{}{append}", message.as_ref());
     exit(exitcode);
 }
 
-#[cfg(feature = "parallel")]
-#[tokio::main]
+/// Ask the user a yes/no question, defaulting to no.
+/// Invalid answers are asked again. With `--yes`, the question is answered with yes without asking.
+pub fn confirm(message: &str) -> bool {
+    if *ASSUME_YES.get_or_init(|| false) {
+        return true;
+    }
+    require_tty();
+
+    Confirm::new(message)
+        .with_default(false)
+        .prompt()
+        .unwrap_or_else(|err| {
+            let (message, exitcode) = prompt_error(&err);
+            exit_with_message(message, exitcode)
+        })
+}
+
+/// Ask the user to select one of `options`. Returns the index of the selected option.
+pub fn select(message: &str, options: Vec<String>) -> usize {
+    require_tty();
+    Select::new(message, options)
+        .raw_prompt()
+        .map(|option| option.index)
+        .unwrap_or_else(|err| {
+            let (message, exitcode) = prompt_error(&err);
+            exit_with_message(message, exitcode)
+        })
+}
+
+/// Message for prompts that can't be shown because stdin isn't a terminal.
+const NO_TTY_MESSAGE: &str = "Refusing to prompt without a TTY; pass --yes.";
+
+/// Exit with a clear message instead of prompting if stdin isn't a terminal,
+/// e.g. because mercurium is piped into another tool.
+fn require_tty() {
+    if !io::stdin().is_terminal() {
+        exit_with_message(NO_TTY_MESSAGE, exitcode::USAGE);
+    }
+}
+
+/// Get the message and exit code for a prompt that failed with `err`.
+fn prompt_error(err: &InquireError) -> (String, ExitCode) {
+    match err {
+        InquireError::NotTTY => (NO_TTY_MESSAGE.to_owned(), exitcode::USAGE),
+        InquireError::IO(err) if err.kind() == io::ErrorKind::UnexpectedEof => (
+            "Reached the end of stdin while asking; pass --yes.".to_owned(),
+            exitcode::USAGE,
+        ),
+        InquireError::OperationCanceled | InquireError::OperationInterrupted => {
+            ("Aborting...".to_owned(), exitcode::OK)
+        }
+        err => (format!("Couldn't read the answer: {err}"), exitcode::IOERR),
+    }
+}
+
+// Downloads are asynchronous either way. Without `parallel`, they run on a single thread.
+#[cfg_attr(feature = "parallel", tokio::main)]
+#[cfg_attr(not(feature = "parallel"), tokio::main(flavor = "current_thread"))]
 async fn main() -> DynResult<()> {
     color_eyre::install().unwrap();
 
-    read_args().await;
+    if let Err(err) = read_args().await {
+        exit_with_message(err.to_string(), err.exit_code());
+    }
 
     Ok(())
 }
 
-#[cfg(not(feature = "parallel"))]
-fn main() {
-    color_eyre::install().unwrap();
+/// Get the log level for the number of `-v` flags.
+fn log_level(verbose: u8) -> LevelFilter {
+    match verbose {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
 
-    read_args()
+/// Apply the options of `command` that take precedence over the config for this run.
+fn override_config(conf: &mut Config, command: &Commands) {
+    if let Commands::Install(InstallArgs { download, .. })
+    | Commands::Update(UpdateArgs { download, .. })
+    | Commands::Reinstall(ReinstallArgs { download, .. })
+    | Commands::Downgrade(DowngradeArgs { download, .. })
+    | Commands::Import(ImportArgs { download, .. }) = command
+    {
+        if download.timeout.is_some() {
+            conf.timeout = download.timeout;
+        }
+        if download.retries.is_some() {
+            conf.retries = download.retries;
+        }
+    }
+    if let Commands::Install(InstallArgs {
+        jobs: Some(jobs), ..
+    }) = command
+    {
+        conf.jobs = Some(*jobs);
+    }
 }
 
-pub async fn read_args() {
+pub async fn read_args() -> Result<(), AppError> {
     let cli = Cli::parse();
 
     #[cfg(debug_assertions)]
@@ -80,6 +243,25 @@ pub async fn read_args() {
 
     if *DEBUG.get_or_init(|| false) {
         init_logging();
+    } else {
+        init_logging_at(log_level(cli.verbose));
+    }
+
+    COLOR
+        .set(match cli.color {
+            _ if cli.no_color => false,
+            ColorMode::Auto => io::stdout().is_terminal(),
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+        })
+        .expect("error setting color mode");
+    FORMAT.set(cli.format).expect("error setting output format");
+    ASSUME_YES.set(cli.yes).expect("error setting yes flag");
+    QUIET.set(cli.quiet).expect("error setting quiet flag");
+
+    if let Commands::Completions(args) = &cli.command {
+        completions(args);
+        return Ok(());
     }
 
     let mut conf_path;
@@ -94,231 +276,2443 @@ pub async fn read_args() {
         }
     }
 
-    CONFIG
-        .set(Config::load(&conf_path).unwrap())
-        .expect("error setting config");
+    let mut conf = Config::load(&conf_path).map_err(AppError::Config)?;
+    override_config(&mut conf, &cli.command);
+    CONFIG.set(conf).expect("error setting config");
     DB.set(
         Database::create(CONFIG.get().unwrap().packages_path().join("packages.db"))
             .unwrap_or_else(|_| exit_with_message("Couldn't create database", exitcode::CANTCREAT)),
     )
     .expect("error setting database");
 
-    DB.get()
-        .unwrap()
-        .init_table(ALL_PKGS)
-        .expect("error initiating database tables");
-    DB.get()
-        .unwrap()
-        .init_table(INSTALLED_PKGS)
-        .expect("error initiating database tables");
+    let db = DB.get().unwrap();
+    let schema_version = db::migrate(db, META, SCHEMA_VERSION, MIGRATIONS)?;
+    if schema_version > SCHEMA_VERSION {
+        exit_with_message(
+            format!("The database has schema version {schema_version}, which is newer than this version of mercurium supports ({SCHEMA_VERSION})! Please update mercurium."),
+            exitcode::DATAERR,
+        );
+    }
+    db.init_table(ALL_PKGS)?;
+    db.init_table(INSTALLED_PKGS)?;
+    db.init_table(PKG_VERSIONS)?;
+    init_tables(db)?;
+
+    if cli.check_integrity {
+        check_integrity(true)?;
+    }
 
     match &cli.command {
-        Commands::Install(args) => {
-            if args.local {
-                install_local(args).await;
-            } else {
-                install(args).await;
-            }
-        }
-        Commands::Add(args) => add(args),
+        Commands::Install(args) => install(args).await,
+        Commands::Add(args) => add(args).await,
         Commands::Remove(args) => remove(args),
-        Commands::Update(args) => update(args).await, // TODO
+        Commands::Autoremove => autoremove(),
+        Commands::Update(args) => update(args).await,
+        Commands::Outdated(args) => outdated(args),
+        Commands::Reinstall(args) => reinstall(args).await,
+        Commands::Downgrade(args) => downgrade(args).await,
+        Commands::Pin(args) => pin(args, true),
+        Commands::Unpin(args) => pin(args, false),
         Commands::Search(args) => search(args),
         Commands::List(args) => list(args),
+        Commands::Info(args) => info(args),
+        Commands::Rdepends(args) => rdepends(args),
+        Commands::Doctor(args) => doctor(args),
+        Commands::Audit => audit(),
+        Commands::Lint(args) => lint(args),
+        Commands::Export(args) => export(args),
+        Commands::Import(args) => import(args).await,
+        Commands::Clean(args) => clean(args),
+        Commands::Completions(_) => {
+            unreachable!("completions are handled before loading the config")
+        }
         #[cfg(debug_assertions)]
-        Commands::Config => config(),
+        Commands::Config => {
+            config();
+            Ok(())
+        }
     }
 }
 
-async fn install_local(args: &InstallArgs) {
-    let InstallArgs { pkgs, .. } = args;
+fn completions(args: &CompletionsArgs) {
+    let CompletionsArgs {
+        shell,
+        install,
+        dir,
+    } = args;
 
-    let mut pkgfiles: Vec<PackageFile> = Vec::new();
-    for pkg in pkgs {
-        let pkg_content = fs::read_to_string(pkg)
-            .unwrap_or_else(|_| exit_with_message("Couldn't access file", exitcode::NOINPUT));
+    let shell = shell.or_else(completions::detect_shell).unwrap_or_else(|| {
+        exit_with_message(
+            "Couldn't detect your shell! Please specify one.",
+            exitcode::USAGE,
+        )
+    });
+
+    if !install {
+        completions::print_completions(shell);
+        return;
+    }
 
-        let pkgfile: PackageFile = toml::from_str(&pkg_content).unwrap_or_else(|_| {
-            exit_with_message("Invalid package file format", exitcode::DATAERR)
+    let dir = dir
+        .clone()
+        .or_else(|| completions::completion_dir(shell))
+        .unwrap_or_else(|| {
+            exit_with_message(
+                format!(
+                    "No known completion directory for {shell}! Please specify one using --dir."
+                ),
+                exitcode::USAGE,
+            )
         });
-        pkgfiles.push(pkgfile);
+    let path = completions::install_completions(shell, &dir)
+        .unwrap_or_else(|_| exit_with_message("Couldn't write completions", exitcode::CANTCREAT));
+    println!("Installed completions to {}.", path.to_string_lossy());
+}
+
+fn doctor(args: &DoctorArgs) -> Result<(), AppError> {
+    let DoctorArgs { repair } = args;
+
+    if *repair {
+        for name in doctor::repair(DB.get().unwrap())? {
+            println!("Repaired installation state of package {name}.");
+        }
+        lockfile::regenerate(DB.get().unwrap(), &CONFIG.get().unwrap().lockfile_path());
     }
 
-    let mut payload = Payload::new();
-    for pkg in pkgfiles {
-        payload.add_pkgfile(pkg).expect("error reading database");
+    warn_duplicates(DB.get().unwrap(), |_| true)?;
+    check_integrity(false)
+}
+
+/// Warn about packages sharing their source with other packages, if one of them matches `filter`.
+fn warn_duplicates(db: &Database, filter: impl Fn(&str) -> bool) -> Result<(), AppError> {
+    let duplicates = doctor::duplicates(db)?;
+    for duplicate in duplicates {
+        if duplicate.names.iter().any(|name| filter(name)) {
+            println!("\x1b[33mWarning!\x1b[0m {duplicate}. Consider removing all but one of them.");
+        }
+    }
+    Ok(())
+}
+
+fn audit() -> Result<(), AppError> {
+    let findings = audit::audit(DB.get().unwrap())?;
+
+    if findings.is_empty() {
+        println!("No potentially dangerous scripts found.");
+        return Ok(());
+    }
+
+    println!("Scripts to review:");
+    for finding in findings {
+        println!("- {finding}");
+    }
+    Ok(())
+}
+
+fn lint(args: &LintArgs) -> Result<(), AppError> {
+    let LintArgs { file } = args;
+
+    let content = fs::read_to_string(file)
+        .unwrap_or_else(|_| exit_with_message("Couldn't access file", exitcode::NOINPUT));
+    let lints = lint::lint(&content, DB.get().unwrap())?;
+
+    if lints.is_empty() {
+        println!("No problems found.");
+        return Ok(());
+    }
+
+    let mut message = format!("Problems in {}:", file.to_string_lossy());
+    for lint in lints {
+        message.push_str(&format!("\n- {lint}"));
+    }
+    exit_with_message(message, exitcode::DATAERR);
+}
+
+fn clean(args: &CleanArgs) -> Result<(), AppError> {
+    let CleanArgs {
+        orphaned_sources,
+        sources,
+        builds,
+        all,
+    } = args;
+
+    let kinds = if *all || !(*sources || *builds) {
+        clean::Caches::ALL
+    } else {
+        clean::Caches {
+            sources: *sources,
+            builds: *builds,
+        }
+    };
+
+    let conf = CONFIG.get().unwrap();
+    let caches = if *orphaned_sources {
+        clean::orphaned_caches(DB.get().unwrap(), conf, kinds)
+            .unwrap_or_else(|err| exit_with_message(err.to_string(), exitcode::IOERR))
+    } else {
+        clean::caches(conf, kinds)
+            .unwrap_or_else(|_| exit_with_message("Couldn't access caches", exitcode::IOERR))
+    };
+
+    for path in &caches {
+        info!("Removing {}.", path.to_string_lossy());
+    }
+    let reclaimed = clean::remove(&caches)
+        .unwrap_or_else(|_| exit_with_message("Couldn't remove caches", exitcode::IOERR));
+    clean::recreate_dirs(conf, kinds).unwrap_or_else(|_| {
+        exit_with_message("Couldn't recreate cache directories", exitcode::CANTCREAT)
+    });
+    println!(
+        "Removed {} caches, reclaimed {}.",
+        caches.len(),
+        HumanBytes(reclaimed)
+    );
+    Ok(())
+}
+
+/// Check the database for problems and report them.
+/// If `quiet` is set, nothing is printed if no problems were found.
+fn check_integrity(quiet: bool) -> Result<(), AppError> {
+    let problems = doctor::check_integrity(DB.get().unwrap())?;
+
+    if problems.is_empty() {
+        if !quiet {
+            println!("No problems found.");
+        }
+        return Ok(());
+    }
+
+    let mut message = String::from("The database is inconsistent:");
+    for problem in problems {
+        message.push_str(&format!("\n- {problem}"));
     }
-    payload.install().await.expect("error installing packages"); // TODO: Better errors
+    exit_with_message(message, exitcode::DATAERR);
 }
 
-async fn install(args: &InstallArgs) {
-    let InstallArgs { pkgs, .. } = args;
+/// Prefix marking an argument of `install` as a local pkgfile.
+const PKGFILE_PREFIX: &str = "file:";
+
+async fn install(args: &InstallArgs) -> Result<(), AppError> {
+    let InstallArgs {
+        pkgs,
+        local,
+        strict,
+        trust,
+        no_build,
+        interactive,
+        print_script,
+        overwrite,
+        only_dependencies,
+        jobs: _,
+        download: _,
+    } = args;
 
     let mut payload = Payload::new();
-    for pkg in pkgs {
-        payload.add_pkg(pkg).expect("error reading database");
+    payload.strict = *strict;
+    payload.trust = *trust;
+    payload.assume_yes = *ASSUME_YES.get().unwrap();
+    payload.quiet = *QUIET.get().unwrap();
+    payload.overwrite = *overwrite;
+    payload.only_dependencies = *only_dependencies;
+    payload.no_build = *no_build;
+    if *interactive {
+        payload.selection = Selection::Interactive;
+    }
+    add_install_args(&mut payload, DB.get().unwrap(), pkgs, *local)
+        .unwrap_or_else(|err| exit_with_message(err.to_string(), exitcode::DATAERR));
+
+    if *print_script {
+        let plan = payload.resolve().map_err(AppError::Resolve)?;
+        print!("{}", payload.script(&plan, CONFIG.get().unwrap()));
+        return Ok(());
     }
-    payload.install().await.expect("error installing packages"); // TODO: Better errors
+    payload.install().await.map_err(AppError::Install)?;
+    Ok(())
 }
 
-fn add(args: &AddArgs) {
-    let AddArgs { pkgs } = args;
+/// Read and parse the pkgfile at `path`.
+fn read_pkgfile(path: impl AsRef<Path>) -> PackageFile {
+    let pkg_content = fs::read_to_string(path)
+        .unwrap_or_else(|_| exit_with_message("Couldn't access file", exitcode::NOINPUT));
 
+    toml::from_str(&pkg_content)
+        .unwrap_or_else(|_| exit_with_message("Invalid package file format", exitcode::DATAERR))
+}
+
+/// Add the arguments `pkgs` of `install` to `payload`.
+/// An argument is a local pkgfile if `local` is set, it is prefixed with `file:` or a file with this path exists.
+/// Otherwise, it is the name of a package in the database.
+fn add_install_args(
+    payload: &mut Payload,
+    db: &Database,
+    pkgs: &[String],
+    local: bool,
+) -> DynResult<()> {
     for pkg in pkgs {
-        let pkg_content = fs::read_to_string(pkg)
-            .unwrap_or_else(|_| exit_with_message("Couldn't access file", exitcode::NOINPUT));
-        let pkgfile: PackageFile = toml::from_str(&pkg_content).unwrap_or_else(|_| {
-            exit_with_message("Invalid package file format", exitcode::DATAERR)
-        });
+        if let Some(path) = pkg.strip_prefix(PKGFILE_PREFIX) {
+            payload.add_pkgfile(db, read_pkgfile(path))?;
+        } else if local || Path::new(pkg).is_file() {
+            payload.add_pkgfile(db, read_pkgfile(pkg))?;
+        } else {
+            payload.add_pkg(db, pkg)?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn add(args: &AddArgs) -> Result<(), AppError> {
+    let AddArgs {
+        pkgs,
+        register,
+        pkg_version,
+        install,
+    } = args;
+
+    if *register {
+        let version = Version::from_str(pkg_version)
+            .unwrap_or_else(|_| exit_with_message("Invalid version", exitcode::USAGE));
+        let db = DB.get().unwrap();
+
+        for path in pkgs {
+            let files: Vec<PathBuf> = if path.is_dir() {
+                fs::read_dir(path)
+                    .and_then(|dir| dir.map(|entry| entry.map(|entry| entry.path())).collect())
+                    .unwrap_or_else(|_| {
+                        exit_with_message("Couldn't access directory", exitcode::NOINPUT)
+                    })
+            } else {
+                vec![path.clone()]
+            };
+
+            for file in files.into_iter().filter(|file| file.is_file()) {
+                let pkg = register_binary(db, &file, version.clone())
+                    .unwrap_or_else(|err| exit_with_message(err.to_string(), exitcode::DATAERR));
+                lockfile::regenerate(db, &CONFIG.get().unwrap().lockfile_path());
+                println!(
+                    "Registered {} as package {}.",
+                    file.to_string_lossy(),
+                    pkg.info.name
+                );
+            }
+        }
+
+        return Ok(());
+    }
+
+    let db = DB.get().unwrap();
+    let pkgfiles: Vec<PackageFile> = pkgs.iter().map(read_pkgfile).collect();
+
+    let added: HashSet<String> = pkgfiles
+        .iter()
+        .map(|pkgfile| pkgfile.info.name.clone())
+        .collect();
+    if *install {
+        // The payload adds the pkgfiles in the same transaction as it records the installation,
+        // so nothing is added if the installation fails or is aborted.
+        let mut payload = Payload::new();
+        payload.assume_yes = *ASSUME_YES.get().unwrap();
+        payload.quiet = *QUIET.get().unwrap();
+        for pkgfile in pkgfiles {
+            payload
+                .add_pkgfile(db, pkgfile)
+                .unwrap_or_else(|err| exit_with_message(err.to_string(), exitcode::DATAERR));
+        }
+        if payload.install().await.map_err(AppError::Install)? == Outcome::Aborted {
+            return Ok(());
+        }
+    } else {
+        for pkgfile in pkgfiles {
+            info!("Adding package {} to database.", pkgfile.info.name);
+            let name = pkgfile.info.name.clone();
+            pkgfile
+                .add_to_db(db)
+                .map_err(|err| AppError::Add { name, err })?;
+        }
+    }
+
+    warn_duplicates(db, |name| added.contains(name))
+}
+
+/// Register the existing binary at `path` as a manually installed package named after the file.
+fn register_binary(db: &Database, path: &Path, version: Version) -> DynResult<Package> {
+    let path = path.canonicalize()?;
+    let name = path
+        .file_name()
+        .ok_or("invalid binary path")?
+        .to_string_lossy()
+        .into_owned();
 
-        info!("Adding package {} to database.", pkgfile.info.name);
-        pkgfile.add_to_db().expect("error modifying database");
+    if db.contains(ALL_PKGS, name.as_str())? {
+        return Err(format!("Package {name} already exists!").into());
     }
+
+    info!("Registering {} as package {name}.", path.to_string_lossy());
+    let pkgfile = PackageFile::builder()
+        .name(name.as_str())
+        .version(version.clone())
+        .license("unknown")
+        .url(format!("file://{}", path.to_string_lossy()))
+        .install_command(format!(
+            "cp {} \"${{binary}}\"",
+            Payload::shell_quote(path.as_os_str())
+        ))
+        .build()?;
+    let pkg = Package::from_file(
+        pkgfile,
+        Local {
+            installed: Installed::Manually(version),
+            added: true,
+            files: vec![path],
+            last_installed: Installed::False,
+            installed_at: None,
+            pinned: false,
+        },
+    );
+
+    db.set(ALL_PKGS, name.as_str(), pkg.clone())?;
+    db.set(INSTALLED_PKGS, name.as_str(), pkg.clone())?;
+
+    Ok(pkg)
 }
 
-fn remove(args: &RemoveArgs) {
-    let RemoveArgs { pkgs } = args;
+fn remove(args: &RemoveArgs) -> Result<(), AppError> {
+    let RemoveArgs { pkgs, purge, force } = args;
 
-    // TODO: Remove!
     let db = DB.get().unwrap();
+    let conf = CONFIG.get().unwrap();
+
+    for pkg_name in pkgs {
+        let dependents: Vec<String> = dependents(db, pkg_name, false)?
+            .into_iter()
+            .filter(|name| !pkgs.contains(name))
+            .collect();
+        if dependents.is_empty() {
+            continue;
+        }
+        let message = format!(
+            "Package {pkg_name} is required by {}.",
+            dependents.join(", ")
+        );
+        if !force {
+            exit_with_message(
+                format!("{message} Pass --force to remove it anyway."),
+                exitcode::DATAERR,
+            );
+        }
+        println!("\x1b[33mWarning!\x1b[0m {message}");
+    }
+
     for pkg_name in pkgs {
         info!("Removing package {}.", pkg_name);
-        db.modify(ALL_PKGS, pkg_name.as_str(), |val| {
-            let mut val = val.unwrap();
-            val.local.installed = Installed::False;
-            Some(val)
-        })
-        .expect("error modifying database");
-        db.remove(INSTALLED_PKGS, pkg_name.as_str())
-            .expect("error modifying database");
+        remove_pkg(db, conf, pkg_name, *purge).map_err(|err| AppError::Remove {
+            name: pkg_name.clone(),
+            err,
+        })?;
+    }
+    Ok(())
+}
+
+fn rdepends(args: &RdependsArgs) -> Result<(), AppError> {
+    let RdependsArgs { pkg, all } = args;
+
+    let dependents = dependents(DB.get().unwrap(), pkg, *all)?;
+    if dependents.is_empty() {
+        println!("No package depends on {pkg}.");
+    }
+    for name in dependents {
+        println!("{name}");
     }
+    Ok(())
 }
 
-async fn update(args: &UpdateArgs) {
-    let UpdateArgs { pkgs } = args;
+/// Get the names of the installed packages (or all packages if `all` is set) depending on `name`, sorted.
+fn dependents(db: &Database, name: &str, all: bool) -> Result<Vec<String>, redb::Error> {
+    let read_txn = db.begin_read()?;
+    let read_table = if all {
+        read_txn.open_table(ALL_PKGS)?
+    } else {
+        read_txn.open_table(INSTALLED_PKGS)?
+    };
+
+    let mut dependents = Vec::new();
+    for pkg in read_table.iter()? {
+        let (key, value) = pkg?;
+        if value
+            .value()
+            .dependencies
+            .iter()
+            .any(|dep| Dependency::name_of(dep) == name)
+        {
+            dependents.push(key.value().to_owned());
+        }
+    }
+
+    Ok(dependents)
+}
 
+fn autoremove() -> Result<(), AppError> {
     let db = DB.get().unwrap();
-    let mut payload = Payload::new();
+    let conf = CONFIG.get().unwrap();
 
-    match pkgs {
-        Some(pkgs) => {
-            let iter = db
-                .get_iter(INSTALLED_PKGS, pkgs.iter().map(|k| k.as_str()))
-                .expect("error reading database")
-                .into_iter()
-                .zip(pkgs)
-                .map(|(pkg, name)| {
-                    pkg.unwrap_or_else(|| {
-                        exit_with_message(format!("Package {} not found!", name), exitcode::DATAERR)
-                    })
-                })
-                .filter(|pkg| {
-                    if let Some(installed_ver) = pkg.local.installed.version() {
-                        &pkg.info.version > installed_ver
-                    } else {
-                        warn!("Invalid database state: Package {} in table INSTALLED_PKGS, but installed is set to False.", pkg.info.name);
-                        false
-                    }
-                });
+    let orphans = orphans(db)?;
+    if orphans.is_empty() {
+        println!("No packages to remove.");
+        return Ok(());
+    }
 
-            for pkg in iter {
-                payload
-                    .add_pkg(&pkg.info.name) // Optimization: Take DbPackage directly
-                    .expect("error reading database");
+    println!("Packages marked to be removed:");
+    println!("{}", orphans.join(", "));
+
+    if !confirm("Do you want to remove these packages?") {
+        exit_with_message("Aborting...", exitcode::OK);
+    }
+
+    for name in orphans {
+        info!("Removing package {name}.");
+        remove_pkg(db, conf, &name, false).map_err(|err| AppError::Remove { name, err })?;
+    }
+    Ok(())
+}
+
+/// Get the automatically installed packages that no other installed package depends on, sorted by name.
+/// Packages only depended on by such orphans are orphans as well.
+fn orphans(db: &Database) -> Result<Vec<String>, redb::Error> {
+    let read_txn = db.begin_read()?;
+    let read_table = read_txn.open_table(INSTALLED_PKGS)?;
+
+    let mut remaining: BTreeMap<String, Package> = BTreeMap::new();
+    for pkg in read_table.iter()? {
+        let (key, value) = pkg?;
+        remaining.insert(key.value().to_owned(), Package::try_from(value.value())?);
+    }
+
+    let mut orphans = Vec::new();
+    loop {
+        let dependencies: HashSet<&str> = remaining
+            .values()
+            .flat_map(|pkg| pkg.info.dependencies.iter().flatten())
+            .map(|dep| Dependency::name_of(dep))
+            .collect();
+        let new: Vec<String> = remaining
+            .iter()
+            .filter(|(name, pkg)| {
+                matches!(pkg.local.installed, Installed::Automatically(_))
+                    && !dependencies.contains(name.as_str())
+                    && !pkg
+                        .info
+                        .provides
+                        .iter()
+                        .flatten()
+                        .any(|provides| dependencies.contains(provides.as_str()))
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        if new.is_empty() {
+            break;
+        }
+        for name in &new {
+            remaining.remove(name);
+        }
+        orphans.extend(new);
+    }
+
+    orphans.sort();
+    Ok(orphans)
+}
+
+/// Get the files installed by installed packages other than `name`.
+fn files_of_others(db: &Database, name: &str) -> Result<HashSet<PathBuf>, redb::Error> {
+    let read_txn = db.begin_read()?;
+    let read_table = read_txn.open_table(INSTALLED_PKGS)?;
+
+    let mut files = HashSet::new();
+    for pkg in read_table.iter()? {
+        let (key, value) = pkg?;
+        if key.value() != name {
+            files.extend(Package::try_from(value.value())?.local.files);
+        }
+    }
+
+    Ok(files)
+}
+
+/// Remove the installed package `name` and delete its files.
+/// Files also installed by other packages are kept.
+/// Config files declared by the package are kept unless `purge` is set.
+/// Purging also deletes the cached source and build and removes the package from `ALL_PKGS`.
+fn remove_pkg(db: &Database, conf: &Config, name: &str, purge: bool) -> DynResult<()> {
+    let pkg = db.get(INSTALLED_PKGS, name)?.unwrap_or_else(|| {
+        exit_with_message(
+            format!("Package {name} is not installed!"),
+            exitcode::DATAERR,
+        )
+    });
+
+    Payload::uninstall_pkg(&pkg, conf)?;
+
+    let config_files = pkg.source.config_paths(conf.binaries_path());
+    let shared = files_of_others(db, name)?;
+    for file in &pkg.local.files {
+        if config_files.contains(file) {
+            continue;
+        }
+        if shared.contains(file) {
+            warn!(
+                "Keeping {}, because it is also installed by another package.",
+                file.to_string_lossy()
+            );
+            continue;
+        }
+        if file.exists() {
+            info!("Removing {}.", file.to_string_lossy());
+            fs::remove_file(file)?;
+        }
+    }
+
+    if purge {
+        for path in config_files {
+            if path.exists() {
+                info!("Removing config file {}.", path.to_string_lossy());
+                fs::remove_file(path)?;
             }
         }
-        None => {
-            let read_txn = db.begin_read().expect("error reading database");
-            let read_table = read_txn
-                .open_table(INSTALLED_PKGS)
-                .expect("error reading database");
-
-            let iter = read_table
-                .iter()
-                .expect("error reading database")
-                .map(|pkg| Into::<Package>::into(pkg.as_ref().expect("error reading database").1.value()))
-                .filter(|pkg| {
-                    if let Some(installed_ver) = pkg.local.installed.version() {
-                        &pkg.info.version > installed_ver
-                    } else {
-                        warn!("Invalid database state: Package {} in table INSTALLED_PKGS, but installed is set to False.", pkg.info.name);
-                        false
-                    }
-                });
 
-            for pkg in iter {
-                payload
-                    .add_pkg(&pkg.info.name) // Optimization: Take DbPackage directly
-                    .expect("error reading database");
+        let tarball = conf.tarball_path(&pkg.info);
+        if tarball.exists() {
+            info!("Removing source {}.", tarball.to_string_lossy());
+            fs::remove_file(tarball)?;
+        }
+        let build = conf.build_path(&pkg.info);
+        if build.exists() {
+            info!("Removing build {}.", build.to_string_lossy());
+            fs::remove_dir_all(build)?;
+        }
+    }
+
+    // Update both tables in one transaction, so they can't get out of sync.
+    let write_txn = db.begin_write()?;
+    {
+        let mut installed_table = write_txn.open_table(INSTALLED_PKGS)?;
+        let mut all_table = write_txn.open_table(ALL_PKGS)?;
+        let mut versions_table = write_txn.open_table(PKG_VERSIONS)?;
+
+        installed_table.remove(name)?;
+        if purge {
+            all_table.remove(name)?;
+            for version in db::versions_in(&versions_table, name)? {
+                versions_table.remove(db::version_key(name, version).as_str())?;
+            }
+        } else {
+            let pkg = all_table.get(name)?.map(|pkg| pkg.value());
+            if let Some(mut pkg) = pkg {
+                pkg.last_installed = pkg.installed;
+                pkg.installed = Installed::False;
+                pkg.installed_at = None;
+                all_table.insert(name, pkg)?;
             }
         }
     }
+    write_txn.commit()?;
+    lockfile::regenerate(db, &conf.lockfile_path());
 
-    payload.install().await.expect("error installing packages"); // TODO: Better errors
+    Ok(())
 }
 
-fn search(args: &SearchArgs) {
-    let SearchArgs { pkg, installed } = args;
+async fn update(args: &UpdateArgs) -> Result<(), AppError> {
+    let UpdateArgs {
+        pkgs,
+        incremental,
+        download: _,
+    } = args;
 
     let db = DB.get().unwrap();
-    let read_txn = db.begin_read().expect("error reading database");
-    let read_table = read_txn
-        .open_table(ALL_PKGS)
-        .expect("error reading database");
+    let mut payload = Payload::new();
+    payload.assume_yes = *ASSUME_YES.get().unwrap();
+    payload.quiet = *QUIET.get().unwrap();
 
-    let iter = read_table
-        .iter()
-        .expect("error reading database")
-        .map(|x| x.expect("error reading database"))
-        .filter(|x| x.1.value().installed.into() || !installed)
-        .map(|x| x.0.value().to_owned().clone());
+    for name in pkgs.iter().flatten() {
+        if !db.contains(INSTALLED_PKGS, name)? {
+            exit_with_message(format!("Package {} not found!", name), exitcode::DATAERR)
+        }
+    }
 
-    let mut conf = nucleo_matcher::Config::DEFAULT;
-    conf.ignore_case = true;
-    let mut matcher = Matcher::new(conf);
-    let mut matches: Vec<(String, u32)> =
-        Pattern::parse(pkg, CaseMatching::Ignore).match_list(iter, &mut matcher);
-    matches.sort_by_key(|(_, k)| *k);
+    let refreshed = refresh_metadata(db, pkgs.as_deref())?;
+    for name in &refreshed {
+        println!("Updated metadata of package {name}.");
+    }
+    if !refreshed.is_empty() {
+        lockfile::regenerate(db, &CONFIG.get().unwrap().lockfile_path());
+    }
+
+    let conf = CONFIG.get().unwrap();
+    let outdated = outdated_pkgs(db, conf, pkgs.as_deref(), *incremental)?;
+    for pkg in &outdated.pkgs {
+        payload
+            .add_pkg(db, &pkg.info.name) // Optimization: Take DbPackage directly
+            .map_err(AppError::Resolve)?;
+    }
 
-    for (s, _) in matches {
-        println!("{s}");
+    // Up-to-date packages count as seen right away, outdated ones only once they are installed.
+    record_synced_versions(db, &outdated.up_to_date)?;
+    match payload.install().await.map_err(AppError::Install)? {
+        Outcome::Installed | Outcome::UpToDate => record_synced_versions(db, &outdated.versions())?,
+        Outcome::Aborted => (),
     }
+    Ok(())
 }
 
-fn list(args: &ListArgs) {
-    let ListArgs { all } = args;
+fn outdated(args: &OutdatedArgs) -> Result<(), AppError> {
+    let OutdatedArgs { pkgs, incremental } = args;
 
     let db = DB.get().unwrap();
-    let read_txn = db.begin_read().expect("error reading database");
-    let read_table = if *all {
-        read_txn
-            .open_table(ALL_PKGS)
-            .expect("error reading database")
-    } else {
-        read_txn
-            .open_table(INSTALLED_PKGS)
-            .expect("error reading database")
-    };
+    for name in pkgs.iter().flatten() {
+        if !db.contains(INSTALLED_PKGS, name)? {
+            exit_with_message(format!("Package {} not found!", name), exitcode::DATAERR)
+        }
+    }
 
-    let mut pkgs: Vec<(String, bool)> = Vec::new();
+    // Listing doesn't record the versions as synced, only `update` does.
+    let outdated = outdated_pkgs(db, CONFIG.get().unwrap(), pkgs.as_deref(), *incremental)?;
+    if outdated.pkgs.is_empty() {
+        println!("All packages are up to date.");
+    }
+    for pkg in outdated.pkgs {
+        let installed = db
+            .get(INSTALLED_PKGS, &pkg.info.name)?
+            .and_then(|installed| installed.local.installed.version().cloned());
+        match installed {
+            Some(installed) => println!("{} {installed} -> {}", pkg.info.name, pkg.info.version),
+            None => println!("{} {}", pkg.info.name, pkg.info.version),
+        }
+    }
+    Ok(())
+}
 
-    for pkg in read_table.iter().expect("error reading database") {
-        let (key, value) = pkg.expect("error reading database");
+async fn reinstall(args: &ReinstallArgs) -> Result<(), AppError> {
+    let ReinstallArgs { pkgs, download: _ } = args;
 
-        pkgs.push((key.value().to_owned(), value.value().installed.into()));
-    }
+    let db = DB.get().unwrap();
+    let mut payload = Payload::new();
+    payload.assume_yes = *ASSUME_YES.get().unwrap();
+    payload.quiet = *QUIET.get().unwrap();
+    payload.force = true;
 
-    pkgs.sort_by_key(|x| x.0.to_lowercase());
-    pkgs.into_iter().for_each(|(name, installed)| {
-        let mut to_print = name;
-        if *all && installed {
-            to_print.push_str(" [Installed]");
+    for name in pkgs {
+        if !db.contains(INSTALLED_PKGS, name)? {
+            exit_with_message(
+                format!("Package {name} is not installed!"),
+                exitcode::DATAERR,
+            );
         }
-        println!("{to_print}");
+        payload.add_pkg(db, name).map_err(AppError::Resolve)?;
+    }
+
+    payload.install().await.map_err(AppError::Install)?;
+    Ok(())
+}
+
+async fn downgrade(args: &DowngradeArgs) -> Result<(), AppError> {
+    let DowngradeArgs {
+        pkg,
+        version,
+        download: _,
+    } = args;
+
+    let db = DB.get().unwrap();
+    let version = Version::from_str(version).unwrap_or_else(|err| {
+        exit_with_message(format!("Invalid version {version}: {err}"), exitcode::USAGE)
     });
+    let Some(older) = older_versions(db, pkg)? else {
+        exit_with_message(
+            format!("Package {pkg} is not installed!"),
+            exitcode::DATAERR,
+        );
+    };
+    if !older.contains(&version) {
+        let older: Vec<String> = older.iter().map(Version::to_string).collect();
+        exit_with_message(
+            format!(
+                "Version {version} of package {pkg} is unknown or not older than the installed one! Older versions: {}",
+                if older.is_empty() { "none".to_owned() } else { older.join(", ") }
+            ),
+            exitcode::DATAERR,
+        );
+    }
+
+    let mut payload = Payload::new();
+    payload.assume_yes = *ASSUME_YES.get().unwrap();
+    payload.quiet = *QUIET.get().unwrap();
+    // `check_install` skips packages whose installed version is at least the new one.
+    payload.force = true;
+    payload
+        .add_pkg(db, &format!("{pkg}@{version}"))
+        .map_err(AppError::Resolve)?;
+
+    payload.install().await.map_err(AppError::Install)?;
+    Ok(())
 }
 
-#[cfg(debug_assertions)]
-fn config() {
-    dbg!(CONFIG.get().unwrap());
+/// Pin (or unpin if `pinned` isn't set) the installed packages of `args`.
+fn pin(args: &PinArgs, pinned: bool) -> Result<(), AppError> {
+    let PinArgs { pkgs } = args;
+
+    let db = DB.get().unwrap();
+    let mut missing = Vec::new();
+    for name in pkgs {
+        if !set_pinned(db, name, pinned)? {
+            missing.push(name.as_str());
+        }
+    }
+    lockfile::regenerate(db, &CONFIG.get().unwrap().lockfile_path());
+
+    if !missing.is_empty() {
+        exit_with_message(
+            format!("Packages {} are not installed!", missing.join(", ")),
+            exitcode::DATAERR,
+        );
+    }
+    Ok(())
+}
+
+/// Set whether the installed package `name` is pinned in `ALL_PKGS` and `INSTALLED_PKGS`.
+/// Returns whether the package is installed, nothing is changed otherwise.
+fn set_pinned(db: &Database, name: &str, pinned: bool) -> Result<bool, redb::Error> {
+    if !db.contains(INSTALLED_PKGS, name)? {
+        return Ok(false);
+    }
+    for table in [INSTALLED_PKGS, ALL_PKGS] {
+        db.modify(table, name, |pkg| {
+            pkg.map(|mut pkg| {
+                pkg.local.pinned = pinned;
+                pkg
+            })
+        })?;
+    }
+    Ok(true)
+}
+
+fn export(args: &ExportArgs) -> Result<(), AppError> {
+    let ExportArgs { output } = args;
+
+    let manifest = Manifest::from_db(DB.get().unwrap())?;
+    let json = FORMAT.get() == Some(&OutputFormat::Json)
+        || output.output.as_deref().is_some_and(manifest::is_json);
+
+    output::write_output(output.output.as_deref(), &manifest.to_string(json))
+        .unwrap_or_else(|_| exit_with_message("Couldn't write output", exitcode::CANTCREAT));
+    Ok(())
+}
+
+async fn import(args: &ImportArgs) -> Result<(), AppError> {
+    let ImportArgs { file, download: _ } = args;
+
+    let contents = fs::read_to_string(file)
+        .unwrap_or_else(|_| exit_with_message("Couldn't access file", exitcode::NOINPUT));
+    let json = FORMAT.get() == Some(&OutputFormat::Json) || manifest::is_json(file);
+    let manifest = Manifest::parse(&contents, json)
+        .unwrap_or_else(|err| exit_with_message(err.to_string(), exitcode::DATAERR));
+
+    let db = DB.get().unwrap();
+    let missing = manifest.missing(db)?;
+    if !missing.is_empty() {
+        println!(
+            "\x1b[33mWarning!\x1b[0m Packages not found: {}. Installing the other packages.",
+            missing.join(", ")
+        );
+    }
+
+    let mut payload = Payload::new();
+    payload.assume_yes = *ASSUME_YES.get().unwrap();
+    payload.quiet = *QUIET.get().unwrap();
+
+    for entry in &manifest.packages {
+        if missing.contains(&entry.name()) {
+            continue;
+        }
+        match entry {
+            ManifestEntry::Spec(spec) => payload.add_pkg(db, spec),
+            ManifestEntry::Exported { name, version } => {
+                if let Some(pkg) = db.get(ALL_PKGS, name.as_str())? {
+                    if pkg.info.version.to_string() != *version {
+                        warn!(
+                            "Package {name} is available in version {} instead of {version}.",
+                            pkg.info.version
+                        );
+                    }
+                }
+                payload.add_pkg(db, name)
+            }
+        }
+        .map_err(AppError::Resolve)?;
+    }
+
+    payload.install().await.map_err(AppError::Install)?;
+    Ok(())
+}
+
+/// Rewrite the rows of `ALL_PKGS` and `INSTALLED_PKGS` from the layout of schema version 1
+/// ([`LegacyDbPackage`]) to the current [`DbPackage`].
+fn upgrade_legacy_rows(write_txn: &WriteTransaction) -> Result<(), redb::Error> {
+    for (name, table) in [("all_pkgs", ALL_PKGS), ("installed_pkgs", INSTALLED_PKGS)] {
+        let legacy_table: TableDefinition<&str, LegacyDbPackage> = TableDefinition::new(name);
+        let rows = {
+            let legacy_table = write_txn.open_table(legacy_table)?;
+            let mut rows = Vec::new();
+            for row in legacy_table.iter()? {
+                let (key, value) = row?;
+                rows.push((key.value().to_owned(), DbPackage::from(value.value())));
+            }
+            rows
+        };
+
+        let mut write_table = write_txn.open_table(table)?;
+        for (key, value) in rows {
+            write_table.insert(key.as_str(), value)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Record the version of every package in `ALL_PKGS` in `PKG_VERSIONS`.
+fn record_pkg_versions(write_txn: &WriteTransaction) -> Result<(), redb::Error> {
+    let all_table = write_txn.open_table(ALL_PKGS)?;
+    let mut versions_table = write_txn.open_table(PKG_VERSIONS)?;
+
+    for pkg in all_table.iter()? {
+        let (key, value) = pkg?;
+        let pkg = value.value();
+        versions_table.insert(db::version_key(key.value(), &pkg.version).as_str(), pkg)?;
+    }
+
+    Ok(())
+}
+
+/// Create the tables that don't store packages if they don't exist yet.
+fn init_tables(db: &Database) -> Result<(), redb::Error> {
+    let write_txn = db.begin_write()?;
+    write_txn.open_table(SYNCED_VERSIONS)?;
+    write_txn.open_table(TRUSTED_SOURCES)?;
+    write_txn.commit()?;
+
+    Ok(())
+}
+
+/// Record the `versions` (pairs of package name and version) as synced.
+fn record_synced_versions(db: &Database, versions: &[(String, String)]) -> Result<(), redb::Error> {
+    let write_txn = db.begin_write()?;
+    {
+        let mut synced_table = write_txn.open_table(SYNCED_VERSIONS)?;
+        for (name, version) in versions {
+            synced_table.insert(name.as_str(), version.as_str())?;
+        }
+    }
+    write_txn.commit()?;
+
+    Ok(())
+}
+
+/// Get the versions of the installed package `name` in `PKG_VERSIONS` that are older than the installed one, oldest first.
+/// Returns `None` if the package isn't installed.
+fn older_versions(db: &Database, name: &str) -> Result<Option<Vec<Version>>, redb::Error> {
+    let Some(installed) = db.get(INSTALLED_PKGS, name)? else {
+        return Ok(None);
+    };
+    let Some(installed) = installed.local.installed.version() else {
+        return Ok(None);
+    };
+
+    let mut versions = db.get_versions(PKG_VERSIONS, name)?;
+    versions.retain(|version| version < installed);
+    Ok(Some(versions))
+}
+
+/// The result of [`outdated_pkgs`].
+#[derive(Debug, Default)]
+struct Outdated {
+    /// The available versions of the outdated packages.
+    pkgs: Vec<Package>,
+    /// The names of the checked packages that are up to date, with the versions available in `ALL_PKGS`.
+    /// Packages that were skipped (because they weren't named, are pinned or ignored) are left out,
+    /// so that they aren't recorded as synced.
+    up_to_date: Vec<(String, String)>,
+}
+
+impl Outdated {
+    /// Get the names and available versions of the outdated packages, to record them as synced once installed.
+    fn versions(&self) -> Vec<(String, String)> {
+        self.pkgs
+            .iter()
+            .map(|pkg| (pkg.info.name.clone(), pkg.info.version.to_string()))
+            .collect()
+    }
+}
+
+/// Get the installed packages for which a newer version is available in `ALL_PKGS`.
+/// Pinned packages are skipped.
+/// If `names` is given, only these packages are considered. Otherwise, the packages ignored in `conf` are skipped.
+/// If `incremental` is set, only packages whose available version changed since the last sync are considered.
+fn outdated_pkgs(
+    db: &Database,
+    conf: &Config,
+    names: Option<&[String]>,
+    incremental: bool,
+) -> Result<Outdated, redb::Error> {
+    let read_txn = db.begin_read()?;
+    let all_table = read_txn.open_table(ALL_PKGS)?;
+    let installed_table = read_txn.open_table(INSTALLED_PKGS)?;
+    let synced_table = read_txn.open_table(SYNCED_VERSIONS)?;
+
+    let mut outdated = Outdated::default();
+    for pkg in installed_table.iter()? {
+        let (key, value) = pkg?;
+        let name = key.value();
+        if names.is_some_and(|names| !names.iter().any(|n| n == name)) {
+            continue;
+        }
+
+        let installed = Package::try_from(value.value())?;
+        let Some(installed_ver) = installed.local.installed.version() else {
+            warn!("Invalid database state: Package {name} in table INSTALLED_PKGS, but installed is set to False. `mercurium doctor --repair` fixes it.");
+            continue;
+        };
+        let Some(available) = all_table.get(name)?.map(|pkg| pkg.value()) else {
+            continue;
+        };
+        if incremental
+            && synced_table
+                .get(name)?
+                .map(|version| version.value().to_owned())
+                == Some(available.version.clone())
+        {
+            continue;
+        }
+
+        let available = Package::try_from(available)?;
+        let newer = &available.info.version > installed_ver;
+        if newer {
+            if installed.local.pinned {
+                info!(
+                    "Skipping package {name}, it is pinned to version {installed_ver} ({} available).",
+                    available.info.version
+                );
+                continue;
+            }
+            if names.is_none() && conf.ignores(name) {
+                info!("Skipping package {name}, it is ignored in the config.");
+                continue;
+            }
+        }
+        if newer {
+            outdated.pkgs.push(available);
+        } else {
+            outdated
+                .up_to_date
+                .push((name.to_owned(), available.info.version.to_string()));
+        }
+    }
+
+    Ok(outdated)
+}
+
+/// Refresh the metadata of installed packages whose version in `ALL_PKGS` did not change, without reinstalling them.
+/// If `names` is given, only these packages are considered.
+/// Only the [`PackageInfo`] is refreshed, the source and the local installation info are kept.
+/// Returns the names of the refreshed packages.
+fn refresh_metadata(db: &Database, names: Option<&[String]>) -> Result<Vec<String>, redb::Error> {
+    let mut refreshed = Vec::new();
+
+    let write_txn = db.begin_write()?;
+    {
+        let all_table = write_txn.open_table(ALL_PKGS)?;
+        let mut installed_table = write_txn.open_table(INSTALLED_PKGS)?;
+
+        let mut updates: Vec<Package> = Vec::new();
+        for pkg in installed_table.iter()? {
+            let (key, value) = pkg?;
+            if names.is_some_and(|names| !names.iter().any(|name| name == key.value())) {
+                continue;
+            }
+
+            let installed = Package::try_from(value.value())?;
+            if let Some(available) = all_table.get(key.value())? {
+                let available = Package::try_from(available.value())?;
+                if available.info.version != installed.info.version {
+                    continue;
+                }
+                // The installed files came from the installed source, so a changed one needs a reinstall.
+                if available.source != installed.source {
+                    warn!(
+                        "The source of package {0} changed without a new version, `mercurium reinstall {0}` applies it.",
+                        installed.info.name
+                    );
+                }
+                if available.info != installed.info {
+                    updates.push(Package {
+                        info: available.info,
+                        ..installed
+                    });
+                }
+            }
+        }
+
+        for pkg in updates {
+            installed_table.insert(pkg.info.name.as_str(), Into::<DbPackage>::into(pkg.clone()))?;
+            refreshed.push(pkg.info.name);
+        }
+    }
+    write_txn.commit()?;
+
+    Ok(refreshed)
+}
+
+/// A package matching a search.
+struct SearchMatch {
+    name: String,
+    version: Version,
+    /// The description, highlighted if it was searched and truncated otherwise.
+    description: Option<String>,
+    score: u32,
+}
+
+fn search(args: &SearchArgs) -> Result<(), AppError> {
+    let SearchArgs {
+        pkg,
+        installed,
+        description_only,
+        sort,
+        names_only,
+        search_description,
+    } = args;
+
+    let db = DB.get().unwrap();
+    let read_txn = db.begin_read().map_err(redb::Error::from)?;
+    let read_table = read_txn.open_table(ALL_PKGS).map_err(redb::Error::from)?;
+
+    let mut pkgs = Vec::new();
+    for pkg in read_table.iter().map_err(redb::Error::from)? {
+        let pkg = Package::try_from(pkg.map_err(redb::Error::from)?.1.value())
+            .map_err(redb::Error::from)?;
+        if pkg.local.installed.version().is_some() || !installed {
+            pkgs.push(pkg);
+        }
+    }
+
+    let mut conf = nucleo_matcher::Config::DEFAULT;
+    conf.ignore_case = true;
+    let mut matcher = Matcher::new(conf);
+    let pattern = Pattern::parse(pkg, CaseMatching::Ignore);
+    let color = *COLOR.get().unwrap();
+    let mut buf = Vec::new();
+
+    let mut matches: Vec<SearchMatch> = pkgs
+        .into_iter()
+        .filter_map(|pkg| {
+            if *description_only {
+                let description = pkg.info.description?;
+                let (score, indices) = match_indices(&pattern, &mut matcher, &description)?;
+                Some(SearchMatch {
+                    name: pkg.info.name,
+                    version: pkg.info.version,
+                    description: Some(highlight(&description, &indices, color)),
+                    score,
+                })
+            } else {
+                let haystack = search_haystack(&pkg.info, *search_description);
+                let score = pattern.score(Utf32Str::new(&haystack, &mut buf), &mut matcher)?;
+                Some(SearchMatch {
+                    name: pkg.info.name,
+                    version: pkg.info.version,
+                    description: pkg
+                        .info
+                        .description
+                        .map(|description| truncate(&description, DESCRIPTION_WIDTH)),
+                    score,
+                })
+            }
+        })
+        .collect();
+    sort_matches(&mut matches, *sort);
+
+    if FORMAT.get() == Some(&OutputFormat::Json) {
+        println!("{}", search_json(&matches));
+        return Ok(());
+    }
+
+    for line in format_matches(&matches, *names_only) {
+        println!("{line}");
+    }
+    Ok(())
+}
+
+/// The text a search matches against: the name, and with `description` also the description and provided packages.
+fn search_haystack(info: &PackageInfo, description: bool) -> String {
+    if !description {
+        return info.name.clone();
+    }
+
+    iter::once(&info.name)
+        .chain(&info.description)
+        .chain(info.provides.iter().flatten())
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Maximum number of characters of a description shown in search results.
+const DESCRIPTION_WIDTH: usize = 60;
+
+/// Shorten `text` to at most `width` characters, marking cut text with an ellipsis.
+fn truncate(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        return text.to_owned();
+    }
+    let mut out: String = text.chars().take(width.saturating_sub(1)).collect();
+    out.push('…');
+    out
+}
+
+/// Format search matches as aligned `name  version  — description` lines, or just names if `names_only`.
+fn format_matches(matches: &[SearchMatch], names_only: bool) -> Vec<String> {
+    if names_only {
+        return matches.iter().map(|m| m.name.clone()).collect();
+    }
+
+    let name_width = matches.iter().map(|m| m.name.len()).max().unwrap_or(0);
+    let version_width = matches
+        .iter()
+        .map(|m| m.version.to_string().len())
+        .max()
+        .unwrap_or(0);
+    matches
+        .iter()
+        .map(|m| {
+            let version = m.version.to_string();
+            match &m.description {
+                Some(description) => format!(
+                    "{:name_width$}  {version:version_width$}  — {description}",
+                    m.name
+                ),
+                None => format!("{:name_width$}  {version}", m.name),
+            }
+        })
+        .collect()
+}
+
+/// A search match as printed by `search --format json`.
+#[derive(Serialize)]
+struct SearchEntry<'a> {
+    name: &'a str,
+    score: u32,
+}
+
+/// Format search matches as a pretty JSON array of `{name, score}` objects.
+fn search_json(matches: &[SearchMatch]) -> String {
+    let entries: Vec<SearchEntry> = matches
+        .iter()
+        .map(|m| SearchEntry {
+            name: &m.name,
+            score: m.score,
+        })
+        .collect();
+    serde_json::to_string_pretty(&entries).unwrap()
+}
+
+/// Sort search matches by score (ascending), name or version.
+fn sort_matches(matches: &mut [SearchMatch], sort: SearchSort) {
+    match sort {
+        SearchSort::Score => matches.sort_by_key(|m| m.score),
+        SearchSort::Name => matches.sort_by_key(|m| m.name.to_lowercase()),
+        SearchSort::Version => matches.sort_by(|a, b| a.version.cmp(&b.version)),
+    }
+}
+
+/// Match `haystack` against `pattern` and return the score and the sorted indices of the matched characters.
+fn match_indices(
+    pattern: &Pattern,
+    matcher: &mut Matcher,
+    haystack: &str,
+) -> Option<(u32, Vec<u32>)> {
+    let mut buf = Vec::new();
+    let mut indices = Vec::new();
+    let score = pattern.indices(Utf32Str::new(haystack, &mut buf), matcher, &mut indices)?;
+    indices.sort_unstable();
+    indices.dedup();
+
+    Some((score, indices))
+}
+
+/// Highlight the characters of `text` at the (sorted) character `indices` using ANSI escape codes.
+/// If `color` is false, `text` is returned unchanged.
+fn highlight(text: &str, indices: &[u32], color: bool) -> String {
+    if !color {
+        return text.to_owned();
+    }
+
+    let mut out = String::new();
+    let mut highlighted = false;
+    for (i, c) in text.chars().enumerate() {
+        let matched = indices.binary_search(&(i as u32)).is_ok();
+        if matched && !highlighted {
+            out.push_str("\x1b[1;33m");
+        } else if !matched && highlighted {
+            out.push_str("\x1b[0m");
+        }
+        highlighted = matched;
+        out.push(c);
+    }
+    if highlighted {
+        out.push_str("\x1b[0m");
+    }
+
+    out
+}
+
+/// A package as printed by `list`.
+#[derive(Debug, PartialEq, Serialize)]
+struct ListEntry {
+    name: String,
+    version: String,
+    installed: bool,
+    /// Seconds since the Unix epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    installed_at: Option<i64>,
+}
+
+fn list(args: &ListArgs) -> Result<(), AppError> {
+    let ListArgs {
+        all,
+        json,
+        sort,
+        sort_by_date,
+        orphans,
+        output,
+    } = args;
+
+    let db = DB.get().unwrap();
+    let mut pkgs = if *orphans {
+        orphan_entries(db)?
+    } else {
+        list_entries(db, *all)?
+    };
+    let sort = if *sort_by_date { ListSort::Date } else { *sort };
+    match sort {
+        // The entries are sorted by name already.
+        ListSort::Name => {}
+        ListSort::Version => sort_by_version(&mut pkgs),
+        ListSort::Date => sort_by_date_installed(&mut pkgs),
+    }
+    let contents = if *json || FORMAT.get() == Some(&OutputFormat::Json) {
+        let mut contents = serde_json::to_string_pretty(&pkgs).unwrap();
+        contents.push('\n');
+        contents
+    } else {
+        // Files never get escape codes.
+        let color = *COLOR.get().unwrap() && output.output.is_none();
+        format_list(&pkgs, *all, sort == ListSort::Date, color)
+    };
+
+    output::write_output(output.output.as_deref(), &contents)
+        .unwrap_or_else(|_| exit_with_message("Couldn't write output", exitcode::CANTCREAT));
+    Ok(())
+}
+
+/// Format `pkgs` as aligned columns: the name, the version, whether it is installed (if `all` is set)
+/// and when it was installed (if `dates` is set). If `color` is set, the columns are colored using ANSI escape codes.
+fn format_list(pkgs: &[ListEntry], all: bool, dates: bool, color: bool) -> String {
+    let rows: Vec<[String; 4]> = pkgs
+        .iter()
+        .map(|pkg| {
+            let installed = if all && pkg.installed {
+                "[Installed]".to_owned()
+            } else {
+                String::new()
+            };
+            let installed_at = match (dates, pkg.installed_at) {
+                (true, Some(installed_at)) => format!("({})", format_timestamp(installed_at)),
+                _ => String::new(),
+            };
+            [
+                pkg.name.clone(),
+                pkg.version.clone(),
+                installed,
+                installed_at,
+            ]
+        })
+        .collect();
+    let mut widths = [0; 4];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    // Bold name, green version and cyan installed marker.
+    let styles = ["\x1b[1m", "\x1b[32m", "\x1b[36m", ""];
+
+    let mut out = String::new();
+    for row in rows {
+        let mut line = String::new();
+        for ((cell, width), style) in row.iter().zip(widths).zip(styles) {
+            if width == 0 {
+                continue;
+            }
+            // Pad outside of the escape codes, so that trailing padding can be trimmed.
+            let padding = " ".repeat(width - cell.chars().count() + 1);
+            if color && !cell.is_empty() && !style.is_empty() {
+                line.push_str(&format!("{style}{cell}\x1b[0m{padding}"));
+            } else {
+                line.push_str(&format!("{cell}{padding}"));
+            }
+        }
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+
+    out
+}
+
+/// Sort `pkgs` by version, oldest first, comparing them as semantic versions (so `9.0.0` comes before `10.0.0`).
+/// Invalid versions come first. The sort is stable, so packages with the same version stay sorted by name.
+fn sort_by_version(pkgs: &mut [ListEntry]) {
+    pkgs.sort_by_cached_key(|pkg| Version::from_str(&pkg.version).ok());
+}
+
+/// Sort `pkgs` by the time they were installed, most recent last. Packages that aren't installed come first.
+/// The sort is stable, so packages installed at the same time stay sorted by name.
+fn sort_by_date_installed(pkgs: &mut [ListEntry]) {
+    pkgs.sort_by_key(|pkg| pkg.installed_at);
+}
+
+/// Format `timestamp` (seconds since the Unix epoch) as a UTC date and time like `2023-09-01 12:34 UTC`.
+fn format_timestamp(timestamp: i64) -> String {
+    let (days, seconds) = (timestamp.div_euclid(86400), timestamp.rem_euclid(86400));
+
+    // Convert the days since the epoch to a civil date (see http://howardhinnant.github.io/date_algorithms.html).
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02} UTC",
+        seconds / 3600,
+        seconds % 3600 / 60
+    )
+}
+
+/// Get the packages `autoremove` would remove (see [`orphans`]), sorted by name.
+fn orphan_entries(db: &Database) -> Result<Vec<ListEntry>, redb::Error> {
+    let orphans = orphans(db)?;
+    let mut pkgs = list_entries(db, false)?;
+    pkgs.retain(|pkg| orphans.contains(&pkg.name));
+    Ok(pkgs)
+}
+
+/// Get the installed packages (or all packages if `all` is set), sorted by name.
+fn list_entries(db: &Database, all: bool) -> Result<Vec<ListEntry>, redb::Error> {
+    let read_txn = db.begin_read()?;
+    let read_table = if all {
+        read_txn.open_table(ALL_PKGS)?
+    } else {
+        read_txn.open_table(INSTALLED_PKGS)?
+    };
+
+    let mut pkgs = Vec::new();
+    for pkg in read_table.iter()? {
+        let (key, value) = pkg?;
+        let value = value.value();
+
+        pkgs.push(ListEntry {
+            name: key.value().to_owned(),
+            version: value.version,
+            installed: value.installed.into(),
+            installed_at: value.installed_at,
+        });
+    }
+
+    pkgs.sort_by_key(|pkg| pkg.name.to_lowercase());
+    Ok(pkgs)
+}
+
+fn info(args: &InfoArgs) -> Result<(), AppError> {
+    let InfoArgs {
+        pkg,
+        installed_files,
+    } = args;
+
+    let pkg = DB.get().unwrap().get(ALL_PKGS, pkg)?.unwrap_or_else(|| {
+        exit_with_message(format!("Package {pkg} not found!"), exitcode::DATAERR)
+    });
+
+    if !installed_files {
+        print!("{}", format_info(&pkg));
+        return Ok(());
+    }
+
+    if pkg.local.installed == Installed::False {
+        exit_with_message(
+            format!("Package {} is not installed!", pkg.info.name),
+            exitcode::DATAERR,
+        );
+    }
+    let files = file_sizes(&pkg.local.files);
+    for (file, size) in &files {
+        match size {
+            Some(size) => println!("{} ({})", file.to_string_lossy(), HumanBytes(*size)),
+            None => println!("{} (missing)", file.to_string_lossy()),
+        }
+    }
+    let total: u64 = files.iter().filter_map(|(_, size)| *size).sum();
+    println!("{} files, {} in total.", files.len(), HumanBytes(total));
+    Ok(())
+}
+
+/// Get the sizes of `files` in bytes. Files that don't exist anymore have no size.
+fn file_sizes(files: &[PathBuf]) -> Vec<(&Path, Option<u64>)> {
+    files
+        .iter()
+        .map(|file| {
+            let size = fs::metadata(file).ok().map(|metadata| metadata.len());
+            (file.as_path(), size)
+        })
+        .collect()
+}
+
+/// Format the metadata and installation status of `pkg` for `info`.
+fn format_info(pkg: &Package) -> String {
+    let info = &pkg.info;
+    let none = || "None".to_owned();
+
+    let installed = match &pkg.local.installed {
+        Installed::Manually(ver) => format!("{ver} (manually)"),
+        Installed::Automatically(ver) => format!("{ver} (automatically)"),
+        Installed::False => "No".to_owned(),
+    };
+    let installed = match pkg.local.installed.version() {
+        Some(ver) if ver < &info.version => format!("{installed}, {} available", info.version),
+        _ => installed,
+    };
+    let installed = match pkg.local.pinned {
+        true => format!("{installed}, pinned"),
+        false => installed,
+    };
+
+    let fields = [
+        ("Name", info.name.clone()),
+        ("Version", info.version.to_string()),
+        ("License", info.license.clone()),
+        (
+            "Authors",
+            info.authors
+                .as_ref()
+                .map_or_else(none, |authors| authors.join(", ")),
+        ),
+        ("Description", info.description.clone().unwrap_or_else(none)),
+        ("Repository", info.repository.clone().unwrap_or_else(none)),
+        (
+            "Dependencies",
+            info.dependencies
+                .as_ref()
+                .map_or_else(none, |deps| deps.join(", ")),
+        ),
+        (
+            "Provides",
+            info.provides
+                .as_ref()
+                .map_or_else(none, |provides| provides.join(", ")),
+        ),
+        ("Installed", installed),
+        (
+            "Installed at",
+            pkg.local.installed_at.map_or_else(none, format_timestamp),
+        ),
+    ];
+
+    fields
+        .into_iter()
+        .map(|(field, value)| format!("{field:<13}: {value}\n"))
+        .collect()
+}
+
+#[cfg(debug_assertions)]
+fn config() {
+    dbg!(CONFIG.get().unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConfigDirs;
+    use crate::pkg::Source;
+    use crate::pkgfile::PackageFileBuilder;
+
+    /// A pkgfile of version 1.0.0 of package `name` with the required fields set.
+    pub(crate) fn test_pkgfile(name: &str) -> PackageFileBuilder {
+        PackageFile::builder()
+            .name(name)
+            .version(Version::from_str("1.0.0").unwrap())
+            .license("MIT")
+            .url(format!("https://example.com/{name}.tar.gz"))
+            .install_command("true")
+    }
+
+    pub(crate) fn test_package(name: &str, version: &str) -> Package {
+        Package {
+            info: PackageInfo {
+                name: name.to_owned(),
+                version: Version::from_str(version).unwrap(),
+                license: "MIT".to_owned(),
+                repository: None,
+                authors: None,
+                description: None,
+                dependencies: None,
+                build_dependencies: None,
+                provides: None,
+            },
+            source: Source {
+                url: format!("https://example.com/{name}-{version}.tar.gz"),
+                vcs: None,
+                rev: None,
+                mirrors: None,
+                checksum: None,
+                signature: None,
+                signing_key: None,
+                strip_components: None,
+                patches: None,
+                prepare: None,
+                build: None,
+                install: format!("cp ${{source}}/{name} ${{binary}}"),
+                post_install: None,
+                uninstall: None,
+                executable: None,
+                config_files: None,
+                env: None,
+            },
+            local: Local::default(),
+        }
+    }
+
+    pub(crate) fn test_config(dir: &std::path::Path) -> Config {
+        Config {
+            directories: ConfigDirs {
+                sources: dir.join("sources"),
+                builds: dir.join("builds"),
+                binaries: dir.join("binaries"),
+                packages: dir.to_owned(),
+            },
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn test_db(dir: &std::path::Path) -> Database {
+        let db = Database::create(dir.join("packages.db")).unwrap();
+        db.init_table(ALL_PKGS).unwrap();
+        db.init_table(INSTALLED_PKGS).unwrap();
+        db.init_table(PKG_VERSIONS).unwrap();
+        init_tables(&db).unwrap();
+        db
+    }
+
+    #[test]
+    fn test_app_error() {
+        let err = AppError::Remove {
+            name: "foo".to_owned(),
+            err: "uninstall script of package foo failed".into(),
+        };
+        assert_eq!(
+            err.to_string(),
+            "Couldn't remove package foo: uninstall script of package foo failed"
+        );
+        assert_eq!(err.exit_code(), exitcode::SOFTWARE);
+
+        let err = AppError::Config(io::Error::from(io::ErrorKind::PermissionDenied));
+        assert_eq!(err.exit_code(), exitcode::CONFIG);
+    }
+
+    #[test]
+    fn test_sort_matches_by_name() {
+        let search_match = |name: &str, version: &str, score| SearchMatch {
+            name: name.to_owned(),
+            version: Version::from_str(version).unwrap(),
+            description: None,
+            score,
+        };
+        let mut matches = vec![
+            search_match("beta", "2.0.0", 10),
+            search_match("Gamma", "10.0.0", 30),
+            search_match("alpha", "9.0.0", 20),
+        ];
+
+        sort_matches(&mut matches, SearchSort::Name);
+        let names: Vec<&str> = matches.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, vec!["alpha", "beta", "Gamma"]);
+
+        sort_matches(&mut matches, SearchSort::Version);
+        let names: Vec<&str> = matches.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, vec!["beta", "alpha", "Gamma"]);
+
+        sort_matches(&mut matches, SearchSort::Score);
+        let names: Vec<&str> = matches.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, vec!["beta", "alpha", "Gamma"]);
+    }
+
+    #[test]
+    fn test_search_json() {
+        let matches = [SearchMatch {
+            name: "foo".to_owned(),
+            version: Version::from_str("1.0.0").unwrap(),
+            description: Some("\x1b[1mfoo\x1b[0m".to_owned()),
+            score: 42,
+        }];
+
+        let json: serde_json::Value = serde_json::from_str(&search_json(&matches)).unwrap();
+        assert_eq!(json, serde_json::json!([{ "name": "foo", "score": 42 }]));
+    }
+
+    #[test]
+    fn test_search_haystack() {
+        let mut pkg = test_package("ripgrep", "1.0.0");
+        assert_eq!(search_haystack(&pkg.info, true), "ripgrep");
+
+        pkg.info.description = Some("Recursively search directories".to_owned());
+        pkg.info.provides = Some(vec!["grep".to_owned()]);
+        assert_eq!(search_haystack(&pkg.info, false), "ripgrep");
+        assert_eq!(
+            search_haystack(&pkg.info, true),
+            "ripgrep Recursively search directories grep"
+        );
+
+        // The description contributes to the score.
+        let mut matcher = Matcher::new(nucleo_matcher::Config::DEFAULT);
+        let pattern = Pattern::parse("search", CaseMatching::Ignore);
+        let mut buf = Vec::new();
+        let haystack = search_haystack(&pkg.info, true);
+        assert!(pattern
+            .score(Utf32Str::new(&haystack, &mut buf), &mut matcher)
+            .is_some());
+    }
+
+    #[test]
+    fn test_format_matches() {
+        let search_match = |name: &str, version: &str, description: Option<&str>| SearchMatch {
+            name: name.to_owned(),
+            version: Version::from_str(version).unwrap(),
+            description: description.map(|d| truncate(d, 12)),
+            score: 0,
+        };
+        let matches = [
+            search_match("foo", "1.0.0", Some("A short one")),
+            search_match("foobar", "10.2.0", Some("A much longer description")),
+            search_match("baz", "0.1.0", None),
+        ];
+
+        assert_eq!(
+            format_matches(&matches, false),
+            [
+                "foo     1.0.0   — A short one",
+                "foobar  10.2.0  — A much long…",
+                "baz     0.1.0",
+            ]
+        );
+        assert_eq!(format_matches(&matches, true), ["foo", "foobar", "baz"]);
+    }
+
+    #[test]
+    fn test_refresh_metadata() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = test_db(tmpdir.path());
+
+        let mut installed = test_package("foo", "1.0.0");
+        installed.info.description = Some("Old description".to_owned());
+        installed.local.installed = Installed::Manually(installed.info.version.clone());
+        db.set(INSTALLED_PKGS, "foo", installed.clone()).unwrap();
+
+        let mut available = installed.clone();
+        available.info.description = Some("New description".to_owned());
+        available.info.dependencies = Some(vec!["bar".to_owned()]);
+        available.source.install = "make install".to_owned();
+        db.set(ALL_PKGS, "foo", available).unwrap();
+
+        assert_eq!(refresh_metadata(&db, None).unwrap(), vec!["foo".to_owned()]);
+
+        let refreshed = db.get(INSTALLED_PKGS, "foo").unwrap().unwrap();
+        assert_eq!(
+            refreshed.info.description.as_deref(),
+            Some("New description")
+        );
+        assert_eq!(refreshed.info.dependencies, Some(vec!["bar".to_owned()]));
+        assert_eq!(refreshed.local, installed.local);
+        // The source was installed, so it isn't replaced.
+        assert_eq!(refreshed.source, installed.source);
+
+        assert!(refresh_metadata(&db, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_register_binary() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = test_db(tmpdir.path());
+        let binary = tmpdir.path().join("tool");
+        fs::write(&binary, "#!/bin/sh").unwrap();
+
+        let version = Version::from_str("0.1.0").unwrap();
+        register_binary(&db, &binary, version.clone()).unwrap();
+
+        let pkg = db.get(INSTALLED_PKGS, "tool").unwrap().unwrap();
+        assert_eq!(pkg.local.installed, Installed::Manually(version.clone()));
+        assert!(pkg.local.added);
+        assert_eq!(pkg.local.files, vec![binary.canonicalize().unwrap()]);
+        assert_eq!(db.get(ALL_PKGS, "tool").unwrap(), Some(pkg));
+
+        assert!(register_binary(&db, &binary, version).is_err());
+    }
+
+    #[test]
+    fn test_register_binary_quotes_path() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = test_db(tmpdir.path());
+        let binary = tmpdir.path().join("it's");
+        fs::write(&binary, "#!/bin/sh").unwrap();
+
+        let version = Version::from_str("0.1.0").unwrap();
+        let pkg = register_binary(&db, &binary, version).unwrap();
+
+        let path = binary.canonicalize().unwrap();
+        let path = path.to_string_lossy().replace('\'', r"'\''");
+        assert_eq!(pkg.source.install, format!("cp '{path}' \"${{binary}}\""));
+    }
+
+    #[test]
+    fn test_list_json_output() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = test_db(tmpdir.path());
+
+        let mut foo = test_package("foo", "1.0.0");
+        foo.local.installed = Installed::Manually(foo.info.version.clone());
+        foo.local.installed_at = Some(1693571640);
+        db.set(ALL_PKGS, "foo", foo.clone()).unwrap();
+        db.set(INSTALLED_PKGS, "foo", foo).unwrap();
+        db.set(ALL_PKGS, "Bar", test_package("Bar", "2.0.0"))
+            .unwrap();
+
+        let pkgs = list_entries(&db, true).unwrap();
+        let path = tmpdir.path().join("out").join("list.json");
+        output::write_output(Some(&path), &serde_json::to_string(&pkgs).unwrap()).unwrap();
+
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([
+                { "name": "Bar", "version": "2.0.0", "installed": false },
+                { "name": "foo", "version": "1.0.0", "installed": true, "installed_at": 1693571640 },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_format_list() {
+        let entry = |name: &str, version: &str, installed| ListEntry {
+            name: name.to_owned(),
+            version: version.to_owned(),
+            installed,
+            installed_at: None,
+        };
+        let pkgs = [
+            entry("foo", "1.0.0", true),
+            entry("longer-name", "10.2.0", false),
+        ];
+
+        assert_eq!(
+            format_list(&pkgs, false, false, false),
+            "foo         1.0.0\n\
+             longer-name 10.2.0\n"
+        );
+        assert_eq!(
+            format_list(&pkgs, true, false, false),
+            "foo         1.0.0  [Installed]\n\
+             longer-name 10.2.0\n"
+        );
+        assert_eq!(
+            format_list(&pkgs[..1], true, false, true),
+            "\x1b[1mfoo\x1b[0m \x1b[32m1.0.0\x1b[0m \x1b[36m[Installed]\x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn test_sort_by_date_installed() {
+        let entry = |name: &str, installed_at| ListEntry {
+            name: name.to_owned(),
+            version: "1.0.0".to_owned(),
+            installed: installed_at.is_some(),
+            installed_at,
+        };
+        let mut pkgs = vec![
+            entry("a", Some(200)),
+            entry("b", None),
+            entry("c", Some(100)),
+        ];
+        sort_by_date_installed(&mut pkgs);
+        let names: Vec<&str> = pkgs.iter().map(|pkg| pkg.name.as_str()).collect();
+        assert_eq!(names, ["b", "c", "a"]);
+
+        assert_eq!(
+            format_list(&pkgs, true, true, false),
+            "b 1.0.0\n\
+             c 1.0.0 [Installed] (1970-01-01 00:01 UTC)\n\
+             a 1.0.0 [Installed] (1970-01-01 00:03 UTC)\n"
+        );
+
+        let mut pkgs = vec![
+            ListEntry {
+                version: "10.0.0".to_owned(),
+                ..entry("a", None)
+            },
+            ListEntry {
+                version: "9.0.0".to_owned(),
+                ..entry("b", None)
+            },
+            entry("c", None),
+        ];
+        sort_by_version(&mut pkgs);
+        let names: Vec<&str> = pkgs.iter().map(|pkg| pkg.name.as_str()).collect();
+        assert_eq!(names, ["c", "b", "a"]);
+
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00 UTC");
+        assert_eq!(format_timestamp(1693571640), "2023-09-01 12:34 UTC");
+        assert_eq!(format_timestamp(951782400), "2000-02-29 00:00 UTC");
+    }
+
+    #[test]
+    fn test_outdated_incremental() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = test_db(tmpdir.path());
+        let conf = Config::default();
+
+        for name in ["foo", "bar", "baz"] {
+            let mut pkg = test_package(name, "1.0.0");
+            pkg.local.installed = Installed::Manually(pkg.info.version.clone());
+            db.set(ALL_PKGS, name, pkg.clone()).unwrap();
+            db.set(INSTALLED_PKGS, name, pkg).unwrap();
+        }
+        db.set(ALL_PKGS, "bar", test_package("bar", "2.0.0"))
+            .unwrap();
+        db.set(ALL_PKGS, "baz", test_package("baz", "2.0.0"))
+            .unwrap();
+        set_pinned(&db, "baz", true).unwrap();
+        let names = |outdated: Outdated| {
+            outdated
+                .pkgs
+                .into_iter()
+                .map(|pkg| pkg.info.name)
+                .collect::<Vec<_>>()
+        };
+
+        let outdated = outdated_pkgs(&db, &conf, None, false).unwrap();
+        // The pinned package was skipped, so it isn't recorded as synced.
+        assert_eq!(
+            outdated.up_to_date,
+            [("foo".to_owned(), "1.0.0".to_owned())]
+        );
+        assert_eq!(
+            outdated.versions(),
+            [("bar".to_owned(), "2.0.0".to_owned())]
+        );
+        // Like an aborted or failed update: the outdated package isn't recorded.
+        record_synced_versions(&db, &outdated.up_to_date).unwrap();
+        assert_eq!(
+            names(outdated_pkgs(&db, &conf, None, true).unwrap()),
+            ["bar"]
+        );
+        // Like a successful update.
+        record_synced_versions(&db, &outdated.versions()).unwrap();
+        assert!(outdated_pkgs(&db, &conf, None, true)
+            .unwrap()
+            .pkgs
+            .is_empty());
+
+        db.set(ALL_PKGS, "foo", test_package("foo", "1.1.0"))
+            .unwrap();
+        set_pinned(&db, "baz", false).unwrap();
+
+        assert_eq!(
+            names(outdated_pkgs(&db, &conf, None, false).unwrap()),
+            ["bar", "baz", "foo"]
+        );
+        assert_eq!(
+            names(outdated_pkgs(&db, &conf, None, true).unwrap()),
+            ["baz", "foo"]
+        );
+
+        // Only the named package is recorded.
+        let foo = ["foo".to_owned()];
+        let outdated = outdated_pkgs(&db, &conf, Some(&foo), true).unwrap();
+        assert_eq!(
+            outdated.versions(),
+            [("foo".to_owned(), "1.1.0".to_owned())]
+        );
+        assert!(outdated.up_to_date.is_empty());
+        record_synced_versions(&db, &outdated.versions()).unwrap();
+        assert_eq!(
+            names(outdated_pkgs(&db, &conf, None, true).unwrap()),
+            ["baz"]
+        );
+    }
+
+    #[test]
+    fn test_outdated_ignored() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = test_db(tmpdir.path());
+        let conf = Config {
+            ignore: vec!["linux-*".to_owned()],
+            ..Default::default()
+        };
+
+        for name in ["linux-lts", "foo"] {
+            let mut pkg = test_package(name, "1.0.0");
+            pkg.local.installed = Installed::Manually(pkg.info.version.clone());
+            db.set(INSTALLED_PKGS, name, pkg).unwrap();
+            db.set(ALL_PKGS, name, test_package(name, "2.0.0")).unwrap();
+        }
+
+        let outdated = outdated_pkgs(&db, &conf, None, false).unwrap().pkgs;
+        assert_eq!(outdated.len(), 1);
+        assert_eq!(outdated[0].info.name, "foo");
+        // Naming an ignored package updates it anyway.
+        let linux = ["linux-lts".to_owned()];
+        let outdated = outdated_pkgs(&db, &conf, Some(&linux), false).unwrap().pkgs;
+        assert_eq!(outdated.len(), 1);
+        assert_eq!(outdated[0].info.name, "linux-lts");
+    }
+
+    #[test]
+    fn test_outdated_pinned() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = test_db(tmpdir.path());
+        let conf = Config::default();
+
+        for name in ["foo", "bar"] {
+            let mut pkg = test_package(name, "1.0.0");
+            pkg.local.installed = Installed::Manually(pkg.info.version.clone());
+            db.set(INSTALLED_PKGS, name, pkg).unwrap();
+            db.set(ALL_PKGS, name, test_package(name, "2.0.0")).unwrap();
+        }
+        assert!(set_pinned(&db, "foo", true).unwrap());
+        assert!(!set_pinned(&db, "baz", true).unwrap());
+        assert!(db.get(ALL_PKGS, "foo").unwrap().unwrap().local.pinned);
+
+        let names = |pkgs: Vec<Package>| {
+            pkgs.into_iter()
+                .map(|pkg| pkg.info.name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(outdated_pkgs(&db, &conf, None, false).unwrap().pkgs),
+            ["bar"]
+        );
+        let foo = ["foo".to_owned()];
+        assert!(outdated_pkgs(&db, &conf, Some(&foo), false)
+            .unwrap()
+            .pkgs
+            .is_empty());
+
+        assert!(set_pinned(&db, "foo", false).unwrap());
+        assert_eq!(
+            names(outdated_pkgs(&db, &conf, Some(&foo), false).unwrap().pkgs),
+            ["foo"]
+        );
+    }
+
+    #[test]
+    fn test_migrate_legacy() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = Database::create(tmpdir.path().join("packages.db")).unwrap();
+
+        // A database as written before the schema was versioned.
+        let legacy = LegacyDbPackage {
+            name: "foo".to_owned(),
+            version: "1.2.0".to_owned(),
+            license: "MIT".to_owned(),
+            repository: String::new(),
+            authors: vec!["bar".to_owned()],
+            description: "A package".to_owned(),
+            dependencies: vec!["baz".to_owned()],
+            build_dependencies: Vec::new(),
+            provides: "qux".to_owned(),
+            url: "https://example.com/foo.tar.gz".to_owned(),
+            checksum: String::new(),
+            build: String::new(),
+            install: "true".to_owned(),
+            installed: Installed::Manually(Version::from_str("1.2.0").unwrap()),
+            added: false,
+        };
+        let write_txn = db.begin_write().unwrap();
+        for name in ["all_pkgs", "installed_pkgs"] {
+            let table: TableDefinition<&str, LegacyDbPackage> = TableDefinition::new(name);
+            let mut write_table = write_txn.open_table(table).unwrap();
+            write_table.insert("foo", legacy.clone()).unwrap();
+        }
+        write_txn.commit().unwrap();
+
+        assert_eq!(
+            db::migrate(&db, META, SCHEMA_VERSION, MIGRATIONS).unwrap(),
+            1
+        );
+        for table in [ALL_PKGS, INSTALLED_PKGS] {
+            let pkg = db.get(table, "foo").unwrap().unwrap();
+            assert_eq!(pkg.info.provides, Some(vec!["qux".to_owned()]));
+            assert_eq!(pkg.info.dependencies, Some(vec!["baz".to_owned()]));
+            assert_eq!(pkg.source.install, "true");
+            assert!(matches!(pkg.local.installed, Installed::Manually(_)));
+            assert!(!pkg.local.pinned);
+        }
+        assert!(db
+            .contains(PKG_VERSIONS, db::version_key("foo", "1.2.0").as_str())
+            .unwrap());
+    }
+
+    #[test]
+    fn test_older_versions() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = test_db(tmpdir.path());
+
+        assert_eq!(older_versions(&db, "foo").unwrap(), None);
+        for version in ["1.0.0", "1.1.0", "2.0.0", "3.0.0"] {
+            let key = db::version_key("foo", version);
+            db.set(PKG_VERSIONS, key.as_str(), test_package("foo", version))
+                .unwrap();
+        }
+        let mut pkg = test_package("foo", "2.0.0");
+        pkg.local.installed = Installed::Automatically(pkg.info.version.clone());
+        db.set(INSTALLED_PKGS, "foo", pkg).unwrap();
+
+        assert_eq!(
+            older_versions(&db, "foo").unwrap(),
+            Some(vec![
+                Version::from_str("1.0.0").unwrap(),
+                Version::from_str("1.1.0").unwrap()
+            ])
+        );
+    }
+
+    #[test]
+    fn test_install_args_mixed() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = test_db(tmpdir.path());
+        db.set(ALL_PKGS, "repo", test_package("repo", "1.0.0"))
+            .unwrap();
+
+        let pkgfile = PackageFile::builder()
+            .name("dev")
+            .version(Version::from_str("0.1.0").unwrap())
+            .license("MIT")
+            .url("https://example.com/dev.tar.gz")
+            .install_command("true")
+            .build()
+            .unwrap();
+        let path = tmpdir.path().join("dev.toml");
+        fs::write(&path, pkgfile.to_toml().unwrap()).unwrap();
+
+        let mut payload = Payload::new();
+        let pkgs = [path.to_string_lossy().into_owned(), "repo".to_owned()];
+        add_install_args(&mut payload, &db, &pkgs, false).unwrap();
+        assert_eq!(payload.package_names(), ["dev", "repo"]);
+
+        let mut payload = Payload::new();
+        let pkgs = [format!("file:{}", path.to_string_lossy())];
+        add_install_args(&mut payload, &db, &pkgs, false).unwrap();
+        assert_eq!(payload.package_names(), ["dev"]);
+    }
+
+    #[test]
+    fn test_orphans() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = test_db(tmpdir.path());
+
+        let install = |name: &str, manually: bool, dependencies: &[&str]| {
+            let mut pkg = test_package(name, "1.0.0");
+            let version = pkg.info.version.clone();
+            pkg.local.installed = if manually {
+                Installed::Manually(version)
+            } else {
+                Installed::Automatically(version)
+            };
+            if !dependencies.is_empty() {
+                pkg.info.dependencies =
+                    Some(dependencies.iter().map(|dep| dep.to_string()).collect());
+            }
+            db.set(INSTALLED_PKGS, name, pkg).unwrap();
+        };
+        install("app", true, &["lib"]);
+        install("lib", false, &[]);
+        install("leftover", false, &["leftover-lib"]);
+        install("leftover-lib", false, &[]);
+
+        assert_eq!(orphans(&db).unwrap(), ["leftover", "leftover-lib"]);
+        let entries = orphan_entries(&db).unwrap();
+        let names: Vec<&str> = entries.iter().map(|pkg| pkg.name.as_str()).collect();
+        assert_eq!(names, ["leftover", "leftover-lib"]);
+    }
+
+    #[test]
+    fn test_dependents() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = test_db(tmpdir.path());
+
+        let mut app = test_package("app", "1.0.0");
+        app.info.dependencies = Some(vec!["lib >=1.0".to_owned()]);
+        app.local.installed = Installed::Manually(app.info.version.clone());
+        db.set(ALL_PKGS, "app", app.clone()).unwrap();
+        db.set(INSTALLED_PKGS, "app", app).unwrap();
+        let mut tool = test_package("tool", "1.0.0");
+        tool.info.dependencies = Some(vec!["lib".to_owned()]);
+        db.set(ALL_PKGS, "tool", tool).unwrap();
+
+        assert_eq!(dependents(&db, "lib", false).unwrap(), ["app"]);
+        assert_eq!(dependents(&db, "lib", true).unwrap(), ["app", "tool"]);
+        assert!(dependents(&db, "app", true).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_log_level() {
+        let verbose = |args: &[&str]| {
+            let cli = Cli::try_parse_from([&["mercurium"], args, &["list"]].concat()).unwrap();
+            log_level(cli.verbose)
+        };
+        assert_eq!(verbose(&[]), LevelFilter::Warn);
+        assert_eq!(verbose(&["-v"]), LevelFilter::Info);
+        assert_eq!(verbose(&["-vv"]), LevelFilter::Debug);
+        assert_eq!(verbose(&["-v", "--verbose", "-v"]), LevelFilter::Trace);
+        assert_eq!(verbose(&["-vvvv"]), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn test_override_config() {
+        let mut conf = Config {
+            retries: Some(1),
+            ..Default::default()
+        };
+        let cli = Cli::try_parse_from(["mercurium", "install", "foo"]).unwrap();
+        override_config(&mut conf, &cli.command);
+        assert_eq!(conf.retries(), 1);
+
+        let cli = Cli::try_parse_from([
+            "mercurium",
+            "install",
+            "--retries",
+            "5",
+            "--timeout",
+            "60",
+            "foo",
+        ])
+        .unwrap();
+        override_config(&mut conf, &cli.command);
+        assert_eq!(conf.retries(), 5);
+        assert_eq!(conf.timeout(), Some(std::time::Duration::from_secs(60)));
+
+        let cli = Cli::try_parse_from(["mercurium", "install", "-j", "3", "foo"]).unwrap();
+        override_config(&mut conf, &cli.command);
+        assert_eq!(conf.jobs(), 3);
+
+        let cli = Cli::try_parse_from(["mercurium", "update", "--retries", "0"]).unwrap();
+        override_config(&mut conf, &cli.command);
+        assert_eq!(conf.retries(), 0);
+
+        let cli = Cli::try_parse_from(["mercurium", "reinstall", "--retries", "3", "foo"]).unwrap();
+        override_config(&mut conf, &cli.command);
+        assert_eq!(conf.retries(), 3);
+        assert!(Cli::try_parse_from(["mercurium", "reinstall"]).is_err());
+    }
+
+    #[test]
+    fn test_search_names_only() {
+        let names_only = |args: &[&str]| {
+            let cli = Cli::try_parse_from([&["mercurium"], args].concat()).unwrap();
+            match cli.command {
+                Commands::Search(args) => (cli.quiet, args.names_only),
+                _ => unreachable!(),
+            }
+        };
+        assert_eq!(names_only(&["search", "-q", "foo"]), (false, true));
+        assert_eq!(
+            names_only(&["search", "--names-only", "foo"]),
+            (false, true)
+        );
+        // The global `--quiet` only suppresses status output.
+        assert_eq!(names_only(&["--quiet", "search", "foo"]), (true, false));
+    }
+
+    #[test]
+    fn test_prompt_error() {
+        // Closed or redirected stdin makes prompts fail with one of these errors.
+        let eof = InquireError::IO(io::Error::from(io::ErrorKind::UnexpectedEof));
+        for err in [InquireError::NotTTY, eof] {
+            let (message, exitcode) = prompt_error(&err);
+            assert!(message.ends_with("; pass --yes."));
+            assert_eq!(exitcode, exitcode::USAGE);
+        }
+
+        assert_eq!(
+            prompt_error(&InquireError::OperationCanceled),
+            ("Aborting...".to_owned(), exitcode::OK)
+        );
+    }
+
+    #[test]
+    fn test_format_info() {
+        let mut pkg = test_package("foo", "1.1.0");
+        pkg.info.dependencies = Some(vec!["bar".to_owned(), "baz".to_owned()]);
+        assert!(format_info(&pkg).contains("Dependencies : bar, baz\n"));
+        assert!(format_info(&pkg).contains("Installed    : No\n"));
+
+        pkg.local.installed = Installed::Automatically(Version::from_str("1.0.0").unwrap());
+        assert!(
+            format_info(&pkg).contains("Installed    : 1.0.0 (automatically), 1.1.0 available\n")
+        );
+
+        pkg.local.installed = Installed::Manually(Version::from_str("1.1.0").unwrap());
+        assert!(format_info(&pkg).contains("Installed    : 1.1.0 (manually)\n"));
+
+        pkg.local.pinned = true;
+        assert!(format_info(&pkg).contains("Installed    : 1.1.0 (manually), pinned\n"));
+    }
+
+    #[test]
+    fn test_file_sizes() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let conf = test_config(tmpdir.path());
+        fs::create_dir_all(conf.binaries_path()).unwrap();
+
+        let mut foo = test_package("foo", "1.0.0");
+        fs::write(conf.binaries_path().join("foo"), "foo\n").unwrap();
+        fs::write(conf.binaries_path().join("foo.bash"), "completions\n").unwrap();
+        foo.local.files = vec![
+            conf.binaries_path().join("foo"),
+            conf.binaries_path().join("foo.bash"),
+            conf.binaries_path().join("removed"),
+        ];
+
+        assert_eq!(
+            file_sizes(&foo.local.files),
+            [
+                (foo.local.files[0].as_path(), Some(4)),
+                (foo.local.files[1].as_path(), Some(12)),
+                (foo.local.files[2].as_path(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_remove_files() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = test_db(tmpdir.path());
+        let conf = test_config(tmpdir.path());
+        fs::create_dir_all(conf.binaries_path()).unwrap();
+
+        let own = conf.binaries_path().join("foo");
+        let shared = conf.binaries_path().join("shared");
+        fs::write(&own, "foo").unwrap();
+        fs::write(&shared, "shared").unwrap();
+
+        let mut foo = test_package("foo", "1.0.0");
+        foo.local.installed = Installed::Manually(foo.info.version.clone());
+        foo.local.files = vec![own.clone(), shared.clone()];
+        let mut bar = test_package("bar", "1.0.0");
+        bar.local.installed = Installed::Manually(bar.info.version.clone());
+        bar.local.files = vec![shared.clone()];
+        for pkg in [foo, bar] {
+            let name = pkg.info.name.clone();
+            db.set(ALL_PKGS, &name, pkg.clone()).unwrap();
+            db.set(INSTALLED_PKGS, &name, pkg).unwrap();
+        }
+
+        remove_pkg(&db, &conf, "foo", false).unwrap();
+        assert!(!own.exists());
+        assert!(shared.exists());
+
+        remove_pkg(&db, &conf, "bar", false).unwrap();
+        assert!(!shared.exists());
+    }
+
+    #[test]
+    fn test_remove_uninstall() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = test_db(tmpdir.path());
+        let conf = test_config(tmpdir.path());
+        fs::create_dir_all(conf.binaries_path()).unwrap();
+        let state = conf.binaries_path().join("foo.state");
+        fs::write(&state, "running").unwrap();
+
+        let mut foo = test_package("foo", "1.0.0");
+        foo.local.installed = Installed::Manually(foo.info.version.clone());
+        foo.source.uninstall = Some("exit 1".to_owned());
+        db.set(ALL_PKGS, "foo", foo.clone()).unwrap();
+        db.set(INSTALLED_PKGS, "foo", foo.clone()).unwrap();
+
+        let err = remove_pkg(&db, &conf, "foo", false).unwrap_err();
+        assert_eq!(err.to_string(), "uninstall script of package foo failed");
+        assert!(db.get(INSTALLED_PKGS, "foo").unwrap().is_some());
+
+        foo.source.uninstall = Some("rm \"$binary/foo.state\"".to_owned());
+        db.set(INSTALLED_PKGS, "foo", foo).unwrap();
+        remove_pkg(&db, &conf, "foo", false).unwrap();
+        assert!(!state.exists());
+        assert!(db.get(INSTALLED_PKGS, "foo").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_remove_keeps_config() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = test_db(tmpdir.path());
+        let conf = test_config(tmpdir.path());
+        fs::create_dir_all(conf.binaries_path()).unwrap();
+
+        let mut pkg = test_package("foo", "1.0.0");
+        pkg.source.config_files = Some(vec!["foo.toml".to_owned()]);
+        pkg.local.installed = Installed::Manually(pkg.info.version.clone());
+        let config = conf.binaries_path().join("foo.toml");
+        fs::write(&config, "user = \"modified\"").unwrap();
+
+        db.set(ALL_PKGS, "foo", pkg.clone()).unwrap();
+        db.set(INSTALLED_PKGS, "foo", pkg).unwrap();
+        remove_pkg(&db, &conf, "foo", false).unwrap();
+
+        assert!(config.exists());
+        assert!(db.get(INSTALLED_PKGS, "foo").unwrap().is_none());
+        assert_eq!(
+            db.get(ALL_PKGS, "foo").unwrap().unwrap().local.installed,
+            Installed::False
+        );
+    }
+
+    #[test]
+    fn test_remove_purge() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = test_db(tmpdir.path());
+        let conf = test_config(tmpdir.path());
+        fs::create_dir_all(conf.binaries_path()).unwrap();
+
+        let mut pkg = test_package("foo", "1.0.0");
+        pkg.source.config_files = Some(vec!["foo.toml".to_owned()]);
+        pkg.local.installed = Installed::Manually(pkg.info.version.clone());
+        let config = conf.binaries_path().join("foo.toml");
+        fs::write(&config, "user = \"modified\"").unwrap();
+        fs::create_dir_all(conf.build_path(&pkg.info)).unwrap();
+
+        db.set(ALL_PKGS, "foo", pkg.clone()).unwrap();
+        db.set(INSTALLED_PKGS, "foo", pkg.clone()).unwrap();
+        remove_pkg(&db, &conf, "foo", true).unwrap();
+
+        assert!(!config.exists());
+        assert!(!conf.build_path(&pkg.info).exists());
+        assert!(db.get(INSTALLED_PKGS, "foo").unwrap().is_none());
+        assert!(db.get(ALL_PKGS, "foo").unwrap().is_none());
+    }
 }
@@ -0,0 +1,169 @@
+use std::path::Path;
+
+use redb::{Database, ReadableTable};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::doctor::read_rows;
+use crate::pkg::Installed;
+use crate::{ALL_PKGS, INSTALLED_PKGS};
+
+/// Error reading a manifest.
+#[derive(Debug, Error)]
+pub enum ManifestError {
+    #[error("invalid TOML manifest: {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("invalid JSON manifest: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// The manually installed and added packages, to reproduce a setup on another machine.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Manifest {
+    #[serde(default)]
+    pub packages: Vec<ManifestEntry>,
+}
+
+/// A package listed in a [`Manifest`].
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ManifestEntry {
+    /// A package name, optionally with a version requirement (e.g. `foo@>=1.2, <2`).
+    Spec(String),
+    /// A package with the version installed when the manifest was exported.
+    Exported { name: String, version: String },
+}
+
+impl ManifestEntry {
+    /// Get the name of the package.
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Spec(spec) => spec.split_once('@').map_or(spec, |(name, _)| name),
+            Self::Exported { name, .. } => name,
+        }
+    }
+}
+
+impl Manifest {
+    /// Collect the installed packages of `INSTALLED_PKGS` that were installed manually or added, sorted by name.
+    pub fn from_db(db: &Database) -> Result<Self, redb::Error> {
+        let read_txn = db.begin_read()?;
+        let read_table = read_txn.open_table(INSTALLED_PKGS)?;
+
+        let mut packages = Vec::new();
+        for pkg in read_table.iter()? {
+            let (key, value) = pkg?;
+            let pkg = value.value();
+            let version = match &pkg.installed {
+                Installed::Manually(version) => version,
+                Installed::Automatically(version) if pkg.added => version,
+                _ => continue,
+            };
+            packages.push(ManifestEntry::Exported {
+                name: key.value().to_owned(),
+                version: version.to_string(),
+            });
+        }
+
+        Ok(Self { packages })
+    }
+
+    /// Get the names of the listed packages that neither are in `ALL_PKGS` nor provided by a package in it.
+    pub fn missing(&self, db: &Database) -> Result<Vec<&str>, redb::Error> {
+        let all = read_rows(db, ALL_PKGS)?;
+        let provided: Vec<&str> = all
+            .values()
+            .flat_map(|pkg| pkg.provides.iter().map(String::as_str))
+            .collect();
+
+        Ok(self
+            .packages
+            .iter()
+            .map(ManifestEntry::name)
+            .filter(|name| !all.contains_key(*name) && !provided.contains(name))
+            .collect())
+    }
+
+    /// Serialize the manifest as JSON if `json` is set, as TOML otherwise.
+    pub fn to_string(&self, json: bool) -> String {
+        if json {
+            let mut contents = serde_json::to_string_pretty(self).unwrap();
+            contents.push('\n');
+            contents
+        } else {
+            toml::to_string(self).unwrap()
+        }
+    }
+
+    /// Parse a manifest written by [`Manifest::to_string`], as JSON if `json` is set, as TOML otherwise.
+    pub fn parse(contents: &str, json: bool) -> Result<Self, ManifestError> {
+        if json {
+            Ok(serde_json::from_str(contents)?)
+        } else {
+            Ok(toml::from_str(contents)?)
+        }
+    }
+}
+
+/// Whether the manifest at `path` is (or should be written as) JSON, judging by its extension.
+pub fn is_json(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "json")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use semver::Version;
+
+    use super::*;
+    use crate::db::Db;
+    use crate::tests::{test_db, test_package};
+
+    #[test]
+    fn test_manifest() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = test_db(tmpdir.path());
+
+        let version = Version::from_str("1.0.0").unwrap();
+        for (name, installed, added) in [
+            ("manual", Installed::Manually(version.clone()), false),
+            (
+                "dependency",
+                Installed::Automatically(version.clone()),
+                false,
+            ),
+            ("added", Installed::Automatically(version.clone()), true),
+        ] {
+            let mut pkg = test_package(name, "1.0.0");
+            pkg.local.installed = installed;
+            pkg.local.added = added;
+            db.set(ALL_PKGS, name, pkg.clone()).unwrap();
+            db.set(INSTALLED_PKGS, name, pkg).unwrap();
+        }
+
+        let manifest = Manifest::from_db(&db).unwrap();
+        let names: Vec<_> = manifest.packages.iter().map(ManifestEntry::name).collect();
+        assert_eq!(names, ["added", "manual"]);
+
+        for json in [false, true] {
+            let contents = manifest.to_string(json);
+            assert_eq!(Manifest::parse(&contents, json).unwrap(), manifest);
+        }
+        assert!(Manifest::parse("packages = 1", false).is_err());
+
+        let manifest = Manifest::parse(
+            "packages = [\"manual@>=1\", \"unknown\", { name = \"added\", version = \"1.0.0\" }]",
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            manifest.packages[0],
+            ManifestEntry::Spec("manual@>=1".to_owned())
+        );
+        assert_eq!(manifest.missing(&db).unwrap(), ["unknown"]);
+
+        assert!(is_json(Path::new("setup.json")));
+        assert!(!is_json(Path::new("setup.toml")));
+    }
+}
@@ -0,0 +1,62 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Write `contents` to `path`, or to stdout if `path` is `None`.
+///
+/// Files are written atomically: the contents go to a temporary file next to `path` first,
+/// which then replaces `path`. On error, an existing file at `path` is left untouched.
+/// Missing parent directories are created.
+pub fn write_output(path: Option<&Path>, contents: &str) -> io::Result<()> {
+    let Some(path) = path else {
+        return io::stdout().write_all(contents.as_bytes());
+    };
+
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    fs::create_dir_all(parent)?;
+
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(path.file_name().unwrap_or_default());
+    tmp_name.push(".tmp");
+    let tmp = parent.join(tmp_name);
+
+    let result = fs::write(&tmp, contents).and_then(|_| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_output() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("nested").join("out.txt");
+
+        write_output(Some(&path), "first").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first");
+
+        write_output(Some(&path), "second").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+        assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_write_output_error_keeps_file() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("out.txt");
+        fs::write(&path, "old").unwrap();
+
+        // A directory in place of the temporary file makes the write fail.
+        fs::create_dir(tmpdir.path().join(".out.txt.tmp")).unwrap();
+        assert!(write_output(Some(&path), "new").is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+    }
+}
@@ -1,30 +1,223 @@
-use std::collections::HashSet;
-use std::ffi::OsStr;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
 use std::fs::{self, File};
-use std::io::{self, BufReader, Write};
+use std::io::{self, BufReader, Read, Write};
+use std::iter;
+use std::mem;
 use std::ops::Deref;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 use std::process::{Command, ExitStatus};
+use std::str::FromStr;
+#[cfg(test)]
+use std::sync::Arc;
+#[cfg(feature = "parallel")]
+use std::sync::Condvar;
+#[cfg(any(test, feature = "parallel"))]
+use std::sync::Mutex;
+#[cfg(feature = "parallel")]
+use std::thread;
+#[cfg(feature = "parallel")]
+use std::time::Duration;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use flate2::bufread::GzDecoder;
 use futures::stream::FuturesUnordered;
-use futures::StreamExt;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use inquire::Confirm;
+use futures::{Future, StreamExt};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use log::{info, trace, warn};
-use sha2::{Digest, Sha512};
+use redb::{Database, ReadableTable};
+use reqwest::header::RANGE;
+use reqwest::StatusCode;
+use semver::{Version, VersionReq};
+use sha2::{Digest, Sha256, Sha512};
 use tar::Archive;
+use thiserror::Error;
 
-use crate::db::Db;
-use crate::pkg::{Installed, Local, Package};
+use crate::config::Config;
+use crate::db::{version_key, Db, DbPackage};
+use crate::lockfile;
+use crate::pkg::{parse_spec, select_version, Dependency, Installed, Local, Package, Source, Vcs};
 use crate::pkgfile::PackageFile;
-use crate::{exit_with_message, DynResult, ALL_PKGS, CONFIG, DB, INSTALLED_PKGS};
+use crate::{
+    confirm, exit_with_message, select, DynResult, ALL_PKGS, CONFIG, DB, INSTALLED_PKGS,
+    PKG_VERSIONS, TRUSTED_SOURCES,
+};
+
+/// Errors while resolving or executing a payload.
+#[derive(Debug, Error)]
+pub enum PayloadError {
+    #[error("dependency cycle: {}", .0.join(" -> "))]
+    DependencyCycle(Vec<String>),
+    #[error("dependency {dependency} of package {package} not found ({} -> {dependency})", .path.join(" -> "))]
+    DependencyNotFound {
+        package: String,
+        dependency: String,
+        /// The chain of packages that led to `package` (ending with `package`).
+        path: Vec<String>,
+    },
+    #[error("invalid dependency {dependency} of package {package}: {source}")]
+    InvalidDependency {
+        package: String,
+        dependency: String,
+        source: semver::Error,
+    },
+    #[error(
+        "package {package} requires {dependency} {req}, but only version {version} is available"
+    )]
+    UnsatisfiedDependency {
+        package: String,
+        dependency: String,
+        req: VersionReq,
+        version: Version,
+    },
+    #[error("conflicting versions of package {0}")]
+    Conflict(String),
+    #[error("preparing package {package} failed ({status}):\n{stderr}")]
+    PrepareFailed {
+        package: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+    #[error("building package {package} failed ({status}):\n{stderr}")]
+    BuildFailed {
+        package: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+    #[error("installing package {package} failed ({status}):\n{stderr}")]
+    InstallFailed {
+        package: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+    #[error("package {package} and package {other} both install {}", .file.to_string_lossy())]
+    FileConflict {
+        package: String,
+        other: String,
+        /// The file relative to the binaries directory.
+        file: PathBuf,
+    },
+    #[error("package {package} installs {}, which exists already, but isn't owned by any package (install with --overwrite to replace it)", .file.to_string_lossy())]
+    UnownedFile {
+        package: String,
+        /// The file relative to the binaries directory.
+        file: PathBuf,
+    },
+    #[error("post-install command of package {package} failed ({status}):\n{stderr}")]
+    PostInstallFailed {
+        package: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+    #[error("cloning the repository of package {package} failed ({status}):\n{stderr}")]
+    CloneFailed {
+        package: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+    #[error("uninstall script of package {0} failed")]
+    UninstallFailed(String),
+    #[error("download of {url} is incomplete ({actual} of {expected} bytes)")]
+    IncompleteDownload {
+        url: String,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("download of {0} timed out")]
+    TimedOut(String),
+    #[error("invalid checksum of {0}")]
+    InvalidChecksum(String),
+    #[error("extracted source for {0} is empty — download may be corrupt, remove it with `mercurium clean --sources` and try again")]
+    EmptySource(String),
+    #[error("unknown checksum algorithm {0}")]
+    UnknownChecksumAlgo(String),
+    #[cfg(feature = "verify-signatures")]
+    #[error("package {0} has a signature, but no signing key")]
+    MissingSigningKey(String),
+    #[cfg(feature = "verify-signatures")]
+    #[error("invalid signature of package {package}: {reason}")]
+    InvalidSignature { package: String, reason: String },
+    #[error("error accessing database: {0}")]
+    Db(#[from] redb::Error),
+}
+
+/// A hash algorithm for checksums.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChecksumAlgo {
+    Sha256,
+    Sha512,
+}
+
+impl ChecksumAlgo {
+    /// Split a checksum like `sha256:<hex>` into the algorithm and the hex digest.
+    /// Checksums without a prefix are SHA512.
+    pub fn parse(spec: &str) -> Result<(Self, &str), PayloadError> {
+        match spec.split_once(':') {
+            Some(("sha256", digest)) => Ok((Self::Sha256, digest)),
+            Some(("sha512", digest)) => Ok((Self::Sha512, digest)),
+            Some((algo, _)) => Err(PayloadError::UnknownChecksumAlgo(algo.to_owned())),
+            None => Ok((Self::Sha512, spec)),
+        }
+    }
+
+    /// Hash `data` with this algorithm.
+    pub fn digest(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Sha256 => Sha256::digest(data).to_vec(),
+            Self::Sha512 => Sha512::digest(data).to_vec(),
+        }
+    }
+}
+
+/// The output of a finished command.
+#[derive(Clone, Debug)]
+pub struct CommandOutput {
+    pub status: ExitStatus,
+    // Not used by mercurium itself, stdout is only traced.
+    #[allow(dead_code)]
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Destination for the output of commands.
+///
+/// Every line is emitted at once and prefixed with the package it belongs to,
+/// so the output of concurrently running commands doesn't interleave and stays attributable.
+#[derive(Clone, Debug)]
+pub enum OutputSink {
+    /// Log the lines.
+    Log,
+    /// Print the lines above the progress bars.
+    Progress(MultiProgress),
+    /// Collect the lines.
+    #[cfg(test)]
+    Buffer(Arc<Mutex<Vec<String>>>),
+}
+
+impl OutputSink {
+    /// Emit the line `line` of the package `package`.
+    pub fn line(&self, package: &str, line: &str) {
+        let line = format!("[{package}] {line}");
+        match self {
+            Self::Log => info!("{line}"),
+            Self::Progress(mpb) => {
+                if mpb.println(&line).is_err() {
+                    warn!("{line}");
+                }
+            }
+            #[cfg(test)]
+            Self::Buffer(lines) => lines.lock().unwrap().push(line),
+        }
+    }
+}
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 struct PayloadPackage {
     file: PackageFile,
     manually_selected: bool,
     manually_added: bool,
+    /// Whether the package is only needed to build other packages of the payload, not to run them.
+    only_build: bool,
 }
 
 impl Deref for PayloadPackage {
@@ -35,6 +228,50 @@ impl Deref for PayloadPackage {
     }
 }
 
+/// A resolved payload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InstallPlan {
+    /// The names of the packages in the order they are installed in (dependencies first).
+    pub order: Vec<String>,
+    /// `order` split into stages. A stage is only built once the previous ones are installed,
+    /// because its packages build-depend on them.
+    pub stages: Vec<Vec<String>>,
+}
+
+impl InstallPlan {
+    /// Get a plan for each of the `stages`.
+    fn stage_plans(&self) -> impl Iterator<Item = InstallPlan> + '_ {
+        self.stages.iter().map(|stage| InstallPlan {
+            order: stage.clone(),
+            stages: vec![stage.clone()],
+        })
+    }
+}
+
+/// What executing a payload did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// The packages were installed.
+    Installed,
+    /// All packages were already installed and up-to-date, so nothing was done.
+    UpToDate,
+    /// The user declined to install the packages or to trust their sources, so nothing was done.
+    Aborted,
+}
+
+/// How to choose between several packages providing the same virtual package.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Selection {
+    /// Choose the first provider by name.
+    #[default]
+    First,
+    /// Ask the user.
+    Interactive,
+    /// Choose the providers at these indices in turn.
+    #[cfg(test)]
+    Scripted(Vec<usize>),
+}
+
 #[derive(Clone, Debug)]
 struct MultiProgressFormat<'a> {
     multiprogress: &'a MultiProgress,
@@ -45,26 +282,150 @@ struct MultiProgressFormat<'a> {
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct Payload {
     packages: HashSet<PayloadPackage>,
+    /// Fail instead of warning if an install script doesn't install any files.
+    pub strict: bool,
+    /// Trust the sources of all packages without asking, and without recording them as trusted.
+    pub trust: bool,
+    /// Answer all questions with yes instead of asking (`--yes`), except whether to trust a source.
+    pub assume_yes: bool,
+    /// Don't print status messages and progress bars (`--quiet`).
+    pub quiet: bool,
+    /// Overwrite existing files (also ones installed by other packages) instead of failing.
+    pub overwrite: bool,
+    /// Skip the build step, even for packages with a build command.
+    pub no_build: bool,
+    /// Only install the dependencies of the added packages, not the packages themselves.
+    pub only_dependencies: bool,
+    /// Reinstall the added packages from scratch, even if they are up-to-date.
+    /// Their dependencies are still skipped if they are up-to-date.
+    pub force: bool,
+    /// The added packages that aren't installed because of `only_dependencies`.
+    skipped: Vec<String>,
+    /// The pkgfiles added by the user that aren't installed because they are up-to-date already.
+    /// They are still added to the database together with the installation.
+    up_to_date_added: Vec<PackageFile>,
+    /// How to choose between several providers of a dependency.
+    pub selection: Selection,
+    /// The files installed by each package.
+    files: HashMap<String, Vec<PathBuf>>,
+}
+
+/// Get the current time in seconds since the Unix epoch.
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs() as i64)
+}
+
+/// Whether `url` starts with `prefix` at a path boundary, so that `https://github.com/foo`
+/// covers `https://github.com/foo/bar`, but not `https://github.com/foobar`.
+fn has_path_prefix(url: &str, prefix: &str) -> bool {
+    url.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'))
+}
+
+/// Get the package in `pkgs` satisfying the dependency `dep`,
+/// preferring a package with that name over one providing it.
+fn find_dependency<'a>(
+    pkgs: impl IntoIterator<Item = &'a PayloadPackage>,
+    dep: &str,
+) -> Option<&'a PayloadPackage> {
+    let dep = Dependency::name_of(dep);
+    let mut provider = None;
+    for pkg in pkgs {
+        if pkg.info.name == dep {
+            return Some(pkg);
+        }
+        if provider.is_none() && pkg.info.provides(dep) {
+            provider = Some(pkg);
+        }
+    }
+    provider
+}
+
+/// Check that `version` of the dependency `name` of `pkg` satisfies `req`.
+fn check_requirement(
+    pkg: &PackageFile,
+    name: &str,
+    req: &VersionReq,
+    version: &Version,
+) -> Result<(), PayloadError> {
+    if req.matches(version) {
+        Ok(())
+    } else {
+        Err(PayloadError::UnsatisfiedDependency {
+            package: pkg.info.name.clone(),
+            dependency: name.to_owned(),
+            req: req.clone(),
+            version: version.clone(),
+        })
+    }
 }
 
 impl Payload {
     /// Download a tarball from a URL.
+    /// If a partial download exists at `path`, it is resumed with a range request.
+    /// Servers that don't support range requests send the whole file, which replaces the partial one.
     async fn download_source<'a>(
+        client: &reqwest::Client,
         url: &str,
         path: impl AsRef<Path>,
         mpb: Option<MultiProgressFormat<'a>>,
     ) -> DynResult<()> {
-        let response = reqwest::get(url).await?;
-        let total_size = response.content_length().unwrap();
+        let existing = fs::metadata(&path).map_or(0, |metadata| metadata.len());
 
-        let pb = mpb.map(|MultiProgressFormat { multiprogress: mpb, message, longest_message }| {
-            let pb = mpb.add(ProgressBar::new(total_size));
-            pb.set_style(
-            ProgressStyle::default_bar()
-                .template(&format!("{{spinner:.green}} {{msg:{longest_message}!}} [{{wide_bar:.cyan/blue}}] {{bytes}}/{{total_bytes}} ({{bytes_per_sec}}, {{eta}})")).unwrap()
-                .progress_chars("#>-")
+        let mut request = client.get(url);
+        if existing > 0 {
+            request = request.header(RANGE, format!("bytes={existing}-"));
+        }
+        let mut response = request
+            .send()
+            .await
+            .map_err(|err| Self::request_error(url, err))?;
+        if existing > 0 && response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            // The partial file is at least as large as the source, so it can't be resumed.
+            response = client
+                .get(url)
+                .send()
+                .await
+                .map_err(|err| Self::request_error(url, err))?;
+        }
+        let response = response.error_for_status()?;
+
+        let (mut file, mut downloaded) = if response.status() == StatusCode::PARTIAL_CONTENT {
+            info!(
+                "Resuming download of {} at {existing} bytes.",
+                path.as_ref().to_string_lossy()
             );
+            (fs::OpenOptions::new().append(true).open(&path)?, existing)
+        } else {
+            (File::create(&path)?, 0)
+        };
+        let total_size = response.content_length().map(|len| downloaded + len);
+
+        let pb = mpb.map(|MultiProgressFormat { multiprogress: mpb, message, longest_message }| {
+            let pb = match total_size {
+                Some(total_size) => {
+                    let pb = mpb.add(ProgressBar::new(total_size));
+                    pb.set_style(
+                    ProgressStyle::default_bar()
+                        .template(&format!("{{spinner:.green}} {{msg:{longest_message}!}} [{{wide_bar:.cyan/blue}}] {{bytes}}/{{total_bytes}} ({{bytes_per_sec}}, {{eta}})")).unwrap()
+                        .progress_chars("#>-")
+                    );
+                    pb
+                }
+                // Without a known size, only show how much was downloaded.
+                None => {
+                    let pb = mpb.add(ProgressBar::new_spinner());
+                    pb.set_style(
+                        ProgressStyle::default_spinner()
+                            .template(&format!("{{spinner:.green}} {{msg:{longest_message}!}} {{bytes}} ({{bytes_per_sec}})")).unwrap()
+                    );
+                    pb
+                }
+            };
             pb.set_message(message);
+            pb.set_position(downloaded);
             pb
         });
 
@@ -74,14 +435,12 @@ impl Payload {
             url
         );
 
-        let mut file = fs::File::create(path)?;
-        let mut downloaded: u64 = 0;
         let mut stream = response.bytes_stream();
 
         while let Some(item) = stream.next().await {
-            let chunk = item?;
+            let chunk = item.map_err(|err| Self::request_error(url, err))?;
             file.write_all(&chunk)?;
-            downloaded = (downloaded + (chunk.len() as u64)).min(total_size);
+            downloaded += chunk.len() as u64;
             if let Some(pb) = &pb {
                 pb.set_position(downloaded);
             }
@@ -92,14 +451,216 @@ impl Payload {
             pb.finish();
         }
 
+        match total_size {
+            Some(expected) if expected != downloaded => Err(PayloadError::IncompleteDownload {
+                url: url.to_owned(),
+                expected,
+                actual: downloaded,
+            }
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Report a timed out request to `url` as [`PayloadError::TimedOut`], so that the URL is named.
+    fn request_error(url: &str, err: reqwest::Error) -> Box<dyn std::error::Error> {
+        if err.is_timeout() {
+            PayloadError::TimedOut(url.to_owned()).into()
+        } else {
+            err.into()
+        }
+    }
+
+    /// Download `url` of package `name` to `path`.
+    /// Failed downloads are retried up to `retries` times, resuming where they stopped.
+    async fn download_retrying<'a>(
+        client: &reqwest::Client,
+        name: &str,
+        url: &str,
+        path: &Path,
+        retries: usize,
+        mpb: Option<MultiProgressFormat<'a>>,
+    ) -> DynResult<()> {
+        let attempts = retries + 1;
+        let mut download = Self::download_source(client, url, path, mpb.clone()).await;
+        for attempt in 2..=attempts {
+            let Err(err) = &download else {
+                break;
+            };
+            warn!(
+                "Downloading package {name} from {url} failed: {err}. Retrying ({attempt}/{attempts})."
+            );
+            download = Self::download_source(client, url, path, mpb.clone()).await;
+        }
+        download
+    }
+
+    /// Download the tarball of `pkg` to `path` and check its checksum.
+    /// Failed downloads are retried up to `retries` times, resuming where they stopped.
+    /// If this fails for the `url`, the mirrors are tried in order.
+    async fn download_pkg<'a>(
+        client: &reqwest::Client,
+        pkg: &PackageFile,
+        path: PathBuf,
+        retries: usize,
+        mpb: Option<MultiProgressFormat<'a>>,
+    ) -> DynResult<()> {
+        let mut last_err = None;
+        let urls = iter::once(&pkg.source.url).chain(pkg.source.mirrors.iter().flatten());
+
+        for url in urls {
+            let download =
+                Self::download_retrying(client, &pkg.info.name, url, &path, retries, mpb.clone())
+                    .await;
+
+            let result = match download {
+                Ok(()) => match &pkg.source.checksum {
+                    Some(checksum) if !Self::verify_checksum_worker(&path, checksum).await? => {
+                        // A resumed download can't fix a corrupt file.
+                        fs::remove_file(&path)?;
+                        Err(PayloadError::InvalidChecksum(url.clone()).into())
+                    }
+                    _ => Ok(()),
+                },
+                Err(err) => Err(err),
+            };
+
+            match result {
+                Ok(()) => {
+                    info!("Downloaded package {} from {url}.", pkg.info.name);
+                    return Ok(());
+                }
+                Err(err) => {
+                    warn!(
+                        "Downloading package {} from {url} failed: {err}",
+                        pkg.info.name
+                    );
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        Err(last_err.expect("package without url"))
+    }
+
+    /// Download the remote patches of `pkg` next to its tarball.
+    async fn download_patches(
+        client: &reqwest::Client,
+        pkg: &PackageFile,
+        conf: &Config,
+        retries: usize,
+    ) -> DynResult<()> {
+        for (index, patch) in pkg.source.patches.iter().flatten().enumerate() {
+            if Source::is_remote(patch) {
+                let path = conf.patch_path(&pkg.info, index, patch);
+                Self::download_retrying(client, &pkg.info.name, patch, &path, retries, None)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Verify the tarball of `pkg` against its signature, downloading the signature first if it is a URL.
+    async fn check_signature(
+        client: &reqwest::Client,
+        pkg: &PackageFile,
+        conf: &Config,
+        retries: usize,
+    ) -> DynResult<()> {
+        let Some(signature) = &pkg.source.signature else {
+            return Ok(());
+        };
+        let signature = if Source::is_remote(signature) {
+            let path = conf.signature_path(&pkg.info);
+            Self::download_retrying(client, &pkg.info.name, signature, &path, retries, None)
+                .await?;
+            fs::read_to_string(path)?
+        } else {
+            signature.clone()
+        };
+
+        #[cfg(feature = "parallel")]
+        {
+            let (pkg, tar) = (pkg.clone(), conf.tarball_path(&pkg.info));
+            tokio::task::spawn_blocking(move || {
+                Self::verify_signature(&pkg, &tar, &signature).map_err(|err| err.to_string())
+            })
+            .await?
+            .map_err(Into::into)
+        }
+        #[cfg(not(feature = "parallel"))]
+        Self::verify_signature(pkg, &conf.tarball_path(&pkg.info), &signature)
+    }
+
+    /// Verify the file at `path` against the minisign `signature` with the signing key of `pkg`.
+    #[cfg(feature = "verify-signatures")]
+    fn verify_signature(pkg: &PackageFile, path: &Path, signature: &str) -> DynResult<()> {
+        use minisign_verify::{PublicKey, Signature};
+
+        let invalid = |err: minisign_verify::Error| PayloadError::InvalidSignature {
+            package: pkg.info.name.clone(),
+            reason: err.to_string(),
+        };
+        let key = pkg
+            .source
+            .signing_key
+            .as_deref()
+            .ok_or_else(|| PayloadError::MissingSigningKey(pkg.info.name.clone()))?;
+        let key = PublicKey::from_base64(key).map_err(invalid)?;
+        let signature = Signature::decode(signature).map_err(invalid)?;
+
+        info!("Checking signature.");
+        key.verify(&fs::read(path)?, &signature, false)
+            .map_err(invalid)?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "verify-signatures"))]
+    fn verify_signature(pkg: &PackageFile, _path: &Path, _signature: &str) -> DynResult<()> {
+        warn!(
+            "Not checking the signature of package {}, mercurium was built without the verify-signatures feature.",
+            pkg.info.name
+        );
         Ok(())
     }
 
+    /// Build the HTTP client shared by all downloads, configured with the timeout and proxies of `conf`.
+    fn client(conf: &Config) -> reqwest::Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(timeout) = conf.timeout() {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(proxy) = conf.http_proxy() {
+            builder = builder.proxy(reqwest::Proxy::http(proxy)?);
+        }
+        if let Some(proxy) = conf.https_proxy() {
+            builder = builder.proxy(reqwest::Proxy::https(proxy)?);
+        }
+        builder.build()
+    }
+
+    /// Print the status message `message`, unless `quiet` is set.
+    fn status(&self, message: &str) {
+        if !self.quiet {
+            println!("{message}");
+        }
+    }
+
+    /// Create the progress bars of a step, hidden if `quiet` is set.
+    fn multi_progress(&self) -> MultiProgress {
+        if self.quiet {
+            MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+        } else {
+            MultiProgress::new()
+        }
+    }
+
     /// Download all `packages`.
     async fn download_pkgs(&self) -> DynResult<()> {
         let conf = CONFIG.get().unwrap();
-        println!("Downloading packages...");
-        let mpb = MultiProgress::new();
+        self.status("Downloading packages...");
+        let mpb = self.multi_progress();
+        let client = Self::client(conf)?;
 
         let longest_message = self
             .packages
@@ -108,134 +669,372 @@ impl Payload {
             .max()
             .unwrap();
 
+        let client = &client;
         let futures = FuturesUnordered::new();
         for pkg in &self.packages {
-            let tar_name = format!("{}_{}.tar.gz", pkg.info.name, pkg.info.version);
-            let tar = conf.sources_path().join(tar_name);
+            if let Some(vcs) = pkg.source.vcs {
+                // Repositories are cloned right into the build directory, no tarball is downloaded.
+                Self::clone_pkg(pkg, vcs, &conf.build_path(&pkg.info))?;
+            }
+            let tar = conf.tarball_path(&pkg.info);
             fs::create_dir_all(conf.sources_path())?;
-            let future = Self::download_source(
-                &pkg.source.url,
-                tar,
-                Some(MultiProgressFormat {
-                    multiprogress: &mpb,
-                    message: pkg.info.name.clone(),
-                    longest_message,
-                }),
-            );
+            if self.force && tar.exists() {
+                // Don't resume a possibly corrupt download.
+                fs::remove_file(&tar)?;
+            }
+            let progress = Some(MultiProgressFormat {
+                multiprogress: &mpb,
+                message: pkg.info.name.clone(),
+                longest_message,
+            });
+            let future = async move {
+                if pkg.source.vcs.is_some() {
+                    return Self::download_patches(client, pkg, conf, conf.retries()).await;
+                }
+                Self::download_pkg(client, pkg, tar, conf.retries(), progress).await?;
+                Self::download_patches(client, pkg, conf, conf.retries()).await?;
+                Self::check_signature(client, pkg, conf, conf.retries()).await
+            };
             futures.push(future);
         }
 
-        let _: Vec<_> = futures.collect().await;
+        Self::await_downloads(futures).await
+    }
+
+    /// Clone the repository of `pkg` with `vcs` into `dir` and check out its revision, replacing an earlier clone.
+    /// The revision takes the place of the checksum, so nothing is verified.
+    fn clone_pkg(pkg: &PackageFile, vcs: Vcs, dir: &Path) -> DynResult<()> {
+        if dir.exists() {
+            fs::remove_dir_all(dir)?;
+        }
+
+        let mut commands = Vec::new();
+        match vcs {
+            Vcs::Git => {
+                let mut clone = Command::new("git");
+                clone
+                    .args(["clone", "--quiet", "--"])
+                    .arg(&pkg.source.url)
+                    .arg(dir);
+                commands.push(clone);
+                if let Some(rev) = &pkg.source.rev {
+                    let mut checkout = Command::new("git");
+                    checkout
+                        .arg("-C")
+                        .arg(dir)
+                        .args(["checkout", "--quiet", rev.as_str(), "--"]);
+                    commands.push(checkout);
+                }
+            }
+        }
+
+        for mut command in commands {
+            let output = command.output()?;
+            if !output.status.success() {
+                return Err(PayloadError::CloneFailed {
+                    package: pkg.info.name.clone(),
+                    status: output.status,
+                    stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+                }
+                .into());
+            }
+        }
+
         Ok(())
     }
 
-    /// Check which packages have to be installed.
-    fn check_install(&mut self) -> DynResult<()> {
-        let db = DB.get().unwrap();
-        let pkgs = db.get_iter(
-            INSTALLED_PKGS,
-            self.packages.iter().map(|x| x.info.name.as_str()),
-        )?;
-
-        self.packages.retain(|payload_pkg| {
-            for db_pkg in pkgs.iter().flatten() {
-                if db_pkg.info.version >= payload_pkg.info.version {
-                    db.modify(INSTALLED_PKGS, db_pkg.info.name.as_str(), |pkg| match pkg {
-                        Some(mut pkg) => match pkg.local.installed {
-                            Installed::Automatically(ver) | Installed::Manually(ver) => {
-                                pkg.local.installed = Installed::Manually(ver);
-                                Some(pkg)
-                            }
-                            Installed::False => {
-                                warn!(
-                                    "Not installed package {} in INSTALLED_PKGS table!",
-                                    pkg.info.name
-                                );
-                                Some(pkg)
-                            }
-                        },
-                        None => None,
-                    })
-                    .expect("error writing database");
-                    return false;
+    /// Wait for all `downloads`. As soon as one of them fails, the remaining ones are aborted.
+    async fn await_downloads(
+        mut downloads: FuturesUnordered<impl Future<Output = DynResult<()>>>,
+    ) -> DynResult<()> {
+        while let Some(result) = downloads.next().await {
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Check the checksum of the file at `path` on a worker thread, so that the other downloads continue meanwhile.
+    async fn verify_checksum_worker(path: &Path, spec: &str) -> DynResult<bool> {
+        #[cfg(feature = "parallel")]
+        {
+            let (path, spec) = (path.to_owned(), spec.to_owned());
+            tokio::task::spawn_blocking(move || {
+                Self::verify_checksum(path, &spec).map_err(|err| err.to_string())
+            })
+            .await?
+            .map_err(Into::into)
+        }
+        #[cfg(not(feature = "parallel"))]
+        Self::verify_checksum(path, spec)
+    }
+
+    /// Find the packages of the payload whose installed version is at least the one to install, except for
+    /// forced ones. Manually selected ones among them are marked as manually installed, in `INSTALLED_PKGS` and
+    /// `ALL_PKGS` at once; dependencies keep their installation reason.
+    /// Returns the names of these packages.
+    fn mark_up_to_date(&self, db: &Database) -> Result<HashSet<String>, redb::Error> {
+        let mut up_to_date = HashSet::new();
+
+        let write_txn = db.begin_write()?;
+        {
+            let mut installed_table = write_txn.open_table(INSTALLED_PKGS)?;
+            let mut all_table = write_txn.open_table(ALL_PKGS)?;
+
+            for payload_pkg in &self.packages {
+                if self.force && payload_pkg.manually_selected {
+                    continue;
+                }
+                let name = payload_pkg.info.name.as_str();
+                let Some(db_pkg) = installed_table.get(name)?.map(|pkg| pkg.value()) else {
+                    continue;
+                };
+                let mut db_pkg = Package::try_from(db_pkg)?;
+                let Some(version) = db_pkg.local.installed.version().cloned() else {
+                    warn!("Not installed package {name} in INSTALLED_PKGS table! Removing it.");
+                    installed_table.remove(name)?;
+                    continue;
+                };
+                if db_pkg.info.version < payload_pkg.info.version {
+                    continue;
+                }
+
+                up_to_date.insert(name.to_owned());
+                if !payload_pkg.manually_selected {
+                    continue;
+                }
+                db_pkg.local.installed = Installed::Manually(version.clone());
+                installed_table.insert(name, DbPackage::from(db_pkg))?;
+                let all_pkg = all_table.get(name)?.map(|pkg| pkg.value());
+                if let Some(mut all_pkg) = all_pkg {
+                    all_pkg.installed = Installed::Manually(version);
+                    all_table.insert(name, all_pkg)?;
                 }
             }
-            true
-        });
+        }
+        write_txn.commit()?;
+
+        Ok(up_to_date)
+    }
+
+    /// Remove the up-to-date packages (see [`Payload::mark_up_to_date`]) from the payload.
+    /// Up-to-date pkgfiles added by the user are kept in `up_to_date_added`.
+    fn skip_up_to_date(&mut self, db: &Database) -> Result<(), redb::Error> {
+        let up_to_date = self.mark_up_to_date(db)?;
+        let (up_to_date, packages): (Vec<_>, Vec<_>) = mem::take(&mut self.packages)
+            .into_iter()
+            .partition(|payload_pkg| up_to_date.contains(&payload_pkg.info.name));
+        self.packages = packages.into_iter().collect();
+        self.up_to_date_added = up_to_date
+            .into_iter()
+            .filter(|payload_pkg| payload_pkg.manually_added)
+            .map(|payload_pkg| payload_pkg.file)
+            .collect();
+
+        Ok(())
+    }
+
+    /// Check which packages have to be installed and ask for confirmation.
+    /// Returns the outcome if there is nothing to do, `None` if the installation should go on.
+    fn check_install(&mut self) -> DynResult<Option<Outcome>> {
+        self.skip_up_to_date(DB.get().unwrap())?;
+        if !self.skipped.is_empty() {
+            self.status(&format!(
+                "Only installing the dependencies of {}, not the packages themselves.",
+                self.skipped.join(", ")
+            ));
+        }
         if self.packages.is_empty() {
-            exit_with_message(
-                "All packages are already installed and up-to-date.",
-                exitcode::OK,
-            );
+            self.status("All packages are already installed and up-to-date.");
+            self.write_db(DB.get().unwrap())?;
+            return Ok(Some(Outcome::UpToDate));
+        }
+
+        // The list is only cosmetic if nothing is asked.
+        if !(self.quiet && self.assume_yes) {
+            println!("Packages marked to be installed:");
+            let mut iter = self.packages.iter();
+            print!("{}", iter.next().expect("empty package list").info.name);
+            for pkg in iter {
+                print!(", {}", pkg.info.name)
+            }
+            println!();
         }
 
-        println!("Packages marked to be installed:");
-        let mut iter = self.packages.iter();
-        print!("{}", iter.next().expect("empty package list").info.name);
-        for pkg in iter {
-            print!(", {}", pkg.info.name)
+        if !self.assume_yes && !confirm("Do you want to install these packages?") {
+            self.status("Aborting...");
+            return Ok(Some(Outcome::Aborted));
         }
-        println!();
 
-        let ans = Confirm::new("Do you want to install these packages?")
-            .with_default(false)
-            .prompt()?;
+        Ok(None)
+    }
 
-        if !ans {
-            exit_with_message("Aborting...", exitcode::OK);
+    /// Get the hash of everything `pkg` runs (see [`Source::commands`]), to notice when it changes.
+    fn commands_hash(pkg: &PackageFile) -> String {
+        let mut hasher = Sha256::new();
+        for (label, command) in pkg.source.commands() {
+            hasher.update(label);
+            hasher.update([0u8]);
+            hasher.update(command);
+            hasher.update([0u8]);
         }
+        hex::encode(hasher.finalize())
+    }
 
-        Ok(())
+    /// Show everything `pkg` runs and ask whether its source is trusted.
+    fn prompt_trust(pkg: &PackageFile) -> DynResult<bool> {
+        println!(
+            "Package {} comes from the untrusted source {}.",
+            pkg.info.name, pkg.source.url
+        );
+        for (label, command) in pkg.source.commands() {
+            println!("{label}: {command}");
+        }
+
+        Ok(confirm("Do you trust this source?"))
     }
 
-    /// Check the SHA512 checksum of a file at `path`.
-    fn check_sha512(path: impl AsRef<Path>, sha512: &str) -> DynResult<bool> {
-        info!("Checking SHA512 checksum.");
+    /// Ask for confirmation via `confirm` before running the commands of packages from untrusted sources.
+    /// Sources are the URLs packages are downloaded from; the repository a pkgfile claims doesn't matter.
+    /// Sources below one of the `trusted` prefixes (see [`has_path_prefix`]) are trusted.
+    /// Confirmed sources are recorded with the hash of the commands, so they are only asked for again
+    /// if the commands change. Sources accepted by `trust` aren't recorded, and `assume_yes` doesn't accept any.
+    /// Returns whether all sources are trusted.
+    fn check_trust(
+        &self,
+        db: &Database,
+        trusted: &[String],
+        mut confirm: impl FnMut(&PackageFile) -> DynResult<bool>,
+    ) -> DynResult<bool> {
+        for pkg in &self.packages {
+            let url = pkg.source.url.as_str();
+            if trusted.iter().any(|prefix| has_path_prefix(url, prefix)) {
+                continue;
+            }
+
+            let hash = Self::commands_hash(pkg);
+            let known = db
+                .begin_read()?
+                .open_table(TRUSTED_SOURCES)?
+                .get(url)?
+                .is_some_and(|confirmed| confirmed.value() == hash);
+            if known {
+                continue;
+            }
+
+            if self.trust {
+                continue;
+            }
+            if self.assume_yes {
+                warn!(
+                    "Package {} comes from the untrusted source {url}, pass --trust to install it without asking.",
+                    pkg.info.name
+                );
+                return Ok(false);
+            }
+            if !confirm(pkg)? {
+                return Ok(false);
+            }
+
+            let write_txn = db.begin_write()?;
+            write_txn
+                .open_table(TRUSTED_SOURCES)?
+                .insert(url, hash.as_str())?;
+            write_txn.commit()?;
+        }
+
+        Ok(true)
+    }
 
-        let sha512 = hex::decode(sha512)?;
-        trace!("Reference: {:x?}", sha512);
+    /// Check the checksum `spec` (see [`ChecksumAlgo::parse`]) of a file at `path`.
+    fn verify_checksum(path: impl AsRef<Path>, spec: &str) -> DynResult<bool> {
+        let (algo, checksum) = ChecksumAlgo::parse(spec)?;
+        info!("Checking {algo:?} checksum.");
 
-        let mut hasher = Sha512::new();
+        let checksum = hex::decode(checksum)?;
+        trace!("Reference: {:x?}", checksum);
 
         let binary = fs::read(path)?;
-
-        hasher.update(&binary);
-        let result = hasher.finalize();
+        let result = algo.digest(&binary);
 
         trace!("Calculated: {:x?}", result);
 
-        Ok(result[..] == sha512[..])
+        Ok(result == checksum)
     }
 
-    /// Check the SHA512 checksum of all `package` tarballs.
-    fn check_sha512_pkgs(&self) -> DynResult<()> {
-        let conf = CONFIG.get().unwrap();
-        println!("Checking SHA512 checksums...");
+    /// Open the gzipped tarball at `path`.
+    fn open_tarball(path: impl AsRef<Path>) -> io::Result<Archive<GzDecoder<BufReader<File>>>> {
+        let tar_gz = BufReader::new(File::open(path)?);
+        Ok(Archive::new(GzDecoder::new(tar_gz)))
+    }
 
-        for pkg in &self.packages {
-            let tar_name = format!("{}_{}.tar.gz", pkg.info.name, pkg.info.version);
+    /// Get the components of an entry path, without `.` components.
+    fn entry_components(path: &Path) -> Vec<Component<'_>> {
+        path.components()
+            .filter(|component| *component != Component::CurDir)
+            .collect()
+    }
 
-            if let Some(checksum) = &pkg.source.checksum {
-                if !Self::check_sha512(&conf.sources_path().join(tar_name), checksum)? {
-                    exit_with_message(
-                        format!("Invalid checksum in package {}!", pkg.info.name),
-                        exitcode::SOFTWARE, // TODO: Flag to ignore checksum
-                    )
-                }
+    /// Check whether all entries of the tarball at `path` are in a single top-level directory.
+    /// Returns the number of leading components to strip: 1 if they are, 0 otherwise.
+    fn detect_strip_components(path: impl AsRef<Path>) -> io::Result<usize> {
+        let mut top: Option<OsString> = None;
+        for entry in Self::open_tarball(path)?.entries()? {
+            let entry = entry?;
+            let path = entry.path()?;
+            let components = Self::entry_components(&path);
+            let Some(first) = components.first() else {
+                continue;
+            };
+            let is_dir = components.len() > 1 || entry.header().entry_type().is_dir();
+            match &top {
+                _ if !is_dir => return Ok(0),
+                Some(top) if top.as_os_str() != first.as_os_str() => return Ok(0),
+                Some(_) => (),
+                None => top = Some(first.as_os_str().to_owned()),
             }
         }
-
-        Ok(())
+        Ok(usize::from(top.is_some()))
     }
 
-    /// Decompress a tarball.
-    fn decompress_tarball(path: impl AsRef<Path>, destination: impl AsRef<Path>) -> io::Result<()> {
+    /// Decompress the tarball at `path` into `destination`,
+    /// stripping `strip_components` leading components from the paths of its entries.
+    /// Entries with fewer components are skipped.
+    fn decompress_tarball(
+        path: impl AsRef<Path>,
+        destination: impl AsRef<Path>,
+        strip_components: usize,
+    ) -> io::Result<()> {
         info!("Decompressing tarball {}.", path.as_ref().to_string_lossy(),);
 
-        let tar_gz = BufReader::new(File::open(path)?);
-        let tar = GzDecoder::new(tar_gz);
-        let mut archive = Archive::new(tar);
-        archive.unpack(destination)?;
+        let mut archive = Self::open_tarball(path)?;
+        if strip_components == 0 {
+            return archive.unpack(destination);
+        }
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            let components = Self::entry_components(&path);
+            if components.len() <= strip_components {
+                continue;
+            }
+            // Like `Archive::unpack`, refuse paths escaping the destination.
+            if components
+                .iter()
+                .any(|component| !matches!(component, Component::Normal(_)))
+            {
+                warn!("Skipping entry {} of tarball.", path.to_string_lossy());
+                continue;
+            }
+
+            let target = destination
+                .as_ref()
+                .join(components[strip_components..].iter().collect::<PathBuf>());
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            entry.unpack(target)?;
+        }
 
         Ok(())
     }
@@ -243,261 +1042,3103 @@ impl Payload {
     /// Decompress all `package` tarballs.
     fn decompress_pkgs(&self) -> DynResult<()> {
         let conf = CONFIG.get().unwrap();
-        println!("Decompressing packages...");
+        self.status("Decompressing packages...");
         // TODO: Progressbar
 
         for pkg in &self.packages {
-            let tar_name = format!("{}_{}.tar.gz", pkg.info.name, pkg.info.version);
-            let tar = conf.sources_path().join(tar_name);
-
-            let untar = conf
-                .builds_path()
-                .join(format!("{}_{}", pkg.info.name, pkg.info.version));
+            if pkg.source.vcs.is_some() {
+                // Already cloned into the build directory.
+                continue;
+            }
+            let tar = conf.tarball_path(&pkg.info);
+            let untar = conf.build_path(&pkg.info);
+            if self.force && untar.exists() {
+                fs::remove_dir_all(&untar)?;
+            }
             fs::create_dir_all(&untar)?;
-            Self::decompress_tarball(&tar, &untar)?;
+            let strip_components = match pkg.source.strip_components {
+                Some(strip_components) => strip_components,
+                None => Self::detect_strip_components(&tar)?,
+            };
+            Self::decompress_tarball(&tar, &untar, strip_components)?;
+            Self::check_extracted(&untar, &pkg.info.name)?;
         }
 
         Ok(())
     }
 
-    /// Run a command `cmd` with environment variables `env`.
-    fn run_command<I, K, V>(cmd: &str, env: I) -> DynResult<ExitStatus>
+    /// Check that the source of package `package` extracted to `untar` isn't empty,
+    /// so that a corrupt download doesn't lead to confusing build failures.
+    fn check_extracted(untar: &Path, package: &str) -> DynResult<()> {
+        if fs::read_dir(untar)?.next().is_none() {
+            return Err(PayloadError::EmptySource(package.to_owned()).into());
+        }
+        Ok(())
+    }
+
+    /// Run a command `cmd` of package `package` with environment variables `env`.
+    /// Its stderr is also written to `sink`.
+    fn run_command<I, K, V>(
+        cmd: &str,
+        env: I,
+        package: &str,
+        sink: &OutputSink,
+    ) -> DynResult<CommandOutput>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        Self::run_command_in(cmd, None, env, package, sink)
+    }
+
+    /// Like [`Self::run_command`], but in the working directory `dir` if given.
+    fn run_command_in<I, K, V>(
+        cmd: &str,
+        dir: Option<&Path>,
+        env: I,
+        package: &str,
+        sink: &OutputSink,
+    ) -> DynResult<CommandOutput>
     where
         I: IntoIterator<Item = (K, V)>,
         K: AsRef<OsStr>,
         V: AsRef<OsStr>,
     {
-        let output = Command::new("sh").arg("-c").arg(cmd).envs(env).output()?;
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(cmd).envs(env);
+        if let Some(dir) = dir {
+            command.current_dir(dir);
+        }
+        let output = command.output()?;
 
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        if !stderr.is_empty() {
-            warn!("Command stderr: {stderr}");
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        for line in stderr.lines() {
+            sink.line(package, line);
         }
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        if !stdout.is_empty() {
-            trace!("Command stdout: {stdout}");
+        for line in stdout.lines() {
+            trace!("[{package}] {line}");
         }
 
-        Ok(output.status)
+        Ok(CommandOutput {
+            status: output.status,
+            stdout,
+            stderr,
+        })
     }
 
-    /// Build all `packages` using their build instructions.
-    fn build_pkgs(&self) -> DynResult<()> {
-        let conf = CONFIG.get().unwrap();
-        println!("Building packages...");
-        // TODO: Progressbar
-
-        for pkg in &self.packages {
-            let untar = conf
-                .builds_path()
-                .join(format!("{}_{}", pkg.info.name, pkg.info.version));
-            let env = [("source", untar.as_path())];
+    /// Build the environment variables for the commands of package `name` with source `source`.
+    /// Package variables override the config variables `conf_env`, which override the inherited environment.
+    /// The `reserved` variables (like `source` and `binary`) can't be overridden.
+    fn command_env(
+        name: &str,
+        source: &Source,
+        conf_env: &BTreeMap<String, String>,
+        reserved: &[(&str, &OsStr)],
+    ) -> BTreeMap<String, OsString> {
+        let mut env: BTreeMap<String, OsString> = conf_env
+            .iter()
+            .chain(source.env.iter().flatten())
+            .map(|(key, value)| (key.clone(), value.into()))
+            .collect();
 
-            if let Some(cmd) = &pkg.source.build {
-                println!("Building {}...", pkg.info.name);
-                let status = Self::run_command(cmd, env)?;
-                assert!(status.success(), "Build failed!");
+        for (key, value) in reserved {
+            if env.insert(key.to_string(), value.into()).is_some() {
+                warn!("Ignoring reserved environment variable {key} in package {name}.");
             }
         }
 
-        Ok(())
+        env
     }
 
-    /// Install all `packages` using their install instructions.
-    fn install_pkgs(&self) -> DynResult<()> {
-        let conf = CONFIG.get().unwrap();
-        println!("Installing packages...");
-        // TODO: Progressbar
+    /// Quote `value` for a POSIX shell.
+    pub fn shell_quote(value: &OsStr) -> String {
+        format!("'{}'", value.to_string_lossy().replace('\'', r"'\''"))
+    }
 
-        for pkg in &self.packages {
-            let untar = conf
-                .builds_path()
-                .join(format!("{}_{}", pkg.info.name, pkg.info.version));
-            fs::create_dir_all(conf.binaries_path())?;
-            let env = [
-                ("source", untar.as_path()),
-                ("binary", conf.binaries_path()),
-            ];
+    /// Get the commands run to build and install the packages of `plan` as a shell script,
+    /// together with the environment variables they are run with.
+    pub fn script(&self, plan: &InstallPlan, conf: &Config) -> String {
+        let mut script = String::new();
+
+        for pkg in self.ordered(plan) {
+            let untar = conf.build_path(&pkg.info);
+            let jobs = conf.jobs().to_string();
+            let env = Self::command_env(
+                &pkg.info.name,
+                &pkg.source,
+                &conf.env,
+                &[
+                    ("source", untar.as_os_str()),
+                    ("binary", conf.binaries_path().as_os_str()),
+                    ("jobs", OsStr::new(&jobs)),
+                ],
+            );
 
-            let status = Self::run_command(&pkg.source.install, env)?;
-            assert!(status.success(), "Build failed!");
+            script.push_str(&format!("# {} {}\n", pkg.info.name, pkg.info.version));
+            for (key, value) in &env {
+                script.push_str(&format!("export {key}={}\n", Self::shell_quote(value)));
+            }
+            for cmd in Self::prepare_commands(pkg, conf) {
+                script.push_str(&format!("(cd \"$source\" && {cmd})\n"));
+            }
+            if let Some(build) = pkg.source.build.as_ref().filter(|_| !self.no_build) {
+                script.push_str(&format!(
+                    "(cd {} && {build})\n",
+                    Self::shell_quote(untar.as_os_str())
+                ));
+            }
+            script.push_str(&format!("{}\n", pkg.source.install));
+            if let Some(post_install) = &pkg.source.post_install {
+                script.push_str(&format!("{post_install}\n"));
+            }
+            script.push('\n');
         }
 
-        Ok(())
+        script
     }
 
-    /// Write the payload to the database.
-    fn write_db(&self) -> DynResult<()> {
-        let db = DB.get().unwrap();
-        for payload_pkg in &self.packages {
-            let name = payload_pkg.info.name.as_str();
-            let installed_new = match payload_pkg.manually_selected {
-                true => Installed::Manually(payload_pkg.file.info.version.clone()),
-                false => Installed::Automatically(payload_pkg.file.info.version.clone()),
-            };
-            let added = payload_pkg.manually_added;
-
-            db.modify(INSTALLED_PKGS, name, |pkg| match pkg {
-                Some(mut pkg) => {
-                    let installed_old = pkg.local.installed;
-                    pkg.local = Local {
-                        installed: installed_old.update(installed_new),
-                        added: payload_pkg.manually_added || added,
-                    };
-                    Some(pkg)
-                }
-                None => Some(Package::from_file(
-                    payload_pkg.file.clone(),
-                    Local {
-                        installed: installed_new,
-                        added: payload_pkg.manually_added,
-                    },
-                )),
-            })?;
+    /// Run the post-install command of `pkg`, if it has one.
+    fn post_install_pkg(pkg: &PackageFile, conf: &Config) -> DynResult<()> {
+        let Some(cmd) = &pkg.source.post_install else {
+            return Ok(());
+        };
+
+        let untar = conf.build_path(&pkg.info);
+        let env = Self::command_env(
+            &pkg.info.name,
+            &pkg.source,
+            &conf.env,
+            &[
+                ("source", untar.as_os_str()),
+                ("binary", conf.binaries_path().as_os_str()),
+            ],
+        );
 
-            if let Some(pkg) = db.get(INSTALLED_PKGS, name)? {
-                db.set(ALL_PKGS, name, pkg)?;
+        let output = Self::run_command(cmd, env, &pkg.info.name, &OutputSink::Log)?;
+        if !output.status.success() {
+            return Err(PayloadError::PostInstallFailed {
+                package: pkg.info.name.clone(),
+                status: output.status,
+                stderr: output.stderr,
             }
+            .into());
         }
 
         Ok(())
     }
 
-    pub fn new() -> Self {
-        Self {
-            packages: HashSet::new(),
+    /// Run the post-install commands of the installed packages, in the order of `plan`.
+    /// A failing command only leads to a warning, unless `strict_post_install` is set in `conf`.
+    fn post_install_pkgs(&self, plan: &InstallPlan, conf: &Config) -> DynResult<()> {
+        for pkg in self.ordered(plan) {
+            if let Err(err) = Self::post_install_pkg(pkg, conf) {
+                if conf.strict_post_install {
+                    return Err(err);
+                }
+                warn!("{err}");
+            }
         }
+
+        Ok(())
     }
 
-    /// Add a package and its dependencies to the payload.
-    /// This marks the package as manually installed.
-    pub fn add_pkg(&mut self, pkg: &str) -> DynResult<()> {
-        let db = DB.get().unwrap();
-        let pkg = db.get(ALL_PKGS, pkg)?.unwrap_or_else(|| {
-            exit_with_message(format!("Package {pkg} not found!"), exitcode::DATAERR)
-        });
+    /// Run the uninstall script of `pkg`, if it has one.
+    pub fn uninstall_pkg(pkg: &Package, conf: &Config) -> DynResult<()> {
+        let Some(cmd) = &pkg.source.uninstall else {
+            return Ok(());
+        };
 
-        if let Some(deps) = &pkg.info.dependencies {
-            let pkgs = db.get_iter(
-                ALL_PKGS,
-                deps.iter().map(|x| x.as_str()).collect::<Vec<&str>>(),
-            )?;
+        let untar = conf.build_path(&pkg.info);
+        let env = Self::command_env(
+            &pkg.info.name,
+            &pkg.source,
+            &conf.env,
+            &[
+                ("source", untar.as_os_str()),
+                ("binary", conf.binaries_path().as_os_str()),
+            ],
+        );
 
-            for (key, pkg) in deps.iter().zip(pkgs.into_iter()) {
-                let pkg = pkg.unwrap_or_else(|| {
-                    exit_with_message(format!("Dependency {key} not found!"), exitcode::DATAERR)
+        info!("Uninstalling {}.", pkg.info.name);
+        let output = Self::run_command(cmd, env, &pkg.info.name, &OutputSink::Log)?;
+        if !output.status.success() {
+            return Err(PayloadError::UninstallFailed(pkg.info.name.clone()).into());
+        }
+
+        Ok(())
+    }
+
+    /// Get the commands preparing the source of `pkg`: applying its patches and running its prepare script.
+    fn prepare_commands(pkg: &PackageFile, conf: &Config) -> Vec<String> {
+        let mut commands: Vec<String> = pkg
+            .source
+            .patches
+            .iter()
+            .flatten()
+            .enumerate()
+            .map(|(index, patch)| {
+                let path = conf.patch_path(&pkg.info, index, patch);
+                // The commands run in the source directory, so relative paths have to be resolved first.
+                let path = fs::canonicalize(&path).unwrap_or(path);
+                format!("patch -p1 -N -i {}", Self::shell_quote(path.as_os_str()))
+            })
+            .collect();
+        commands.extend(pkg.source.prepare.clone());
+        commands
+    }
+
+    /// Apply the patches of `pkg` and run its prepare script in its source directory.
+    fn prepare_pkg(pkg: &PackageFile, conf: &Config, sink: &OutputSink) -> DynResult<()> {
+        let untar = conf.build_path(&pkg.info);
+        let env = Self::command_env(
+            &pkg.info.name,
+            &pkg.source,
+            &conf.env,
+            &[("source", untar.as_os_str())],
+        );
+
+        for cmd in Self::prepare_commands(pkg, conf) {
+            let output = Self::run_command_in(&cmd, Some(&untar), &env, &pkg.info.name, sink)?;
+            if !output.status.success() {
+                return Err(PayloadError::PrepareFailed {
+                    package: pkg.info.name.clone(),
+                    status: output.status,
+                    stderr: output.stderr,
+                }
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Prepare the sources of all `packages` before building them.
+    fn prepare_pkgs(&self, plan: &InstallPlan) -> DynResult<()> {
+        let conf = CONFIG.get().unwrap();
+        for pkg in self.ordered(plan) {
+            Self::prepare_pkg(pkg, conf, &OutputSink::Log)?;
+        }
+        Ok(())
+    }
+
+    /// Build `pkg` using its build instructions, if it has any.
+    fn build_pkg(pkg: &PackageFile, conf: &Config, sink: &OutputSink) -> DynResult<()> {
+        let Some(cmd) = &pkg.source.build else {
+            return Ok(());
+        };
+
+        let untar = conf.build_path(&pkg.info);
+        let jobs = conf.jobs().to_string();
+        let env = Self::command_env(
+            &pkg.info.name,
+            &pkg.source,
+            &conf.env,
+            &[("source", untar.as_os_str()), ("jobs", OsStr::new(&jobs))],
+        );
+
+        let output = Self::run_command_in(cmd, Some(&untar), env, &pkg.info.name, sink)?;
+        if !output.status.success() {
+            return Err(PayloadError::BuildFailed {
+                package: pkg.info.name.clone(),
+                status: output.status,
+                stderr: output.stderr,
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Get the `packages` in the order of `plan`.
+    fn ordered<'a>(&'a self, plan: &'a InstallPlan) -> impl Iterator<Item = &'a PayloadPackage> {
+        plan.order
+            .iter()
+            .filter_map(|name| self.packages.iter().find(|pkg| &pkg.info.name == name))
+    }
+
+    /// Get the `packages` that have to be built, in the order of `plan`.
+    /// Prebuilt packages without a build command are skipped, as are all packages if `no_build` is set.
+    fn to_build<'a>(&'a self, plan: &'a InstallPlan) -> Vec<&'a PayloadPackage> {
+        if self.no_build {
+            return Vec::new();
+        }
+        self.ordered(plan)
+            .filter(|pkg| pkg.source.build.is_some())
+            .collect()
+    }
+
+    /// Build all `packages` using their build instructions.
+    #[cfg(not(feature = "parallel"))]
+    fn build_pkgs(&self, plan: &InstallPlan) -> DynResult<()> {
+        let pkgs = self.to_build(plan);
+        if pkgs.is_empty() {
+            return Ok(());
+        }
+
+        let conf = CONFIG.get().unwrap();
+        self.status("Building packages...");
+        // TODO: Progressbar
+        let sink = OutputSink::Log;
+
+        for pkg in pkgs {
+            self.status(&format!("Building {}...", pkg.info.name));
+            Self::build_pkg(pkg, conf, &sink)?;
+        }
+
+        Ok(())
+    }
+
+    /// Build all `packages` using their build instructions.
+    #[cfg(feature = "parallel")]
+    fn build_pkgs(&self, plan: &InstallPlan) -> DynResult<()> {
+        let pkgs = self.to_build(plan);
+        if pkgs.is_empty() {
+            return Ok(());
+        }
+
+        let conf = CONFIG.get().unwrap();
+        self.status("Building packages...");
+        Self::build_pkgs_parallel(pkgs, conf, conf.jobs(), &self.multi_progress())
+    }
+
+    /// Build `pkgs` with at most `jobs` builds at the same time.
+    /// A package is only built once all of its dependencies in `pkgs` are built.
+    #[cfg(feature = "parallel")]
+    fn build_pkgs_parallel(
+        pkgs: Vec<&PayloadPackage>,
+        conf: &Config,
+        jobs: usize,
+        mpb: &MultiProgress,
+    ) -> DynResult<()> {
+        struct State<'a> {
+            pending: Vec<&'a PayloadPackage>,
+            built: HashSet<&'a str>,
+            running: usize,
+            error: Option<String>,
+        }
+
+        let names: HashSet<&str> = pkgs.iter().map(|pkg| pkg.info.name.as_str()).collect();
+        let all = pkgs.clone();
+        let is_ready = |pkg: &PayloadPackage, built: &HashSet<&str>| {
+            pkg.info
+                .dependencies
+                .iter()
+                .flatten()
+                .filter_map(|dep| find_dependency(all.iter().copied(), dep))
+                .all(|dep| built.contains(dep.info.name.as_str()))
+        };
+
+        let state = Mutex::new(State {
+            pending: pkgs,
+            built: HashSet::new(),
+            running: 0,
+            error: None,
+        });
+        let changed = Condvar::new();
+        let sink = OutputSink::Progress(mpb.clone());
+        let longest_message = names.iter().map(|name| name.len()).max().unwrap_or(0);
+
+        let worker = || {
+            let mut guard = state.lock().unwrap();
+            loop {
+                if guard.error.is_some() || guard.pending.is_empty() {
+                    return;
+                }
+
+                let ready = guard
+                    .pending
+                    .iter()
+                    .position(|pkg| is_ready(pkg, &guard.built));
+                let Some(index) = ready else {
+                    if guard.running == 0 {
+                        guard.error = Some("unresolvable build order".to_owned());
+                        changed.notify_all();
+                        return;
+                    }
+                    guard = changed.wait(guard).unwrap();
+                    continue;
+                };
+
+                let pkg = guard.pending.swap_remove(index);
+                guard.running += 1;
+                drop(guard);
+
+                let pb = mpb.add(ProgressBar::new_spinner());
+                pb.set_style(
+                    ProgressStyle::default_spinner()
+                        .template(&format!(
+                            "{{spinner:.green}} {{msg:{longest_message}!}} {{elapsed}}"
+                        ))
+                        .unwrap(),
+                );
+                pb.set_message(pkg.info.name.clone());
+                pb.enable_steady_tick(Duration::from_millis(100));
+                let result = Self::build_pkg(pkg, conf, &sink).map_err(|err| err.to_string());
+                pb.finish();
+
+                guard = state.lock().unwrap();
+                guard.running -= 1;
+                match result {
+                    Ok(()) => {
+                        guard.built.insert(&pkg.info.name);
+                    }
+                    Err(err) => guard.error = Some(err.to_string()),
+                }
+                changed.notify_all();
+            }
+        };
+
+        thread::scope(|scope| {
+            for _ in 0..jobs.max(1).min(names.len()) {
+                scope.spawn(worker);
+            }
+        });
+
+        match state.into_inner().unwrap().error {
+            Some(err) => Err(err.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Recursively collect all files below `path` together with their modification time.
+    fn snapshot_dir(path: impl AsRef<Path>) -> io::Result<HashMap<PathBuf, SystemTime>> {
+        let mut files = HashMap::new();
+        if !path.as_ref().exists() {
+            return Ok(files);
+        }
+
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                files.extend(Self::snapshot_dir(entry.path())?);
+            } else {
+                files.insert(entry.path(), metadata.modified()?);
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// Get the files that were created or modified between two snapshots.
+    fn changed_files(
+        before: &HashMap<PathBuf, SystemTime>,
+        after: &HashMap<PathBuf, SystemTime>,
+    ) -> Vec<PathBuf> {
+        let mut changed: Vec<PathBuf> = after
+            .iter()
+            .filter(|(path, modified)| before.get(*path) != Some(*modified))
+            .map(|(path, _)| path.clone())
+            .collect();
+        changed.sort();
+        changed
+    }
+
+    /// Check whether the file at `path` starts with an ELF or Mach-O header.
+    fn is_native_executable(path: impl AsRef<Path>) -> io::Result<bool> {
+        const MAGICS: [[u8; 4]; 6] = [
+            [0x7f, b'E', b'L', b'F'],
+            [0xfe, 0xed, 0xfa, 0xce],
+            [0xfe, 0xed, 0xfa, 0xcf],
+            [0xce, 0xfa, 0xed, 0xfe],
+            [0xcf, 0xfa, 0xed, 0xfe],
+            [0xca, 0xfe, 0xba, 0xbe],
+        ];
+
+        let mut magic = [0; 4];
+        match File::open(path)?.read_exact(&mut magic) {
+            Ok(()) => Ok(MAGICS.contains(&magic)),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Set the permissions of the file at `path` to `0755`.
+    #[cfg(unix)]
+    fn make_executable(path: impl AsRef<Path>) -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut permissions = fs::metadata(&path)?.permissions();
+        permissions.set_mode(0o755);
+        fs::set_permissions(path, permissions)
+    }
+
+    /// Executable permissions only exist on Unix.
+    #[cfg(not(unix))]
+    fn make_executable(_path: impl AsRef<Path>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Mark installed files as executable.
+    /// If `executable` is given, exactly these files (relative to `binaries`) are marked.
+    /// Otherwise, all `installed` files with a native executable header are marked.
+    fn fix_permissions(
+        installed: &[PathBuf],
+        binaries: impl AsRef<Path>,
+        executable: Option<&[String]>,
+    ) -> io::Result<()> {
+        match executable {
+            Some(executable) => {
+                for file in executable {
+                    let path = binaries.as_ref().join(file);
+                    if path.is_file() {
+                        trace!("Marking {} as executable.", path.to_string_lossy());
+                        Self::make_executable(&path)?;
+                    } else {
+                        warn!("Executable {} was not installed!", path.to_string_lossy());
+                    }
+                }
+            }
+            None => {
+                for path in installed {
+                    if Self::is_native_executable(path)? {
+                        trace!("Marking {} as executable.", path.to_string_lossy());
+                        Self::make_executable(path)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get a message if the install script of `pkg` neither installed any files nor declared any.
+    fn empty_install_message(pkg: &PackageFile, installed: &[PathBuf]) -> Option<String> {
+        if installed.is_empty() && pkg.source.executable.is_none() {
+            Some(format!(
+                "Installing package {} didn't produce any files!",
+                pkg.info.name
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Find the `files` (relative to `binaries`) that packages other than `name` have installed already,
+    /// or that exist without being owned by any package.
+    /// `installing` are the files installed during this run, which aren't in the database yet.
+    /// Returns the other package (`None` if the file isn't owned) and the file for each conflict.
+    fn file_conflicts(
+        db: &Database,
+        name: &str,
+        binaries: &Path,
+        files: &[PathBuf],
+        installing: &HashMap<String, Vec<PathBuf>>,
+    ) -> Result<Vec<(Option<String>, PathBuf)>, redb::Error> {
+        let read_txn = db.begin_read()?;
+        let read_table = read_txn.open_table(INSTALLED_PKGS)?;
+
+        let mut owners: HashMap<PathBuf, String> = HashMap::new();
+        for pkg in read_table.iter()? {
+            let pkg = Package::try_from(pkg?.1.value())?;
+            for file in pkg.local.files {
+                owners.insert(file, pkg.info.name.clone());
+            }
+        }
+        for (other, other_files) in installing {
+            for file in other_files {
+                owners.insert(file.clone(), other.clone());
+            }
+        }
+
+        Ok(files
+            .iter()
+            .filter_map(|file| {
+                let path = binaries.join(file);
+                match owners.get(&path) {
+                    Some(owner) if owner == name => None,
+                    Some(owner) => Some((Some(owner.clone()), file.clone())),
+                    // Not following symlinks, so that dangling ones count as well.
+                    None if fs::symlink_metadata(&path).is_ok() => Some((None, file.clone())),
+                    None => None,
+                }
+            })
+            .collect())
+    }
+
+    /// Create a symlink at `link` pointing to `target`.
+    #[cfg(unix)]
+    fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+        std::os::unix::fs::symlink(target, link)
+    }
+
+    /// Symlinks are only recreated on Unix, elsewhere the target is copied.
+    #[cfg(not(unix))]
+    fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+        fs::copy(target, link).map(drop)
+    }
+
+    /// Move the `files` (relative paths) from the `staging` directory to `binaries`.
+    /// Symlinks are recreated, and ones pointing into `staging` are pointed to the moved files instead.
+    fn move_staged(staging: &Path, binaries: &Path, files: &[PathBuf]) -> io::Result<()> {
+        for file in files {
+            let (from, to) = (staging.join(file), binaries.join(file));
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            if fs::symlink_metadata(&from)?.file_type().is_symlink() {
+                let target = fs::read_link(&from)?;
+                let target = match target.strip_prefix(staging) {
+                    Ok(rest) => binaries.join(rest),
+                    Err(_) => target,
+                };
+                if fs::symlink_metadata(&to).is_ok() {
+                    fs::remove_file(&to)?;
+                }
+                Self::symlink(&target, &to)?;
+                fs::remove_file(&from)?;
+                continue;
+            }
+            // Renaming fails across file systems, e.g. if the cache is on another one than the binaries.
+            if fs::rename(&from, &to).is_err() {
+                fs::copy(&from, &to)?;
+                fs::remove_file(&from)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Move the files the install command of `pkg` created in `staging` to `binaries` and remove `staging`.
+    /// Conflicts with the files of other packages (see [`Payload::file_conflicts`]) are errors unless
+    /// `overwrite` is set. Config files of `pkg` that exist already (e.g. because it was removed without purging)
+    /// are kept instead of being overwritten. Returns the installed files.
+    fn install_staged(
+        &self,
+        db: &Database,
+        pkg: &PackageFile,
+        staging: &Path,
+        binaries: &Path,
+        installing: &HashMap<String, Vec<PathBuf>>,
+    ) -> DynResult<Vec<PathBuf>> {
+        let (kept, staged): (Vec<PathBuf>, Vec<PathBuf>) =
+            Self::changed_files(&HashMap::new(), &Self::snapshot_dir(staging)?)
+                .into_iter()
+                .filter_map(|file| file.strip_prefix(staging).ok().map(Path::to_path_buf))
+                .partition(|file| {
+                    pkg.source
+                        .config_files
+                        .iter()
+                        .flatten()
+                        .any(|config| Path::new(config) == file)
+                        && binaries.join(file).exists()
                 });
-                self.packages.insert(PayloadPackage {
-                    file: pkg.into(),
-                    manually_selected: false,
-                    manually_added: false,
+        for file in &kept {
+            info!(
+                "Keeping the existing config file {}.",
+                binaries.join(file).to_string_lossy()
+            );
+        }
+
+        let conflicts = Self::file_conflicts(db, &pkg.info.name, binaries, &staged, installing)?;
+        for (other, file) in conflicts {
+            let package = pkg.info.name.clone();
+            match other {
+                Some(other) if !self.overwrite => {
+                    return Err(PayloadError::FileConflict {
+                        package,
+                        other,
+                        file,
+                    }
+                    .into())
+                }
+                None if !self.overwrite => {
+                    return Err(PayloadError::UnownedFile { package, file }.into())
+                }
+                Some(other) => {
+                    warn!("Overwriting {} of package {other}.", file.to_string_lossy())
+                }
+                None => warn!(
+                    "Overwriting {}, which isn't owned by any package.",
+                    file.to_string_lossy()
+                ),
+            }
+        }
+
+        Self::move_staged(staging, binaries, &staged)?;
+        fs::remove_dir_all(staging)?;
+
+        let mut installed: Vec<PathBuf> = staged
+            .iter()
+            .chain(&kept)
+            .map(|file| binaries.join(file))
+            .collect();
+        installed.sort();
+        Ok(installed)
+    }
+
+    /// Install all `packages` using their install instructions.
+    /// The installed files are recorded, so they can be removed later.
+    fn install_pkgs(&mut self, plan: &InstallPlan) -> DynResult<()> {
+        let conf = CONFIG.get().unwrap();
+        self.status("Installing packages...");
+        // TODO: Progressbar
+        let sink = OutputSink::Log;
+        let mut files = HashMap::new();
+
+        fs::create_dir_all(conf.binaries_path())?;
+        // Record absolute paths, so that the files can be found regardless of the working directory.
+        let binaries = fs::canonicalize(conf.binaries_path())?;
+
+        for pkg in self.ordered(plan) {
+            let untar = conf.build_path(&pkg.info);
+            // Install into a staging directory first to know the files before anything is overwritten.
+            let staging = conf.staging_path(&pkg.info);
+            if staging.exists() {
+                fs::remove_dir_all(&staging)?;
+            }
+            fs::create_dir_all(&staging)?;
+            // Canonical like `binaries`, so that symlinks into it can be recognized when moving the files.
+            let staging = fs::canonicalize(&staging)?;
+            let env = Self::command_env(
+                &pkg.info.name,
+                &pkg.source,
+                &conf.env,
+                &[
+                    ("source", untar.as_os_str()),
+                    ("binary", staging.as_os_str()),
+                ],
+            );
+
+            let output = Self::run_command(&pkg.source.install, env, &pkg.info.name, &sink)?;
+            if !output.status.success() {
+                return Err(PayloadError::InstallFailed {
+                    package: pkg.info.name.clone(),
+                    status: output.status,
+                    stderr: output.stderr,
+                }
+                .into());
+            }
+            let installed =
+                self.install_staged(DB.get().unwrap(), pkg, &staging, &binaries, &files)?;
+
+            if let Some(message) = Self::empty_install_message(pkg, &installed) {
+                if self.strict {
+                    return Err(message.into());
+                }
+                warn!("{message}");
+            }
+
+            Self::fix_permissions(&installed, &binaries, pkg.source.executable.as_deref())?;
+            files.insert(pkg.info.name.clone(), installed);
+        }
+
+        self.files.extend(files);
+        Ok(())
+    }
+
+    /// Write the payload to the database, including the up-to-date pkgfiles added by the user.
+    /// A package is in `INSTALLED_PKGS` if and only if it is installed.
+    fn write_db(&self, db: &Database) -> DynResult<()> {
+        let write_txn = db.begin_write()?;
+        {
+            let mut installed_table = write_txn.open_table(INSTALLED_PKGS)?;
+            let mut all_table = write_txn.open_table(ALL_PKGS)?;
+            let mut versions_table = write_txn.open_table(PKG_VERSIONS)?;
+
+            for payload_pkg in &self.packages {
+                let name = payload_pkg.info.name.as_str();
+                let installed_new = match payload_pkg.manually_selected {
+                    true => Installed::Manually(payload_pkg.file.info.version.clone()),
+                    false => Installed::Automatically(payload_pkg.file.info.version.clone()),
+                };
+                // Once added from a local pkgfile, a package stays added, even if it is reinstalled as a dependency.
+                let added = payload_pkg.manually_added;
+                // Only set when the package becomes installed, updates keep the original time.
+                let installed_at = unix_now();
+
+                let old = installed_table.get(name)?;
+                let old = old.map(|pkg| Package::try_from(pkg.value())).transpose()?;
+                let mut local = match old {
+                    Some(pkg) => Local {
+                        installed: pkg.local.installed.update(installed_new),
+                        added: added || pkg.local.added,
+                        files: pkg.local.files,
+                        last_installed: Installed::False,
+                        installed_at: pkg.local.installed_at.or(Some(installed_at)),
+                        pinned: pkg.local.pinned,
+                    },
+                    None => {
+                        let (last_installed, was_added, pinned) = all_table
+                            .get(name)?
+                            .map(|pkg| {
+                                let pkg = pkg.value();
+                                (pkg.last_installed, pkg.added, pkg.pinned)
+                            })
+                            .unwrap_or((Installed::False, false, false));
+                        Local {
+                            installed: last_installed.restore(installed_new),
+                            added: added || was_added,
+                            files: Vec::new(),
+                            last_installed: Installed::False,
+                            installed_at: Some(installed_at),
+                            pinned,
+                        }
+                    }
+                };
+                if let Some(files) = self.files.get(name) {
+                    local.files.extend(files.iter().cloned());
+                    local.files.sort();
+                    local.files.dedup();
+                }
+                let pkg = Package::from_file(payload_pkg.file.clone(), local);
+
+                if pkg.local.installed == Installed::False {
+                    installed_table.remove(name)?;
+                } else {
+                    installed_table.insert(name, Into::<DbPackage>::into(pkg.clone()))?;
+                }
+                versions_table.insert(
+                    version_key(name, &pkg.info.version).as_str(),
+                    Into::<DbPackage>::into(pkg.clone()),
+                )?;
+
+                // `ALL_PKGS` keeps describing the newest version, e.g. when an older one was installed.
+                let newest = all_table.get(name)?;
+                let newest = newest
+                    .map(|pkg| Package::try_from(pkg.value()))
+                    .transpose()?;
+                let pkg = match newest {
+                    Some(newest) if newest.info.version > pkg.info.version => Package {
+                        local: pkg.local,
+                        ..newest
+                    },
+                    _ => pkg,
+                };
+                all_table.insert(name, Into::<DbPackage>::into(pkg))?;
+            }
+        }
+        for file in &self.up_to_date_added {
+            file.add_in(&write_txn)?;
+        }
+        // All packages are written at once, so the database is never left half updated.
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    /// Recursively add the dependencies of `pkg` to the payload.
+    /// If `pkg` is built, its build dependencies are added as well, marked as `only_build`.
+    /// `only_build` is whether `pkg` itself is only needed for building, which its dependencies inherit.
+    /// `path` is the chain of packages that led to `pkg` (ending with `pkg`) and is used to detect cycles.
+    fn add_deps(
+        &mut self,
+        db: &Database,
+        pkg: &PackageFile,
+        only_build: bool,
+        path: &mut Vec<String>,
+    ) -> Result<(), PayloadError> {
+        let builds = pkg.source.build.is_some() && !self.no_build;
+        let build_deps = pkg.info.build_dependencies.iter().flatten();
+        let deps = pkg
+            .info
+            .dependencies
+            .iter()
+            .flatten()
+            .map(|dep| (dep, only_build))
+            .chain(build_deps.filter(|_| builds).map(|dep| (dep, true)))
+            .map(|(dep, only_build)| {
+                Dependency::from_str(dep)
+                    .map(|dep| (dep, only_build))
+                    .map_err(|source| PayloadError::InvalidDependency {
+                        package: pkg.info.name.clone(),
+                        dependency: dep.clone(),
+                        source,
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let pkgs = db.get_iter(ALL_PKGS, deps.iter().map(|(dep, _)| dep.name.as_str()))?;
+
+        for (Dependency { name: key, req }, only_build) in &deps {
+            if let Some(existing) = find_dependency(&self.packages, key) {
+                check_requirement(pkg, &existing.info.name, req, &existing.info.version)?;
+                if existing.only_build && !only_build {
+                    // Needed at runtime after all, and so are its dependencies.
+                    let existing = existing.clone();
+                    self.packages.remove(&existing);
+                    path.push(existing.info.name.clone());
+                    self.add_deps(db, &existing.file, false, path)?;
+                    path.pop();
+                    self.packages.insert(PayloadPackage {
+                        only_build: false,
+                        ..existing
+                    });
+                }
+                continue;
+            }
+
+            let dep: PackageFile = match pkgs.get(key.as_str()).cloned() {
+                Some(dep) => {
+                    check_requirement(pkg, key, req, &dep.info.version)?;
+                    dep.into()
+                }
+                None => {
+                    let providers = Self::providers(db, key, req)?;
+                    if providers.is_empty() {
+                        return Err(PayloadError::DependencyNotFound {
+                            package: pkg.info.name.clone(),
+                            dependency: key.clone(),
+                            path: path.clone(),
+                        });
+                    }
+                    self.choose_provider(key, providers).into()
+                }
+            };
+
+            if let Some(start) = path.iter().position(|name| name == &dep.info.name) {
+                let mut cycle = path[start..].to_vec();
+                cycle.push(dep.info.name.clone());
+                return Err(PayloadError::DependencyCycle(cycle));
+            }
+            path.push(dep.info.name.clone());
+            self.add_deps(db, &dep, *only_build, path)?;
+            path.pop();
+
+            self.packages.insert(PayloadPackage {
+                file: dep,
+                manually_selected: false,
+                manually_added: false,
+                only_build: *only_build,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Get the packages in `ALL_PKGS` providing the virtual package `name` in a version matching `req`, sorted by name.
+    fn providers(db: &Database, name: &str, req: &VersionReq) -> Result<Vec<Package>, redb::Error> {
+        let read_txn = db.begin_read()?;
+        let read_table = read_txn.open_table(ALL_PKGS)?;
+
+        let mut providers = Vec::new();
+        for pkg in read_table.iter()? {
+            let pkg = Package::try_from(pkg?.1.value())?;
+            if pkg.info.provides(name) && req.matches(&pkg.info.version) {
+                providers.push(pkg);
+            }
+        }
+        Ok(providers)
+    }
+
+    /// Choose one of the `providers` of the virtual package `name` according to `selection`.
+    fn choose_provider(&mut self, name: &str, mut providers: Vec<Package>) -> Package {
+        if providers.len() == 1 {
+            return providers.remove(0);
+        }
+
+        let index = match &mut self.selection {
+            Selection::First => {
+                let names: Vec<&str> = providers.iter().map(|pkg| pkg.info.name.as_str()).collect();
+                println!(
+                    "Packages {} provide {name}. Choosing {}, run with --interactive to choose another one.",
+                    names.join(", "),
+                    names[0]
+                );
+                0
+            }
+            Selection::Interactive => {
+                let options = providers
+                    .iter()
+                    .map(|pkg| format!("{} {}", pkg.info.name, pkg.info.version))
+                    .collect();
+                select(&format!("Which package should provide {name}?"), options)
+            }
+            #[cfg(test)]
+            Selection::Scripted(indices) => indices.remove(0),
+        };
+        providers.swap_remove(index)
+    }
+
+    pub fn new() -> Self {
+        Self {
+            packages: HashSet::new(),
+            strict: false,
+            trust: false,
+            assume_yes: false,
+            quiet: false,
+            overwrite: false,
+            no_build: false,
+            only_dependencies: false,
+            force: false,
+            skipped: Vec::new(),
+            up_to_date_added: Vec::new(),
+            selection: Selection::First,
+            files: HashMap::new(),
+        }
+    }
+
+    /// Add a package and its dependencies to the payload.
+    /// This marks the package as manually installed.
+    /// The package may be constrained to a version requirement using `name@requirement`.
+    pub fn add_pkg(&mut self, db: &Database, pkg: &str) -> DynResult<()> {
+        let (name, req) = parse_spec(pkg).unwrap_or_else(|err| {
+            exit_with_message(
+                format!("Invalid version requirement in {pkg}: {err}"),
+                exitcode::USAGE,
+            )
+        });
+        let pkg = match db.get(ALL_PKGS, name)? {
+            Some(pkg) => pkg,
+            None => {
+                let providers = Self::providers(db, name, &VersionReq::STAR)?;
+                if providers.is_empty() {
+                    exit_with_message(format!("Package {name} not found!"), exitcode::DATAERR);
+                }
+                self.choose_provider(name, providers)
+            }
+        };
+
+        let pkg = match req {
+            Some(req) => {
+                let pkg_name = pkg.info.name.clone();
+                let mut available = db.get_versions(PKG_VERSIONS, &pkg_name)?;
+                available.push(pkg.info.version.clone());
+                available.sort();
+                available.dedup();
+                match select_version(&available, &req) {
+                    Some(version) if *version == pkg.info.version => pkg,
+                    Some(version) => db
+                        .get(PKG_VERSIONS, version_key(&pkg_name, version).as_str())?
+                        .expect("listed versions are in PKG_VERSIONS"),
+                    None => {
+                        let available: Vec<String> =
+                            available.iter().map(|ver| ver.to_string()).collect();
+                        exit_with_message(
+                            format!(
+                                "No version of package {name} matches {req}! Available versions: {}",
+                                available.join(", ")
+                            ),
+                            exitcode::DATAERR,
+                        );
+                    }
+                }
+            }
+            None => pkg,
+        };
+
+        let file: PackageFile = pkg.into();
+        self.add_deps(db, &file, false, &mut vec![file.info.name.clone()])?;
+
+        self.insert_added(PayloadPackage {
+            file,
+            manually_selected: true,
+            manually_added: false,
+            only_build: false,
+        });
+
+        Ok(())
+    }
+
+    /// Add a package file and its dependencies to the payload.
+    /// This marks the package as manually installed and added.
+    pub fn add_pkgfile(&mut self, db: &Database, pkgfile: PackageFile) -> DynResult<()> {
+        self.add_deps(db, &pkgfile, false, &mut vec![pkgfile.info.name.clone()])?;
+
+        self.insert_added(PayloadPackage {
+            file: pkgfile,
+            manually_selected: true,
+            manually_added: true,
+            only_build: false,
+        });
+
+        Ok(())
+    }
+
+    /// Insert a package added by the user, unless only its dependencies are installed.
+    fn insert_added(&mut self, pkg: PayloadPackage) {
+        if self.only_dependencies {
+            self.skipped.push(pkg.file.info.name);
+        } else {
+            self.packages.insert(pkg);
+        }
+    }
+
+    /// Get the names of the packages in the payload, sorted.
+    #[cfg(test)]
+    pub fn package_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .packages
+            .iter()
+            .map(|pkg| pkg.info.name.as_str())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Resolve the payload into a plan without executing anything.
+    /// Duplicate packages are merged, conflicting versions of a package and dependency cycles are rejected.
+    pub fn resolve(&mut self) -> Result<InstallPlan, PayloadError> {
+        let mut merged: BTreeMap<String, PayloadPackage> = BTreeMap::new();
+        for pkg in &self.packages {
+            match merged.get_mut(&pkg.info.name) {
+                Some(existing) if existing.file != pkg.file => {
+                    return Err(PayloadError::Conflict(pkg.info.name.clone()));
+                }
+                Some(existing) => {
+                    existing.manually_selected |= pkg.manually_selected;
+                    existing.manually_added |= pkg.manually_added;
+                    existing.only_build &= pkg.only_build;
+                }
+                None => {
+                    merged.insert(pkg.info.name.clone(), pkg.clone());
+                }
+            }
+        }
+
+        /// Append `name` to `order` after its dependencies.
+        fn visit(
+            name: &str,
+            packages: &BTreeMap<String, PayloadPackage>,
+            order: &mut Vec<String>,
+            path: &mut Vec<String>,
+        ) -> Result<(), PayloadError> {
+            if order.iter().any(|ordered| ordered == name) {
+                return Ok(());
+            }
+            if let Some(start) = path.iter().position(|visited| visited == name) {
+                let mut cycle = path[start..].to_vec();
+                cycle.push(name.to_owned());
+                return Err(PayloadError::DependencyCycle(cycle));
+            }
+
+            path.push(name.to_owned());
+            let info = &packages[name].info;
+            let build_deps = info.build_dependencies.iter().flatten();
+            for dep in info.dependencies.iter().flatten().chain(build_deps) {
+                if let Some(dep) = find_dependency(packages.values(), dep) {
+                    visit(&dep.info.name, packages, order, path)?;
+                }
+            }
+            path.pop();
+
+            order.push(name.to_owned());
+            Ok(())
+        }
+
+        let mut order = Vec::new();
+        for name in merged.keys() {
+            visit(name, &merged, &mut order, &mut Vec::new())?;
+        }
+
+        // A package that is built starts a new stage if it build-depends on a package of the current one.
+        let mut stages = Vec::new();
+        let mut stage: Vec<String> = Vec::new();
+        for name in &order {
+            let pkg = &merged[name];
+            let needs_stage = pkg.source.build.is_some()
+                && !self.no_build
+                && pkg
+                    .info
+                    .build_dependencies
+                    .iter()
+                    .flatten()
+                    .filter_map(|dep| find_dependency(merged.values(), dep))
+                    .any(|dep| stage.contains(&dep.info.name));
+            if needs_stage {
+                stages.push(mem::take(&mut stage));
+            }
+            stage.push(name.clone());
+        }
+        if !stage.is_empty() {
+            stages.push(stage);
+        }
+
+        self.packages = merged.into_values().collect();
+        Ok(InstallPlan { order, stages })
+    }
+
+    /// Resolve and execute the payload.
+    pub async fn install(mut self) -> DynResult<Outcome> {
+        let plan = self.resolve()?;
+        self.execute(plan).await
+    }
+
+    /// Execute a `plan` resolved from this payload.
+    pub async fn execute(mut self, plan: InstallPlan) -> DynResult<Outcome> {
+        if let Some(outcome) = self.check_install()? {
+            return Ok(outcome);
+        }
+        if !self.check_trust(
+            DB.get().unwrap(),
+            &CONFIG.get().unwrap().trusted,
+            Self::prompt_trust,
+        )? {
+            self.status("Aborting...");
+            return Ok(Outcome::Aborted);
+        }
+        self.download_pkgs().await?;
+        self.decompress_pkgs()?;
+        for stage in plan.stage_plans() {
+            self.prepare_pkgs(&stage)?;
+            self.build_pkgs(&stage)?;
+            self.install_pkgs(&stage)?;
+        }
+        self.write_db(DB.get().unwrap())?;
+        lockfile::regenerate(DB.get().unwrap(), &CONFIG.get().unwrap().lockfile_path());
+        // The packages are recorded first, so that a failing hook doesn't leave untracked files behind.
+        self.post_install_pkgs(&plan, CONFIG.get().unwrap())?;
+        let only_build: Vec<&str> = self
+            .packages
+            .iter()
+            .filter(|pkg| pkg.only_build)
+            .map(|pkg| pkg.info.name.as_str())
+            .collect();
+        if !only_build.is_empty() {
+            self.status(&format!(
+                "Packages {} were only installed to build other packages, `mercurium autoremove` removes them.",
+                only_build.join(", ")
+            ));
+        }
+        self.status("Done!");
+
+        Ok(Outcome::Installed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::str::FromStr;
+
+    use redb::Database;
+    use semver::Version;
+
+    use super::*;
+    use crate::config::{Config, ConfigDirs};
+    use crate::db::Db;
+    use crate::pkg::{Installed, Local, Package, PackageInfo, Source};
+    use crate::tests::test_pkgfile;
+    use crate::{ALL_PKGS, DB, INSTALLED_PKGS};
+
+    #[tokio::test]
+    async fn test_download() {
+        // init_logging();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("topgrade.tar.gz");
+
+        Payload::download_source(&reqwest::Client::new(), "https://github.com/topgrade-rs/topgrade/releases/download/v12.0.2/topgrade-v12.0.2-x86_64-apple-darwin.tar.gz", &path, None).await.unwrap();
+
+        assert!(path.exists());
+    }
+
+    /// How the test server answers a request.
+    #[derive(Clone, Copy)]
+    enum Answer {
+        /// Send the whole file.
+        Full,
+        /// Answer range requests with the rest of the file.
+        Ranges,
+        /// Send the whole file without a `Content-Length`.
+        NoLength,
+        /// Announce the whole file, but close the connection after half of it.
+        Truncated,
+        /// Send the first half of the file and then stall.
+        Stall,
+    }
+
+    /// Serve `data` on localhost, answering one request after another with `answers`.
+    fn serve(data: Vec<u8>, answers: &[Answer]) -> String {
+        use std::io::{BufRead, BufReader};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/foo.tar.gz", listener.local_addr().unwrap());
+        let answers = answers.to_vec();
+
+        std::thread::spawn(move || {
+            for answer in answers {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut start = 0;
+                for line in BufReader::new(&stream).lines() {
+                    let line = line.unwrap().to_lowercase();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(range) = line.strip_prefix("range: bytes=") {
+                        start = range.trim_end_matches('-').parse().unwrap();
+                    }
+                }
+
+                let (header, body) = match answer {
+                    Answer::Ranges if start > 0 => (
+                        format!(
+                            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {start}-{}/{}\r\nContent-Length: {}\r\n",
+                            data.len() - 1,
+                            data.len(),
+                            data.len() - start
+                        ),
+                        &data[start..],
+                    ),
+                    Answer::Full | Answer::Ranges => (
+                        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n", data.len()),
+                        &data[..],
+                    ),
+                    Answer::NoLength => ("HTTP/1.1 200 OK\r\n".to_owned(), &data[..]),
+                    Answer::Truncated | Answer::Stall => (
+                        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n", data.len()),
+                        &data[..data.len() / 2],
+                    ),
+                };
+                write!(stream, "{header}Connection: close\r\n\r\n").unwrap();
+                stream.write_all(body).unwrap();
+                if let Answer::Stall = answer {
+                    std::thread::sleep(std::time::Duration::from_secs(60));
+                }
+            }
+        });
+
+        url
+    }
+
+    #[tokio::test]
+    async fn test_download_timeout() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("foo.tar.gz");
+        let data: Vec<u8> = (0..=255).collect();
+        let url = serve(data, &[Answer::Stall]);
+
+        let conf = Config {
+            timeout: Some(1),
+            ..Default::default()
+        };
+        let client = Payload::client(&conf).unwrap();
+        let err = Payload::download_source(&client, &url, &path, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(PayloadError::TimedOut(timed_out)) if *timed_out == url
+        ));
+        assert_eq!(err.to_string(), format!("download of {url} timed out"));
+    }
+
+    #[tokio::test]
+    async fn test_download_resume() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("foo.tar.gz");
+        let data: Vec<u8> = (0..=255).collect();
+
+        fs::write(&path, &data[..100]).unwrap();
+        Payload::download_source(
+            &reqwest::Client::new(),
+            &serve(data.clone(), &[Answer::Ranges]),
+            &path,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), data);
+
+        // Without range support, the partial file is replaced.
+        fs::write(&path, "garbage").unwrap();
+        Payload::download_source(
+            &reqwest::Client::new(),
+            &serve(data.clone(), &[Answer::Full]),
+            &path,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_download_incomplete() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("foo.tar.gz");
+        let data: Vec<u8> = (0..=255).collect();
+
+        Payload::download_source(
+            &reqwest::Client::new(),
+            &serve(data.clone(), &[Answer::NoLength]),
+            &path,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), data);
+
+        fs::remove_file(&path).unwrap();
+        let url = serve(data.clone(), &[Answer::Truncated]);
+        assert!(
+            Payload::download_source(&reqwest::Client::new(), &url, &path, None)
+                .await
+                .is_err()
+        );
+
+        // A failed download is retried and resumed.
+        let pkg = |url| test_pkgfile("foo").url(url).build().unwrap();
+        let answers = [Answer::Truncated, Answer::Ranges];
+        let client = reqwest::Client::new();
+        fs::remove_file(&path).unwrap();
+        Payload::download_pkg(
+            &client,
+            &pkg(serve(data.clone(), &answers)),
+            path.clone(),
+            2,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), data);
+
+        // Without retries, the first failure is final.
+        fs::remove_file(&path).unwrap();
+        let download =
+            Payload::download_pkg(&client, &pkg(serve(data.clone(), &answers)), path, 0, None);
+        assert!(download.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_download_mirrors() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("foo.tar.gz");
+        let data: Vec<u8> = (0..=255).collect();
+        let checksum = format!("sha256:{}", hex::encode(Sha256::digest(&data)));
+
+        let pkg = test_pkgfile("foo")
+            .url(serve(b"corrupt".to_vec(), &[Answer::Full]))
+            .mirror("http://127.0.0.1:1/foo.tar.gz")
+            .mirror(serve(data.clone(), &[Answer::Full]))
+            .checksum(checksum)
+            .build()
+            .unwrap();
+        Payload::download_pkg(&reqwest::Client::new(), &pkg, path.clone(), 2, None)
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), data);
+
+        let pkg = PackageFile {
+            source: Source {
+                url: "http://127.0.0.1:1/foo.tar.gz".to_owned(),
+                mirrors: None,
+                ..pkg.source
+            },
+            ..pkg
+        };
+        assert!(
+            Payload::download_pkg(&reqwest::Client::new(), &pkg, path, 2, None)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_download_proxy() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("foo.tar.gz");
+        let data: Vec<u8> = (0..=255).collect();
+
+        // The test server answers every request, so it can act as the proxy.
+        let proxy = serve(data.clone(), &[Answer::Full]);
+        let conf = Config {
+            http_proxy: Some(proxy.trim_end_matches("/foo.tar.gz").to_owned()),
+            ..Default::default()
+        };
+        let client = Payload::client(&conf).unwrap();
+        Payload::download_source(&client, "http://example.invalid/foo.tar.gz", &path, None)
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), data);
+
+        let conf = Config {
+            http_proxy: Some("not a proxy".to_owned()),
+            ..Default::default()
+        };
+        assert!(Payload::client(&conf).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_verify_while_downloading() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..=255).collect();
+
+        let package = |name: &str, url| {
+            test_pkgfile(name)
+                .url(url)
+                .checksum(format!("sha256:{}", hex::encode(Sha256::digest(&data))))
+                .build()
+                .unwrap()
+        };
+        let corrupt = package("corrupt", serve(b"corrupt".to_vec(), &[Answer::Full]));
+        let stalled = package("stalled", serve(data.clone(), &[Answer::Stall]));
+
+        // The stalled download never finishes, so this only returns if the corrupt package is
+        // verified while it is still running and the failure aborts it.
+        let client = reqwest::Client::new();
+        let downloads = FuturesUnordered::new();
+        for pkg in [&corrupt, &stalled] {
+            let path = tmpdir.path().join(format!("{}.tar.gz", pkg.info.name));
+            downloads.push(Payload::download_pkg(&client, pkg, path, 0, None));
+        }
+        let result = tokio::time::timeout(
+            std::time::Duration::from_secs(10),
+            Payload::await_downloads(downloads),
+        )
+        .await
+        .expect("verification waited for all downloads");
+
+        let err = result.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(PayloadError::InvalidChecksum(_))
+        ));
+    }
+
+    #[cfg(feature = "verify-signatures")]
+    #[test]
+    fn test_verify_signature() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("foo.tar.gz");
+        let data: Vec<u8> = (0..=255).collect();
+        fs::write(&path, &data).unwrap();
+
+        let signature = "untrusted comment: test
+RUQBAgMEBQYHCLLjVeG7yI9vMk/otEQZfQ8wEWLZH7lIRSOvsOd/ZOdp6uchfRZuvjlOmt3TWJQG8Bp7YUwGeHu2v/InaJpjywE=
+trusted comment: test
+sFkzWPt19APfEYe7fHJei2QM5CoOlhBiAU2spDUVKisi8/tJnsZSQWA5XGxW9O/foR27GR0gu/OXn2ay03ExBQ==";
+        let package = |key: Option<&str>| {
+            let mut builder = test_pkgfile("foo").signature(signature);
+            if let Some(key) = key {
+                builder = builder.signing_key(key);
+            }
+            builder.build().unwrap()
+        };
+
+        let pkg = package(Some(
+            "RWQBAgMEBQYHCAOhB7/zzhC+HXDdGOdLwJln5NYwm6UNXx3chmQSVTG4",
+        ));
+        Payload::verify_signature(&pkg, &path, signature).unwrap();
+
+        let err = Payload::verify_signature(&package(None), &path, signature).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "package foo has a signature, but no signing key"
+        );
+
+        fs::write(&path, "tampered").unwrap();
+        let err = Payload::verify_signature(&pkg, &path, signature).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("invalid signature of package foo: "));
+    }
+
+    #[tokio::test]
+    async fn test_decompress_tarball() {
+        // init_logging();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path();
+
+        Payload::download_source(&reqwest::Client::new(), "https://github.com/topgrade-rs/topgrade/releases/download/v12.0.2/topgrade-v12.0.2-x86_64-apple-darwin.tar.gz", &path.join("topgrade.tar.gz"), None).await.unwrap();
+        Payload::decompress_tarball(path.join("topgrade.tar.gz"), path, 0).unwrap();
+
+        assert!(path.join("topgrade").exists());
+    }
+
+    #[test]
+    fn test_strip_components() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let tarball = |name: &str, entries: &[&str]| {
+            let path = tmpdir.path().join(name);
+            let encoder = flate2::write::GzEncoder::new(
+                File::create(&path).unwrap(),
+                flate2::Compression::default(),
+            );
+            let mut builder = tar::Builder::new(encoder);
+            for entry in entries {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(0);
+                header.set_mode(0o644);
+                header.set_cksum();
+                builder
+                    .append_data(&mut header, entry, io::empty())
+                    .unwrap();
+            }
+            builder.into_inner().unwrap().finish().unwrap();
+            path
+        };
+
+        let nested = tarball("nested.tar.gz", &["./foo-1.0/README", "foo-1.0/src/main.c"]);
+        assert_eq!(Payload::detect_strip_components(&nested).unwrap(), 1);
+        let untar = tmpdir.path().join("nested");
+        Payload::decompress_tarball(&nested, &untar, 1).unwrap();
+        assert!(untar.join("README").is_file());
+        assert!(untar.join("src/main.c").is_file());
+        assert!(!untar.join("foo-1.0").exists());
+
+        let flat = tarball("flat.tar.gz", &["foo", "bar/baz"]);
+        assert_eq!(Payload::detect_strip_components(&flat).unwrap(), 0);
+        let single = tarball("single.tar.gz", &["topgrade"]);
+        assert_eq!(Payload::detect_strip_components(&single).unwrap(), 0);
+
+        let untar = tmpdir.path().join("flat");
+        Payload::decompress_tarball(&flat, &untar, 1).unwrap();
+        assert!(untar.join("baz").is_file());
+        assert!(!untar.join("foo").exists());
+        Payload::check_extracted(&untar, "flat").unwrap();
+
+        let empty = tarball("empty.tar.gz", &[]);
+        let untar = tmpdir.path().join("empty");
+        fs::create_dir_all(&untar).unwrap();
+        Payload::decompress_tarball(&empty, &untar, 0).unwrap();
+        let err = Payload::check_extracted(&untar, "empty").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("extracted source for empty is empty"));
+    }
+
+    #[test]
+    fn test_clone_pkg() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let repo = tmpdir.path().join("repo");
+        fs::create_dir_all(&repo).unwrap();
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .arg("-C")
+                .arg(&repo)
+                .args(args)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        git(&["init", "--quiet"]);
+        fs::write(repo.join("VERSION"), "1").unwrap();
+        git(&["add", "VERSION"]);
+        git(&["commit", "--quiet", "-m", "v1"]);
+        git(&["tag", "v1"]);
+        fs::write(repo.join("VERSION"), "2").unwrap();
+        git(&["commit", "--quiet", "-am", "v2"]);
+
+        let package = |rev: Option<&str>| {
+            let mut builder = test_pkgfile("foo")
+                .url(repo.to_string_lossy())
+                .vcs(Vcs::Git);
+            if let Some(rev) = rev {
+                builder = builder.rev(rev);
+            }
+            builder.build().unwrap()
+        };
+        let dir = tmpdir.path().join("foo_1.0.0");
+
+        Payload::clone_pkg(&package(None), Vcs::Git, &dir).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("VERSION")).unwrap(), "2");
+        // Cloning again replaces the earlier clone.
+        Payload::clone_pkg(&package(Some("v1")), Vcs::Git, &dir).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("VERSION")).unwrap(), "1");
+
+        let err = Payload::clone_pkg(&package(Some("v3")), Vcs::Git, &dir).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<PayloadError>(),
+            Some(PayloadError::CloneFailed { package, .. }) if package == "foo"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_check_sha512() {
+        // init_logging();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("topgrade.tar.gz");
+
+        Payload::download_source(&reqwest::Client::new(), "https://github.com/topgrade-rs/topgrade/releases/download/v12.0.2/topgrade-v12.0.2-x86_64-apple-darwin.tar.gz", &path, None).await.unwrap();
+        assert!(
+            Payload::verify_checksum(
+                &path, "45dfddf13e8f5a5eb4a95dde6743f42f216ed6d3751d7430dae5f9e0dc54e67a400e6572789fb9984ff1c80bdee42a92112a76d5399436e857e723b653b366f1"
+            ).unwrap()
+        );
+        assert!(
+            Payload::verify_checksum(
+                &path, "sha512:45dfddf13e8f5a5eb4a95dde6743f42f216ed6d3751d7430dae5f9e0dc54e67a400e6572789fb9984ff1c80bdee42a92112a76d5399436e857e723b653b366f1"
+            ).unwrap()
+        );
+        assert!(!Payload::verify_checksum(
+            &path,
+            "sha256:45dfddf13e8f5a5eb4a95dde6743f42f216ed6d3751d7430dae5f9e0dc54e67a"
+        )
+        .unwrap());
+    }
+
+    #[test]
+    fn test_verify_checksum() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("file");
+        fs::write(&path, "mercurium\n").unwrap();
+
+        assert!(Payload::verify_checksum(
+            &path,
+            "sha256:2c5edc2285fc8c402dbb7c9c6f7a1bc3ebdbe60a5dca4ae71f3fc19650c38818"
+        )
+        .unwrap());
+        assert!(Payload::verify_checksum(
+            &path,
+            "882af55850b42aee664e5b8ad103fd20b0588343358e4f7f86934cef3dce014863475b51abcf5f77baa571c94ef35c5fdfe759a591af7041f1c586cd22c7cc89"
+        )
+        .unwrap());
+        assert!(!Payload::verify_checksum(
+            &path,
+            "sha512:2c5edc2285fc8c402dbb7c9c6f7a1bc3ebdbe60a5dca4ae71f3fc19650c38818"
+        )
+        .unwrap());
+        assert!(Payload::verify_checksum(&path, "md5:d41d8cd98f00b204e9800998ecf8427e").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_fix_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmpdir = tempfile::tempdir().unwrap();
+        let binaries = tmpdir.path();
+        let before = Payload::snapshot_dir(binaries).unwrap();
+
+        let binary = binaries.join("binary");
+        fs::write(&binary, [0x7f, b'E', b'L', b'F', 0x02, 0x01]).unwrap();
+        fs::set_permissions(&binary, fs::Permissions::from_mode(0o644)).unwrap();
+        let text = binaries.join("README");
+        fs::write(&text, "not a binary").unwrap();
+        fs::set_permissions(&text, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let after = Payload::snapshot_dir(binaries).unwrap();
+        let installed = Payload::changed_files(&before, &after);
+        assert_eq!(installed, vec![text.clone(), binary.clone()]);
+
+        Payload::fix_permissions(&installed, binaries, None).unwrap();
+        assert_eq!(
+            fs::metadata(&binary).unwrap().permissions().mode() & 0o777,
+            0o755
+        );
+        assert_eq!(
+            fs::metadata(&text).unwrap().permissions().mode() & 0o777,
+            0o644
+        );
+
+        Payload::fix_permissions(&[], binaries, Some(["README".to_owned()].as_slice())).unwrap();
+        assert_eq!(
+            fs::metadata(&text).unwrap().permissions().mode() & 0o777,
+            0o755
+        );
+    }
+
+    #[test]
+    fn test_command_env() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut pkgfile: PackageFile = toml::from_str(
+            "
+                [package]
+                name = \"env\"
+                license = \"MIT\"
+                version = \"1.0.0\"
+
+                [source]
+                url = \"https://example.com/env.tar.gz\"
+                build = \"test \\\"$PREFIX\\\" = /opt && test \\\"$CFLAGS\\\" = -O3\"
+                install = \"true\"
+
+                [source.env]
+                CFLAGS = \"-O3\"
+                source = \"/overridden\"
+            ",
+        )
+        .unwrap();
+        let conf_env = BTreeMap::from([
+            ("PREFIX".to_owned(), "/opt".to_owned()),
+            ("CFLAGS".to_owned(), "-O2".to_owned()),
+        ]);
+
+        let env = Payload::command_env(
+            "env",
+            &pkgfile.source,
+            &conf_env,
+            &[("source", tmpdir.path().as_os_str())],
+        );
+        assert_eq!(env["source"], tmpdir.path().as_os_str().to_owned());
+        let status = Payload::run_command(
+            pkgfile.source.build.as_ref().unwrap(),
+            env,
+            "env",
+            &OutputSink::Log,
+        )
+        .unwrap()
+        .status;
+        assert!(status.success());
+
+        pkgfile.source.env = None;
+        let env = Payload::command_env(
+            "env",
+            &pkgfile.source,
+            &conf_env,
+            &[("source", tmpdir.path())],
+        );
+        let status = Payload::run_command(
+            pkgfile.source.build.as_ref().unwrap(),
+            env,
+            "env",
+            &OutputSink::Log,
+        )
+        .unwrap()
+        .status;
+        assert!(!status.success());
+    }
+
+    #[test]
+    fn test_output_sink_concurrent() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let sink = OutputSink::Buffer(lines.clone());
+
+        std::thread::scope(|scope| {
+            for package in ["foo", "bar"] {
+                let sink = &sink;
+                scope.spawn(move || {
+                    let cmd = format!("for i in 1 2 3 4 5; do echo {package} $i >&2; done");
+                    let env: [(&str, &str); 0] = [];
+                    let status = Payload::run_command(&cmd, env, package, sink).unwrap();
+                    assert!(status.success());
                 });
             }
+        });
+
+        let lines = lines.lock().unwrap();
+        assert_eq!(lines.len(), 10);
+        for line in lines.iter() {
+            let (prefix, rest) = line.split_once(' ').unwrap();
+            let package = prefix.trim_start_matches('[').trim_end_matches(']');
+            assert!(
+                rest.starts_with(package),
+                "{line} is attributed to the wrong package"
+            );
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_build_pkgs_parallel() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let conf = Config {
+            directories: ConfigDirs {
+                sources: tmpdir.path().join("sources"),
+                builds: tmpdir.path().join("builds"),
+                binaries: tmpdir.path().join("binaries"),
+                packages: tmpdir.path().to_owned(),
+            },
+            ..Default::default()
+        };
+        let log = tmpdir.path().join("log");
+
+        let package = |name: &str, build: String, dependency: Option<&str>| {
+            let mut builder = test_pkgfile(name).build_command(build);
+            if let Some(dependency) = dependency {
+                builder = builder.dependency(dependency);
+            }
+            PayloadPackage {
+                file: builder.build().unwrap(),
+                manually_selected: true,
+                manually_added: false,
+                only_build: false,
+            }
+        };
+        let log_cmd = |name: &str| format!("echo {name} >> '{}'", log.to_string_lossy());
+
+        let mut payload = Payload::new();
+        payload.packages.insert(package(
+            "lib",
+            format!("sleep 0.2 && {}", log_cmd("lib")),
+            None,
+        ));
+        payload
+            .packages
+            .insert(package("app", log_cmd("app"), Some("lib")));
+        payload
+            .packages
+            .insert(package("other", log_cmd("other"), None));
+
+        let mpb = MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
+        let plan = payload.resolve().unwrap();
+        Payload::build_pkgs_parallel(payload.to_build(&plan), &conf, 2, &mpb).unwrap();
+
+        let built = fs::read_to_string(&log).unwrap();
+        let built: Vec<&str> = built.lines().collect();
+        assert_eq!(built.len(), 3);
+        let position = |name| built.iter().position(|line| *line == name).unwrap();
+        assert!(position("lib") < position("app"));
+
+        payload
+            .packages
+            .insert(package("broken", "echo oops >&2; exit 1".to_owned(), None));
+        let plan = payload.resolve().unwrap();
+        let err =
+            Payload::build_pkgs_parallel(payload.to_build(&plan), &conf, 2, &mpb).unwrap_err();
+        assert!(err.to_string().contains("broken"));
+        assert!(err.to_string().contains("oops"));
+    }
+
+    #[test]
+    fn test_no_build() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let marker = tmpdir.path().join("built");
+
+        let package = |name: &str, build: Option<String>| {
+            let mut builder = test_pkgfile(name);
+            if let Some(build) = build {
+                builder = builder.build_command(build);
+            }
+            PayloadPackage {
+                file: builder.build().unwrap(),
+                manually_selected: true,
+                manually_added: false,
+                only_build: false,
+            }
+        };
+
+        let mut payload = Payload::new();
+        payload.packages.insert(package(
+            "source",
+            Some(format!("touch '{}'", marker.to_string_lossy())),
+        ));
+        payload.packages.insert(package("prebuilt", None));
+        let plan = payload.resolve().unwrap();
+
+        let names = |pkgs: Vec<&PayloadPackage>| -> Vec<String> {
+            pkgs.iter().map(|pkg| pkg.info.name.clone()).collect()
+        };
+        assert_eq!(names(payload.to_build(&plan)), ["source"]);
+
+        payload.no_build = true;
+        assert!(payload.to_build(&plan).is_empty());
+
+        #[cfg(feature = "parallel")]
+        {
+            let conf = Config::default();
+            let mpb = MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden());
+            Payload::build_pkgs_parallel(payload.to_build(&plan), &conf, 2, &mpb).unwrap();
+            assert!(!marker.exists());
+
+            payload.no_build = false;
+            Payload::build_pkgs_parallel(payload.to_build(&plan), &conf, 2, &mpb).unwrap();
+            assert!(marker.exists());
+        }
+    }
+
+    #[test]
+    fn test_script() {
+        let conf = Config {
+            directories: ConfigDirs {
+                sources: PathBuf::from("/cache/sources"),
+                builds: PathBuf::from("/cache/builds"),
+                binaries: PathBuf::from("/bin"),
+                packages: PathBuf::from("/data"),
+            },
+            env: BTreeMap::from([("PREFIX".to_owned(), "/opt/it's".to_owned())]),
+            jobs: Some(4),
+            ..Default::default()
+        };
+        let file = test_pkgfile("foo")
+            .build_command("make")
+            .install_command("mv ${source}/foo ${binary}")
+            .build()
+            .unwrap();
+
+        let mut payload = Payload::new();
+        payload.packages.insert(PayloadPackage {
+            file: file.clone(),
+            manually_selected: true,
+            manually_added: false,
+            only_build: false,
+        });
+        let plan = payload.resolve().unwrap();
+        assert_eq!(
+            payload.script(&plan, &conf),
+            format!(
+                "# foo 1.0.0\n\
+                 export PREFIX='/opt/it'\\''s'\n\
+                 export binary='/bin'\n\
+                 export jobs='4'\n\
+                 export source='/cache/builds/foo_1.0.0'\n\
+                 (cd '/cache/builds/foo_1.0.0' && {})\n\
+                 {}\n\n",
+                file.source.build.as_ref().unwrap(),
+                file.source.install
+            )
+        );
+    }
+
+    #[test]
+    fn test_run_command_output() {
+        let env: [(&str, &str); 0] = [];
+        let output = Payload::run_command(
+            "echo out; echo err >&2; exit 2",
+            env,
+            "test",
+            &OutputSink::Log,
+        )
+        .unwrap();
+        assert_eq!(output.status.code(), Some(2));
+        assert_eq!(output.stdout, "out\n");
+        assert_eq!(output.stderr, "err\n");
+    }
+
+    #[test]
+    fn test_build_failed() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let conf = Config {
+            directories: ConfigDirs {
+                sources: tmpdir.path().join("sources"),
+                builds: tmpdir.path().join("builds"),
+                binaries: tmpdir.path().join("binaries"),
+                packages: tmpdir.path().to_owned(),
+            },
+            ..Default::default()
+        };
+        let file = test_pkgfile("broken")
+            .build_command("echo 'missing header' >&2; exit 3")
+            .build()
+            .unwrap();
+        fs::create_dir_all(conf.build_path(&file.info)).unwrap();
+
+        let err = Payload::build_pkg(&file, &conf, &OutputSink::Log).unwrap_err();
+        match err.downcast_ref::<PayloadError>() {
+            Some(PayloadError::BuildFailed {
+                package,
+                status,
+                stderr,
+            }) => {
+                assert_eq!(package, "broken");
+                assert_eq!(status.code(), Some(3));
+                assert_eq!(stderr, "missing header\n");
+            }
+            _ => panic!("unexpected error {err}"),
         }
+    }
+
+    #[test]
+    fn test_post_install() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut conf = Config {
+            directories: ConfigDirs {
+                sources: tmpdir.path().join("sources"),
+                builds: tmpdir.path().join("builds"),
+                binaries: tmpdir.path().join("binaries"),
+                packages: tmpdir.path().to_owned(),
+            },
+            ..Default::default()
+        };
+        fs::create_dir_all(conf.binaries_path()).unwrap();
+        let package = |name: &str, post_install: &str| {
+            test_pkgfile(name)
+                .post_install_command(post_install)
+                .build()
+                .unwrap()
+        };
+
+        let hook = package("hook", "touch \"$binary/hook-ran\"");
+        Payload::post_install_pkg(&hook, &conf).unwrap();
+        assert!(conf.binaries_path().join("hook-ran").is_file());
+
+        let mut payload = Payload::new();
+        payload.packages.insert(PayloadPackage {
+            file: package("broken", "echo 'no cache' >&2; exit 1"),
+            manually_selected: true,
+            manually_added: false,
+            only_build: false,
+        });
+        let plan = payload.resolve().unwrap();
+        // Only a warning by default.
+        payload.post_install_pkgs(&plan, &conf).unwrap();
+        conf.strict_post_install = true;
+        let err = payload.post_install_pkgs(&plan, &conf).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "post-install command of package broken failed (exit status: 1):\nno cache\n"
+        );
+    }
+
+    #[test]
+    fn test_source_dir() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let conf = Config {
+            directories: ConfigDirs {
+                sources: tmpdir.path().join("sources"),
+                builds: tmpdir.path().join("builds"),
+                binaries: tmpdir.path().join("binaries"),
+                packages: tmpdir.path().to_owned(),
+            },
+            ..Default::default()
+        };
+        let file = test_pkgfile("foo")
+            .prepare_command("test -f src/main.c")
+            .build_command("test -f src/main.c")
+            .build()
+            .unwrap();
+
+        // After stripping `foo-1.0.0`, the only top-level entry is the directory `src`.
+        let tarball = tmpdir.path().join("foo.tar.gz");
+        let encoder = flate2::write::GzEncoder::new(
+            File::create(&tarball).unwrap(),
+            flate2::Compression::default(),
+        );
+        let mut builder = tar::Builder::new(encoder);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(0);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "foo-1.0.0/src/main.c", io::empty())
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let untar = conf.build_path(&file.info);
+        fs::create_dir_all(&untar).unwrap();
+        let strip_components = Payload::detect_strip_components(&tarball).unwrap();
+        Payload::decompress_tarball(&tarball, &untar, strip_components).unwrap();
+        assert!(untar.join("src/main.c").is_file());
+
+        // Prepare and build commands both run in the stripped source tree, not in `src`.
+        Payload::prepare_pkg(&file, &conf, &OutputSink::Log).unwrap();
+        Payload::build_pkg(&file, &conf, &OutputSink::Log).unwrap();
+    }
+
+    #[test]
+    fn test_prepare_pkg() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let conf = Config {
+            directories: ConfigDirs {
+                sources: tmpdir.path().join("sources"),
+                builds: tmpdir.path().join("builds"),
+                binaries: tmpdir.path().join("binaries"),
+                packages: tmpdir.path().to_owned(),
+            },
+            ..Default::default()
+        };
+        let patch = tmpdir.path().join("fix.patch");
+        fs::write(
+            &patch,
+            "--- a/hello.txt\n+++ b/hello.txt\n@@ -1 +1 @@\n-hello\n+patched\n",
+        )
+        .unwrap();
+        let file = test_pkgfile("foo")
+            .patch(patch.to_string_lossy())
+            .prepare_command("echo prepared > prepared.txt")
+            .build_command(
+                "grep -q patched \"$source/hello.txt\" && test -f \"$source/prepared.txt\"",
+            )
+            .build()
+            .unwrap();
+
+        let untar = conf.build_path(&file.info);
+        fs::create_dir_all(&untar).unwrap();
+        fs::write(untar.join("hello.txt"), "hello\n").unwrap();
+
+        // The build only succeeds on the patched and prepared source.
+        assert!(Payload::build_pkg(&file, &conf, &OutputSink::Log).is_err());
+        Payload::prepare_pkg(&file, &conf, &OutputSink::Log).unwrap();
+        assert_eq!(
+            fs::read_to_string(untar.join("hello.txt")).unwrap(),
+            "patched\n"
+        );
+        Payload::build_pkg(&file, &conf, &OutputSink::Log).unwrap();
+
+        // The patch doesn't apply a second time.
+        let err = Payload::prepare_pkg(&file, &conf, &OutputSink::Log).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(PayloadError::PrepareFailed { package, .. }) if package == "foo"
+        ));
+    }
+
+    #[test]
+    fn test_write_db() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = Database::create(tmpdir.path().join("packages.db")).unwrap();
+        db.init_table(ALL_PKGS).unwrap();
+        db.init_table(INSTALLED_PKGS).unwrap();
 
-        self.packages.insert(PayloadPackage {
-            file: pkg.into(),
+        let file = test_pkgfile("foo").build().unwrap();
+        let mut payload = Payload::new();
+        payload.packages.insert(PayloadPackage {
+            file,
             manually_selected: true,
             manually_added: false,
+            only_build: false,
         });
 
-        Ok(())
+        let installed_rows = || {
+            let read_txn = db.begin_read().unwrap();
+            let read_table = read_txn.open_table(INSTALLED_PKGS).unwrap();
+            read_table.iter().unwrap().count()
+        };
+
+        payload.write_db(&db).unwrap();
+        assert_eq!(installed_rows(), 1);
+        let installed_at = db
+            .get(INSTALLED_PKGS, "foo")
+            .unwrap()
+            .unwrap()
+            .local
+            .installed_at;
+        assert!(installed_at.is_some());
+        // Writing an installed package again keeps the time it was installed.
+        db.modify(INSTALLED_PKGS, "foo", |pkg| {
+            pkg.map(|mut pkg| {
+                pkg.local.installed_at = Some(0);
+                pkg
+            })
+        })
+        .unwrap();
+        payload.write_db(&db).unwrap();
+        assert_eq!(installed_rows(), 1);
+        assert_eq!(
+            db.get(INSTALLED_PKGS, "foo")
+                .unwrap()
+                .unwrap()
+                .local
+                .installed_at,
+            Some(0)
+        );
+
+        let all_pkg = db.get(ALL_PKGS, "foo").unwrap().unwrap();
+        assert_eq!(
+            all_pkg.local.installed,
+            Installed::Manually(Version::from_str("1.0.0").unwrap())
+        );
+        assert_eq!(db.get(INSTALLED_PKGS, "foo").unwrap(), Some(all_pkg));
     }
 
-    /// Add a package file and its dependencies to the payload.
-    /// This marks the package as manually installed and added.
-    pub fn add_pkgfile(&mut self, pkgfile: PackageFile) -> DynResult<()> {
-        let db = DB.get().unwrap();
+    #[test]
+    fn test_add_install() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = Database::create(tmpdir.path().join("packages.db")).unwrap();
+        db.init_table(ALL_PKGS).unwrap();
+        db.init_table(INSTALLED_PKGS).unwrap();
 
-        if let Some(deps) = &pkgfile.info.dependencies {
-            let pkgs = db.get_iter(
-                ALL_PKGS,
-                deps.iter().map(|x| x.as_str()).collect::<Vec<&str>>(),
-            )?;
+        let file = test_pkgfile("foo").build().unwrap();
 
-            for (key, pkg) in deps.iter().zip(pkgs.into_iter()) {
-                let pkg = pkg.unwrap_or_else(|| {
-                    exit_with_message(format!("Dependency {key} not found!"), exitcode::DATAERR)
-                });
-                self.packages.insert(PayloadPackage {
-                    file: pkg.into(),
-                    manually_selected: false,
-                    manually_added: false,
-                });
+        // `add --install` adds the pkgfile together with recording its installation.
+        let mut payload = Payload::new();
+        payload.add_pkgfile(&db, file.clone()).unwrap();
+        payload.write_db(&db).unwrap();
+
+        let all_pkg = db.get(ALL_PKGS, "foo").unwrap().unwrap();
+        assert!(all_pkg.local.added);
+        assert_eq!(
+            all_pkg.local.installed,
+            Installed::Manually(Version::from_str("1.0.0").unwrap())
+        );
+        assert_eq!(db.get(INSTALLED_PKGS, "foo").unwrap(), Some(all_pkg));
+
+        // An up-to-date pkgfile isn't installed again, but still added.
+        db.set(
+            ALL_PKGS,
+            "bar",
+            Package::from_file(
+                PackageFile {
+                    info: PackageInfo {
+                        name: "bar".to_owned(),
+                        ..file.info.clone()
+                    },
+                    source: file.source.clone(),
+                },
+                Local {
+                    installed: Installed::Automatically(Version::from_str("1.0.0").unwrap()),
+                    ..Default::default()
+                },
+            ),
+        )
+        .unwrap();
+        let installed_bar = db.get(ALL_PKGS, "bar").unwrap().unwrap();
+        db.set(INSTALLED_PKGS, "bar", installed_bar).unwrap();
+        let mut bar = file.clone();
+        bar.info.name = "bar".to_owned();
+        bar.info.description = Some("Updated description".to_owned());
+
+        let mut payload = Payload::new();
+        payload.add_pkgfile(&db, bar).unwrap();
+        payload.skip_up_to_date(&db).unwrap();
+        assert!(payload.package_names().is_empty());
+        payload.write_db(&db).unwrap();
+
+        let all_pkg = db.get(ALL_PKGS, "bar").unwrap().unwrap();
+        assert!(all_pkg.local.added);
+        assert_eq!(
+            all_pkg.info.description.as_deref(),
+            Some("Updated description")
+        );
+        assert_eq!(
+            all_pkg.local.installed,
+            Installed::Manually(Version::from_str("1.0.0").unwrap())
+        );
+    }
+
+    #[test]
+    fn test_pkg_versions() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = Database::create(tmpdir.path().join("packages.db")).unwrap();
+        db.init_table(ALL_PKGS).unwrap();
+        db.init_table(INSTALLED_PKGS).unwrap();
+        db.init_table(PKG_VERSIONS).unwrap();
+
+        let v1 = Version::from_str("1.0.0").unwrap();
+        let v2 = Version::from_str("2.0.0").unwrap();
+        for version in [&v2, &v1] {
+            test_pkgfile("foo")
+                .version(version.clone())
+                .url(format!("https://example.com/foo-{version}.tar.gz"))
+                .build()
+                .unwrap()
+                .add_to_db(&db)
+                .unwrap();
+        }
+
+        assert_eq!(
+            db.get_versions(PKG_VERSIONS, "foo").unwrap(),
+            [v1.clone(), v2.clone()]
+        );
+        assert_eq!(db.get(ALL_PKGS, "foo").unwrap().unwrap().info.version, v2);
+
+        let mut payload = Payload::new();
+        payload.add_pkg(&db, "foo@1.0.0").unwrap();
+        let pkg = payload.packages.iter().next().unwrap();
+        assert_eq!(pkg.file.info.version, v1);
+        assert_eq!(pkg.file.source.url, "https://example.com/foo-1.0.0.tar.gz");
+
+        // Installing the old version keeps describing the newest one in `ALL_PKGS`.
+        payload.write_db(&db).unwrap();
+        let all_pkg = db.get(ALL_PKGS, "foo").unwrap().unwrap();
+        assert_eq!(all_pkg.info.version, v2);
+        assert_eq!(all_pkg.local.installed, Installed::Manually(v1.clone()));
+        let installed = db.get(INSTALLED_PKGS, "foo").unwrap().unwrap();
+        assert_eq!(installed.info.version, v1);
+    }
+
+    #[test]
+    fn test_reinstall_restores_reason() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = Database::create(tmpdir.path().join("packages.db")).unwrap();
+        db.init_table(ALL_PKGS).unwrap();
+        db.init_table(INSTALLED_PKGS).unwrap();
+        let conf = Config {
+            directories: ConfigDirs {
+                sources: tmpdir.path().join("sources"),
+                builds: tmpdir.path().join("builds"),
+                binaries: tmpdir.path().join("binaries"),
+                packages: tmpdir.path().to_owned(),
+            },
+            ..Default::default()
+        };
+
+        let file = test_pkgfile("dep").build().unwrap();
+        let payload = |manually_selected| {
+            let mut payload = Payload::new();
+            payload.packages.insert(PayloadPackage {
+                file: file.clone(),
+                manually_selected,
+                manually_added: false,
+                only_build: false,
+            });
+            payload
+        };
+        let version = Version::from_str("1.0.0").unwrap();
+
+        payload(false).write_db(&db).unwrap();
+        crate::remove_pkg(&db, &conf, "dep", false).unwrap();
+        let removed = db.get(ALL_PKGS, "dep").unwrap().unwrap();
+        assert_eq!(removed.local.installed, Installed::False);
+        assert_eq!(
+            removed.local.last_installed,
+            Installed::Automatically(version.clone())
+        );
+
+        payload(true).write_db(&db).unwrap();
+        let reinstalled = db.get(INSTALLED_PKGS, "dep").unwrap().unwrap();
+        assert_eq!(
+            reinstalled.local.installed,
+            Installed::Automatically(version)
+        );
+        assert_eq!(reinstalled.local.last_installed, Installed::False);
+    }
+
+    #[test]
+    fn test_added_stays_added() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = Database::create(tmpdir.path().join("packages.db")).unwrap();
+        db.init_table(ALL_PKGS).unwrap();
+        db.init_table(INSTALLED_PKGS).unwrap();
+
+        let file = test_pkgfile("dep").build().unwrap();
+        let as_dependency = || {
+            let mut payload = Payload::new();
+            payload.packages.insert(PayloadPackage {
+                file: file.clone(),
+                manually_selected: false,
+                manually_added: false,
+                only_build: false,
+            });
+            payload
+        };
+
+        // Added, then installed as a dependency.
+        file.clone().add_to_db(&db).unwrap();
+        as_dependency().write_db(&db).unwrap();
+        let installed = db.get(INSTALLED_PKGS, "dep").unwrap().unwrap();
+        assert!(installed.local.added);
+
+        // Reinstalled as a dependency.
+        as_dependency().write_db(&db).unwrap();
+        let reinstalled = db.get(INSTALLED_PKGS, "dep").unwrap().unwrap();
+        assert!(reinstalled.local.added);
+        assert_eq!(db.get(ALL_PKGS, "dep").unwrap(), Some(reinstalled));
+    }
+
+    #[test]
+    fn test_mark_up_to_date() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = Database::create(tmpdir.path().join("packages.db")).unwrap();
+        db.init_table(ALL_PKGS).unwrap();
+        db.init_table(INSTALLED_PKGS).unwrap();
+
+        let file = |name: &str, dependency: Option<&str>| {
+            let mut builder = test_pkgfile(name);
+            if let Some(dependency) = dependency {
+                builder = builder.dependency(dependency);
             }
+            builder.build().unwrap()
+        };
+        let install = |file: PackageFile, manually: bool| {
+            let version = file.info.version.clone();
+            let local = Local {
+                installed: if manually {
+                    Installed::Manually(version)
+                } else {
+                    Installed::Automatically(version)
+                },
+                ..Default::default()
+            };
+            let name = file.info.name.clone();
+            let pkg = Package::from_file(file, local);
+            db.set(ALL_PKGS, name.as_str(), pkg.clone()).unwrap();
+            db.set(INSTALLED_PKGS, name.as_str(), pkg).unwrap();
+        };
+        install(file("app", Some("lib")), true);
+        install(file("lib", None), false);
+
+        // Installing another package sharing the dependency keeps it automatically installed.
+        let mut payload = Payload::new();
+        for (file, manually_selected) in [
+            (file("other", Some("lib")), true),
+            (file("lib", None), false),
+        ] {
+            payload.packages.insert(PayloadPackage {
+                file,
+                manually_selected,
+                manually_added: false,
+                only_build: false,
+            });
         }
+        assert_eq!(
+            payload.mark_up_to_date(&db).unwrap(),
+            HashSet::from(["lib".to_owned()])
+        );
+        install(file("other", Some("lib")), true);
+        for table in [INSTALLED_PKGS, ALL_PKGS] {
+            assert!(matches!(
+                db.get(table, "lib").unwrap().unwrap().local.installed,
+                Installed::Automatically(_)
+            ));
+        }
+        db.remove(INSTALLED_PKGS, "app").unwrap();
+        assert!(crate::orphans(&db).unwrap().is_empty());
+        db.remove(INSTALLED_PKGS, "other").unwrap();
+        assert_eq!(crate::orphans(&db).unwrap(), ["lib"]);
 
-        self.packages.insert(PayloadPackage {
-            file: pkgfile,
+        // Selecting it manually marks it as manually installed in both tables.
+        let mut payload = Payload::new();
+        payload.packages.insert(PayloadPackage {
+            file: file("lib", None),
             manually_selected: true,
-            manually_added: true,
+            manually_added: false,
+            only_build: false,
         });
+        payload.mark_up_to_date(&db).unwrap();
+        for table in [INSTALLED_PKGS, ALL_PKGS] {
+            assert!(matches!(
+                db.get(table, "lib").unwrap().unwrap().local.installed,
+                Installed::Manually(_)
+            ));
+        }
+    }
 
-        Ok(())
+    #[test]
+    fn test_check_trust() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = Database::create(tmpdir.path().join("packages.db")).unwrap();
+        crate::init_tables(&db).unwrap();
+
+        let file = test_pkgfile("foo")
+            .repository("https://example.com/foo")
+            .url("https://example.com/foo.tar.gz")
+            .build()
+            .unwrap();
+        let mut payload = Payload::new();
+        payload.packages.insert(PayloadPackage {
+            file,
+            manually_selected: true,
+            manually_added: false,
+            only_build: false,
+        });
+
+        let prompts = Cell::new(0);
+        let confirm = |answer| {
+            let prompts = &prompts;
+            move |_: &PackageFile| -> DynResult<bool> {
+                prompts.set(prompts.get() + 1);
+                Ok(answer)
+            }
+        };
+
+        // Configured sources are trusted without asking, but only below whole path segments.
+        let trusted = ["https://example.com/".to_owned()];
+        assert!(payload.check_trust(&db, &trusted, confirm(false)).unwrap());
+        assert_eq!(prompts.get(), 0);
+        let trusted = ["https://example.com/fo".to_owned()];
+        assert!(!payload.check_trust(&db, &trusted, confirm(false)).unwrap());
+        assert_eq!(prompts.get(), 1);
+
+        // The repository a pkgfile claims isn't trusted.
+        let trusted = ["https://example.com/foo".to_owned()];
+        assert!(!payload.check_trust(&db, &trusted, confirm(false)).unwrap());
+        assert!(payload.check_trust(&db, &[], confirm(true)).unwrap());
+        assert_eq!(prompts.get(), 3);
+
+        // The confirmed source was recorded.
+        assert!(payload.check_trust(&db, &[], confirm(false)).unwrap());
+        assert_eq!(prompts.get(), 3);
+
+        // Changed commands are asked for again.
+        let mut changed = payload.packages.drain().next().unwrap();
+        changed.file.source.install = "rm -rf ~".to_owned();
+        payload.packages.insert(changed);
+        assert!(!payload.check_trust(&db, &[], confirm(false)).unwrap());
+        assert_eq!(prompts.get(), 4);
+
+        // Neither `--yes` nor `--trust` record the source, and only `--trust` accepts it.
+        let mut other = PackageFile::clone(payload.packages.iter().next().unwrap());
+        other.source.url = "https://example.org/bar.tar.gz".to_owned();
+        payload.packages.insert(PayloadPackage {
+            file: other,
+            manually_selected: true,
+            manually_added: false,
+            only_build: false,
+        });
+        payload.assume_yes = true;
+        assert!(!payload.check_trust(&db, &[], confirm(true)).unwrap());
+        payload.trust = true;
+        assert!(payload.check_trust(&db, &[], confirm(true)).unwrap());
+        assert_eq!(prompts.get(), 4);
+        let read_txn = db.begin_read().unwrap();
+        let table = read_txn.open_table(TRUSTED_SOURCES).unwrap();
+        assert!(table
+            .get("https://example.org/bar.tar.gz")
+            .unwrap()
+            .is_none());
     }
 
-    /// Execute the payload.
-    pub async fn install(mut self) -> DynResult<()> {
-        self.check_install()?;
-        self.download_pkgs().await?;
-        self.check_sha512_pkgs()?;
-        self.decompress_pkgs()?;
-        self.build_pkgs()?;
-        self.install_pkgs()?;
-        self.write_db()?;
-        println!("Done!");
+    #[test]
+    fn test_resolve() {
+        let package = |name: &str, version: &str, dependency: Option<&str>| {
+            let mut builder = test_pkgfile(name).version(Version::from_str(version).unwrap());
+            if let Some(dependency) = dependency {
+                builder = builder.dependency(dependency);
+            }
+            PayloadPackage {
+                file: builder.build().unwrap(),
+                manually_selected: true,
+                manually_added: false,
+                only_build: false,
+            }
+        };
 
-        Ok(())
+        let mut payload = Payload::new();
+        payload
+            .packages
+            .insert(package("app", "1.0.0", Some("lib")));
+        payload.packages.insert(package("lib", "1.0.0", None));
+        payload.packages.insert(PayloadPackage {
+            manually_selected: false,
+            ..package("lib", "1.0.0", None)
+        });
+        let plan = payload.resolve().unwrap();
+        assert_eq!(plan.order, ["lib", "app"]);
+        assert_eq!(payload.packages.len(), 2);
+
+        payload.packages.insert(package("lib", "2.0.0", None));
+        assert!(matches!(
+            payload.resolve(),
+            Err(PayloadError::Conflict(name)) if name == "lib"
+        ));
+
+        let mut payload = Payload::new();
+        payload.packages.insert(package("a", "1.0.0", Some("b")));
+        payload.packages.insert(package("b", "1.0.0", Some("a")));
+        match payload.resolve() {
+            Err(PayloadError::DependencyCycle(cycle)) => assert_eq!(cycle, ["a", "b", "a"]),
+            other => panic!("unexpected result {other:?}"),
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::str::FromStr;
+    #[test]
+    fn test_only_dependencies() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = Database::create(tmpdir.path().join("packages.db")).unwrap();
+        db.init_table(ALL_PKGS).unwrap();
 
-    use redb::Database;
-    use semver::Version;
+        let package = |name: &str, dependencies: &[&str]| {
+            let mut builder = test_pkgfile(name);
+            for dependency in dependencies {
+                builder = builder.dependency(*dependency);
+            }
+            builder.build().unwrap()
+        };
+        for pkg in [package("dep", &[]), package("foo", &["dep"])] {
+            pkg.add_to_db(&db).unwrap();
+        }
 
-    use super::*;
-    use crate::config::{Config, ConfigDirs};
-    use crate::db::Db;
-    use crate::pkg::{Installed, Local, Package, PackageInfo, Source};
-    use crate::{ALL_PKGS, DB, INSTALLED_PKGS};
+        let mut payload = Payload::new();
+        payload.only_dependencies = true;
+        payload.add_pkg(&db, "foo").unwrap();
+        payload.add_pkgfile(&db, package("bar", &["dep"])).unwrap();
+        assert_eq!(payload.package_names(), ["dep"]);
+        assert_eq!(payload.skipped, ["foo", "bar"]);
+    }
 
-    #[tokio::test]
-    async fn test_download() {
-        // init_logging();
+    #[test]
+    fn test_dependency_cycle() {
         let tmpdir = tempfile::tempdir().unwrap();
-        let path = tmpdir.path().join("topgrade.tar.gz");
+        let db = Database::create(tmpdir.path().join("packages.db")).unwrap();
+        db.init_table(ALL_PKGS).unwrap();
 
-        Payload::download_source("https://github.com/topgrade-rs/topgrade/releases/download/v12.0.2/topgrade-v12.0.2-x86_64-apple-darwin.tar.gz", &path, None).await.unwrap();
+        let package = |name: &str, dependency: &str| {
+            test_pkgfile(name).dependency(dependency).build().unwrap()
+        };
+        let local = Local::default();
+        let a = package("a", "b");
+        db.set(ALL_PKGS, "a", Package::from_file(a.clone(), local.clone()))
+            .unwrap();
+        db.set(ALL_PKGS, "b", Package::from_file(package("b", "a"), local))
+            .unwrap();
 
-        assert!(path.exists());
+        let mut payload = Payload::new();
+        let err = payload
+            .add_deps(&db, &a, false, &mut vec!["a".to_owned()])
+            .unwrap_err();
+        assert!(matches!(
+            &err,
+            PayloadError::DependencyCycle(cycle) if cycle == &["a", "b", "a"]
+        ));
+        assert_eq!(err.to_string(), "dependency cycle: a -> b -> a");
     }
 
-    #[tokio::test]
-    async fn test_decompress_tarball() {
-        // init_logging();
+    #[test]
+    fn test_dependency_not_found() {
         let tmpdir = tempfile::tempdir().unwrap();
-        let path = tmpdir.path();
+        let db = Database::create(tmpdir.path().join("packages.db")).unwrap();
+        db.init_table(ALL_PKGS).unwrap();
 
-        Payload::download_source("https://github.com/topgrade-rs/topgrade/releases/download/v12.0.2/topgrade-v12.0.2-x86_64-apple-darwin.tar.gz", &path.join("topgrade.tar.gz"), None).await.unwrap();
-        Payload::decompress_tarball(path.join("topgrade.tar.gz"), path).unwrap();
+        let package = |name: &str, dependency: &str| {
+            test_pkgfile(name).dependency(dependency).build().unwrap()
+        };
+        let local = Local::default();
+        let a = package("a", "b");
+        db.set(ALL_PKGS, "a", Package::from_file(a.clone(), local.clone()))
+            .unwrap();
+        db.set(
+            ALL_PKGS,
+            "b",
+            Package::from_file(package("b", "missing"), local),
+        )
+        .unwrap();
 
-        assert!(path.join("topgrade").exists());
+        let mut payload = Payload::new();
+        let err = payload
+            .add_deps(&db, &a, false, &mut vec!["a".to_owned()])
+            .unwrap_err();
+        assert!(matches!(
+            &err,
+            PayloadError::DependencyNotFound { package, dependency, path }
+                if package == "b" && dependency == "missing" && path == &["a", "b"]
+        ));
+        assert_eq!(
+            err.to_string(),
+            "dependency missing of package b not found (a -> b -> missing)"
+        );
     }
 
-    #[tokio::test]
-    async fn test_check_sha512() {
-        // init_logging();
+    #[test]
+    fn test_select_provider() {
         let tmpdir = tempfile::tempdir().unwrap();
-        let path = tmpdir.path().join("topgrade.tar.gz");
+        let db = Database::create(tmpdir.path().join("packages.db")).unwrap();
+        db.init_table(ALL_PKGS).unwrap();
+
+        let local = Local::default();
+        for name in ["nano", "vim"] {
+            let file = test_pkgfile(name).provides("editor").build().unwrap();
+            db.set(ALL_PKGS, name, Package::from_file(file, local.clone()))
+                .unwrap();
+        }
+        let app = test_pkgfile("app").dependency("editor").build().unwrap();
+
+        let mut payload = Payload::new();
+        payload.add_pkgfile(&db, app.clone()).unwrap();
+        assert_eq!(payload.package_names(), ["app", "nano"]);
+
+        let mut payload = Payload::new();
+        payload.selection = Selection::Scripted(vec![1]);
+        payload.add_pkgfile(&db, app).unwrap();
+        assert_eq!(payload.package_names(), ["app", "vim"]);
+
+        let plan = payload.resolve().unwrap();
+        assert_eq!(plan.order, ["vim", "app"]);
+    }
+
+    #[test]
+    fn test_virtual_dependency() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = Database::create(tmpdir.path().join("packages.db")).unwrap();
+        db.init_table(ALL_PKGS).unwrap();
+
+        let local = Local::default();
+        for file in [
+            test_pkgfile("gcc").provides("cc").build().unwrap(),
+            test_pkgfile("clang").provides("cc").build().unwrap(),
+            test_pkgfile("app").dependency("cc >=1").build().unwrap(),
+        ] {
+            let name = file.info.name.clone();
+            db.set(
+                ALL_PKGS,
+                name.as_str(),
+                Package::from_file(file, local.clone()),
+            )
+            .unwrap();
+        }
+
+        // The dependency isn't a package, so one of its providers is installed.
+        let mut payload = Payload::new();
+        payload.add_pkg(&db, "app").unwrap();
+        assert_eq!(payload.package_names(), ["app", "clang"]);
+        assert_eq!(payload.resolve().unwrap().order, ["clang", "app"]);
+
+        // A provider that is installed anyway satisfies it.
+        let mut payload = Payload::new();
+        payload.add_pkg(&db, "gcc").unwrap();
+        payload.add_pkg(&db, "app").unwrap();
+        assert_eq!(payload.package_names(), ["app", "gcc"]);
+        assert_eq!(payload.resolve().unwrap().order, ["gcc", "app"]);
+    }
+
+    #[test]
+    fn test_build_dependencies() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = Database::create(tmpdir.path().join("packages.db")).unwrap();
+        db.init_table(ALL_PKGS).unwrap();
+
+        for file in [
+            test_pkgfile("libc").build().unwrap(),
+            test_pkgfile("gcc").dependency("libc").build().unwrap(),
+            test_pkgfile("app")
+                .build_command("make")
+                .build_dependency("gcc")
+                .build()
+                .unwrap(),
+            test_pkgfile("prebuilt")
+                .build_dependency("gcc")
+                .build()
+                .unwrap(),
+        ] {
+            file.add_to_db(&db).unwrap();
+        }
+        let only_build = |payload: &Payload| {
+            let mut names: Vec<&str> = payload
+                .packages
+                .iter()
+                .filter(|pkg| pkg.only_build)
+                .map(|pkg| pkg.info.name.as_str())
+                .collect();
+            names.sort();
+            names
+        };
+
+        // The build dependencies are installed in an earlier stage than the package built with them.
+        let mut payload = Payload::new();
+        payload.add_pkg(&db, "app").unwrap();
+        assert_eq!(only_build(&payload), ["gcc", "libc"]);
+        let plan = payload.resolve().unwrap();
+        assert_eq!(plan.order, ["libc", "gcc", "app"]);
+        assert_eq!(plan.stages, [vec!["libc", "gcc"], vec!["app"]]);
+
+        // Packages that aren't built don't need their build dependencies.
+        let mut payload = Payload::new();
+        payload.add_pkg(&db, "prebuilt").unwrap();
+        assert_eq!(payload.package_names(), ["prebuilt"]);
+        let mut payload = Payload::new();
+        payload.no_build = true;
+        payload.add_pkg(&db, "app").unwrap();
+        assert_eq!(payload.package_names(), ["app"]);
+
+        // A build dependency that is also needed at runtime stays installed.
+        let mut payload = Payload::new();
+        payload.add_pkg(&db, "app").unwrap();
+        payload.add_pkg(&db, "gcc").unwrap();
+        let plan = payload.resolve().unwrap();
+        assert!(only_build(&payload).is_empty());
+        assert_eq!(plan.stages.len(), 2);
+    }
+
+    #[test]
+    fn test_dependency_requirement() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = Database::create(tmpdir.path().join("packages.db")).unwrap();
+        db.init_table(ALL_PKGS).unwrap();
+
+        let package = |name: &str, version: &str| {
+            test_pkgfile(name).version(Version::from_str(version).unwrap())
+        };
+        let local = Local::default();
+        let openssl = package("openssl", "1.1.1").build().unwrap();
+        db.set(ALL_PKGS, "openssl", Package::from_file(openssl, local))
+            .unwrap();
+
+        for dependency in ["openssl", "openssl >=1.1, <2"] {
+            let pkg = package("foo", "1.0.0")
+                .dependency(dependency)
+                .build()
+                .unwrap();
+            let mut payload = Payload::new();
+            payload
+                .add_deps(&db, &pkg, false, &mut vec!["foo".to_owned()])
+                .unwrap();
+            assert_eq!(payload.packages.len(), 1);
+        }
+
+        let pkg = package("foo", "1.0.0")
+            .dependency("openssl >=3")
+            .build()
+            .unwrap();
+        let err = Payload::new()
+            .add_deps(&db, &pkg, false, &mut vec!["foo".to_owned()])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "package foo requires openssl >=3, but only version 1.1.1 is available"
+        );
+
+        let pkg = package("foo", "1.0.0")
+            .dependency("openssl not a version")
+            .build()
+            .unwrap();
+        let err = Payload::new()
+            .add_deps(&db, &pkg, false, &mut vec!["foo".to_owned()])
+            .unwrap_err();
+        assert!(matches!(err, PayloadError::InvalidDependency { .. }));
+    }
 
-        Payload::download_source("https://github.com/topgrade-rs/topgrade/releases/download/v12.0.2/topgrade-v12.0.2-x86_64-apple-darwin.tar.gz", &path, None).await.unwrap();
+    #[test]
+    fn test_empty_install() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let binaries = tmpdir.path();
+        let mut pkgfile: PackageFile = toml::from_str(
+            "
+                [package]
+                name = \"noop\"
+                license = \"MIT\"
+                version = \"1.0.0\"
+
+                [source]
+                url = \"https://example.com/noop.tar.gz\"
+                install = \"true\"
+            ",
+        )
+        .unwrap();
+
+        let before = Payload::snapshot_dir(binaries).unwrap();
+        let status = Payload::run_command(
+            &pkgfile.source.install,
+            [("binary", binaries)],
+            "noop",
+            &OutputSink::Log,
+        )
+        .unwrap()
+        .status;
+        assert!(status.success());
+        let after = Payload::snapshot_dir(binaries).unwrap();
+        let installed = Payload::changed_files(&before, &after);
+
+        let message = Payload::empty_install_message(&pkgfile, &installed);
+        assert_eq!(
+            message.as_deref(),
+            Some("Installing package noop didn't produce any files!")
+        );
+
+        pkgfile.source.install = "touch ${binary}/noop".to_owned();
+        let status = Payload::run_command(
+            &pkgfile.source.install,
+            [("binary", binaries)],
+            "noop",
+            &OutputSink::Log,
+        )
+        .unwrap()
+        .status;
+        assert!(status.success());
+        let after = Payload::snapshot_dir(binaries).unwrap();
+        let installed = Payload::changed_files(&before, &after);
+        assert!(Payload::empty_install_message(&pkgfile, &installed).is_none());
+    }
+
+    #[test]
+    fn test_file_conflicts() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = Database::create(tmpdir.path().join("packages.db")).unwrap();
+        db.init_table(INSTALLED_PKGS).unwrap();
+        let binaries = tmpdir.path().join("bin");
+        let staging = tmpdir.path().join("staging");
+        fs::create_dir_all(staging.join("share")).unwrap();
+        fs::write(staging.join("foo"), "new").unwrap();
+        fs::write(staging.join("share/bar"), "new").unwrap();
+
+        let mut installed = Package::from_file(
+            test_pkgfile("other").build().unwrap(),
+            Local {
+                installed: Installed::Manually(Version::from_str("1.0.0").unwrap()),
+                ..Default::default()
+            },
+        );
+        installed.local.files = vec![binaries.join("foo")];
+        db.set(INSTALLED_PKGS, "other", installed).unwrap();
+
+        let staged = [PathBuf::from("foo"), PathBuf::from("share/bar")];
+        let installing = HashMap::from([("third".to_owned(), vec![binaries.join("share/bar")])]);
+        assert_eq!(
+            Payload::file_conflicts(&db, "pkg", &binaries, &staged, &installing).unwrap(),
+            [
+                (Some("other".to_owned()), PathBuf::from("foo")),
+                (Some("third".to_owned()), PathBuf::from("share/bar")),
+            ]
+        );
+        // Existing files nobody owns conflict as well.
+        fs::create_dir_all(binaries.join("share")).unwrap();
+        fs::write(binaries.join("share/bar"), "old").unwrap();
+        assert_eq!(
+            Payload::file_conflicts(&db, "pkg", &binaries, &staged, &HashMap::new()).unwrap(),
+            [
+                (Some("other".to_owned()), PathBuf::from("foo")),
+                (None, PathBuf::from("share/bar")),
+            ]
+        );
+        // Reinstalling a package doesn't conflict with itself.
         assert!(
-            Payload::check_sha512(
-                path, "45dfddf13e8f5a5eb4a95dde6743f42f216ed6d3751d7430dae5f9e0dc54e67a400e6572789fb9984ff1c80bdee42a92112a76d5399436e857e723b653b366f1"
-            ).unwrap()
+            Payload::file_conflicts(&db, "other", &binaries, &staged[..1], &HashMap::new())
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(
+            PayloadError::FileConflict {
+                package: "pkg".to_owned(),
+                other: "other".to_owned(),
+                file: PathBuf::from("foo"),
+            }
+            .to_string(),
+            "package pkg and package other both install foo"
+        );
+
+        Payload::move_staged(&staging, &binaries, &staged).unwrap();
+        assert_eq!(
+            fs::read_to_string(binaries.join("share/bar")).unwrap(),
+            "new"
+        );
+        assert!(!staging.join("foo").exists());
+    }
+
+    #[test]
+    fn test_reinstall_keeps_config() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let db = Database::create(tmpdir.path().join("packages.db")).unwrap();
+        db.init_table(ALL_PKGS).unwrap();
+        db.init_table(INSTALLED_PKGS).unwrap();
+        db.init_table(PKG_VERSIONS).unwrap();
+        let conf = Config {
+            directories: ConfigDirs {
+                sources: tmpdir.path().join("sources"),
+                builds: tmpdir.path().join("builds"),
+                binaries: tmpdir.path().join("binaries"),
+                packages: tmpdir.path().to_owned(),
+            },
+            ..Default::default()
+        };
+        let binaries = conf.binaries_path();
+        fs::create_dir_all(&binaries).unwrap();
+
+        let file = test_pkgfile("foo").config_file("foo.toml").build().unwrap();
+        let files = vec![binaries.join("foo"), binaries.join("foo.toml")];
+        for file in &files {
+            fs::write(file, "default").unwrap();
+        }
+        let local = Local {
+            installed: Installed::Manually(Version::from_str("1.0.0").unwrap()),
+            files: files.clone(),
+            ..Default::default()
+        };
+        let pkg = Package::from_file(file.clone(), local);
+        db.set(ALL_PKGS, "foo", pkg.clone()).unwrap();
+        db.set(INSTALLED_PKGS, "foo", pkg).unwrap();
+
+        fs::write(binaries.join("foo.toml"), "edited").unwrap();
+        crate::remove_pkg(&db, &conf, "foo", false).unwrap();
+        assert!(!binaries.join("foo").exists());
+
+        // Reinstalling keeps the edited config file instead of failing on or overwriting it.
+        let staging = conf.staging_path(&file.info);
+        fs::create_dir_all(&staging).unwrap();
+        fs::write(staging.join("foo"), "default").unwrap();
+        fs::write(staging.join("foo.toml"), "default").unwrap();
+        let installed = Payload::new()
+            .install_staged(&db, &file, &staging, &binaries, &HashMap::new())
+            .unwrap();
+        assert_eq!(installed, files);
+        assert_eq!(
+            fs::read_to_string(binaries.join("foo.toml")).unwrap(),
+            "edited"
+        );
+        assert!(binaries.join("foo").is_file());
+        assert!(!staging.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_move_staged_symlinks() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let binaries = tmpdir.path().join("bin");
+        let staging = tmpdir.path().join("staging");
+        fs::create_dir_all(&staging).unwrap();
+        fs::write(staging.join("foo"), "foo").unwrap();
+        std::os::unix::fs::symlink(staging.join("foo"), staging.join("absolute")).unwrap();
+        std::os::unix::fs::symlink("foo", staging.join("relative")).unwrap();
+
+        let staged = [
+            PathBuf::from("absolute"),
+            PathBuf::from("foo"),
+            PathBuf::from("relative"),
+        ];
+        Payload::move_staged(&staging, &binaries, &staged).unwrap();
+        fs::remove_dir_all(&staging).unwrap();
+
+        assert_eq!(
+            fs::read_link(binaries.join("absolute")).unwrap(),
+            binaries.join("foo")
+        );
+        assert_eq!(
+            fs::read_link(binaries.join("relative")).unwrap(),
+            PathBuf::from("foo")
+        );
+        assert_eq!(
+            fs::read_to_string(binaries.join("absolute")).unwrap(),
+            "foo"
         );
     }
 
@@ -514,6 +4155,7 @@ mod tests {
                     binaries: tmpdir.path().join("binaries"),
                     packages: tmpdir.path().to_owned(),
                 },
+                ..Default::default()
             })
             .unwrap();
         let db_path = CONFIG
@@ -526,6 +4168,7 @@ mod tests {
         let db = DB.get().unwrap();
         db.init_table(ALL_PKGS).unwrap();
         db.init_table(INSTALLED_PKGS).unwrap();
+        crate::init_tables(db).unwrap();
 
         let topgrade = Package {
                         info: PackageInfo {
@@ -541,17 +4184,31 @@ mod tests {
                         },
                         source: Source {
                             url: "https://github.com/topgrade-rs/topgrade/releases/download/v12.0.2/topgrade-v12.0.2-x86_64-apple-darwin.tar.gz".to_owned(),
+                            vcs: None,
+                            rev: None,
+                            mirrors: None,
                             checksum: None,
+                            signature: None,
+                            signing_key: None,
+                            strip_components: None,
+                            patches: None,
+                            prepare: None,
                             build: None,
                             install: "mv ${source}/topgrade ${binary}".to_owned(),
+                            post_install: None,
+                            uninstall: None,
+                            executable: None,
+                            config_files: None,
+                            env: None,
                         },
-                        local: Local { installed: Installed::False, added: false}
+                        local: Local::default()
                     };
 
         db.set(ALL_PKGS, "topgrade", topgrade.clone()).unwrap();
 
         let mut payload = Payload::new();
-        payload.add_pkg("topgrade").unwrap();
+        payload.trust = true;
+        payload.add_pkg(db, "topgrade").unwrap();
         payload.install().await.unwrap();
 
         let topgrade_table = db.get(ALL_PKGS, "topgrade").unwrap().unwrap();
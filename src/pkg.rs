@@ -1,5 +1,9 @@
-use semver::Version;
-use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Deserializer, Serialize};
 
 use crate::pkgfile::PackageFile;
 
@@ -34,6 +38,56 @@ impl From<Package> for PackageFile {
     }
 }
 
+/// Split a package specification `name@requirement` (e.g. `foo@>=1.2, <2`) into the name and the version requirement.
+/// A bare version (e.g. `foo@1.2.0`) requires exactly this version.
+pub fn parse_spec(spec: &str) -> Result<(&str, Option<VersionReq>), semver::Error> {
+    match spec.split_once('@') {
+        Some((name, req)) => match Version::parse(req.trim()) {
+            Ok(version) => Ok((name, Some(VersionReq::parse(&format!("={version}"))?))),
+            Err(_) => Ok((name, Some(VersionReq::parse(req)?))),
+        },
+        None => Ok((spec, None)),
+    }
+}
+
+/// Select the newest of the `available` versions matching `req`.
+pub fn select_version<'a>(available: &'a [Version], req: &VersionReq) -> Option<&'a Version> {
+    available.iter().filter(|ver| req.matches(ver)).max()
+}
+
+/// A dependency on another package, e.g. `openssl >=1.1, <2`.
+/// A bare name depends on any version.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dependency {
+    pub name: String,
+    pub req: VersionReq,
+}
+
+impl Dependency {
+    /// Get the name of the dependency `dep` without parsing the version requirement.
+    pub fn name_of(dep: &str) -> &str {
+        let dep = dep.trim();
+        dep.split_once(char::is_whitespace)
+            .map_or(dep, |(name, _)| name)
+    }
+}
+
+impl FromStr for Dependency {
+    type Err = semver::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let req = match s.split_once(char::is_whitespace) {
+            Some((_, req)) => VersionReq::parse(req.trim())?,
+            None => VersionReq::STAR,
+        };
+        Ok(Self {
+            name: Dependency::name_of(s).to_owned(),
+            req,
+        })
+    }
+}
+
 /// General info of a package.
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub struct PackageInfo {
@@ -43,18 +97,138 @@ pub struct PackageInfo {
     pub repository: Option<String>,
     pub authors: Option<Vec<String>>,
     pub description: Option<String>,
+    /// Runtime dependencies, each a name optionally followed by a version requirement (see [`Dependency`]).
     pub dependencies: Option<Vec<String>>,
     pub build_dependencies: Option<Vec<String>>,
-    pub provides: Option<String>,
+    /// Virtual packages this package provides. Pkgfiles may also give a single name.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub provides: Option<Vec<String>>,
+}
+
+impl PackageInfo {
+    /// Whether this package provides the virtual package `name`.
+    pub fn provides(&self, name: &str) -> bool {
+        self.provides
+            .iter()
+            .flatten()
+            .any(|provided| provided == name)
+    }
+}
+
+/// Deserialize a single string or a list of strings.
+fn one_or_many<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(
+        Option::<OneOrMany>::deserialize(deserializer)?.map(|provides| match provides {
+            OneOrMany::One(name) => vec![name],
+            OneOrMany::Many(names) => names,
+        }),
+    )
 }
 
 /// General info of a package.
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub struct Source {
+    /// URL of the tarball, or of the repository if `vcs` is set.
     pub url: String,
+    /// Version control system `url` is a repository of. It is cloned instead of downloading a tarball.
+    pub vcs: Option<Vcs>,
+    /// Revision (commit, tag or branch) checked out of the repository. Defaults to its default branch.
+    /// It takes the place of the checksum, which isn't checked for repositories.
+    pub rev: Option<String>,
+    /// Alternative URLs of the tarball, tried in order if downloading from `url` fails.
+    pub mirrors: Option<Vec<String>>,
+    /// Checksum of the tarball, optionally prefixed with the algorithm (`sha256:` or `sha512:`, the default).
     pub checksum: Option<String>,
+    /// Detached minisign signature of the tarball, either a URL or the signature itself.
+    /// It is only checked if mercurium is built with the `verify-signatures` feature.
+    pub signature: Option<String>,
+    /// Base64 minisign public key the signature is verified with.
+    pub signing_key: Option<String>,
+    /// Number of leading path components stripped from the entries of the tarball when extracting it.
+    /// By default, a single top-level directory containing everything (like `name-version/`) is stripped.
+    pub strip_components: Option<usize>,
+    /// Patches applied to the decompressed source with `patch -p1`, in order.
+    /// Each one is a URL (downloaded like the tarball) or a local path.
+    pub patches: Option<Vec<String>>,
+    /// Script run in the `${source}` directory after applying the patches and before building.
+    pub prepare: Option<String>,
+    /// Script run in the `${source}` directory, like `prepare`.
     pub build: Option<String>,
+    /// Script installing the package into `${binary}`. That is a staging directory whose files are moved to the
+    /// binaries directory afterwards, so paths into it shouldn't be written to files. Symlinks into it are
+    /// pointed to the moved files.
     pub install: String,
+    /// Command run after the package is installed, e.g. to regenerate caches or print a message.
+    /// `${binary}` is the binaries directory then. If it fails, only a warning is printed unless `strict_post_install` is set in the config.
+    pub post_install: Option<String>,
+    /// Cleanup steps run when the package is removed.
+    pub uninstall: Option<String>,
+    /// Files (relative to `${binary}`) that should be marked executable after installing.
+    /// If not set, installed files are checked for native executable headers instead.
+    pub executable: Option<Vec<String>>,
+    /// Config files installed by the package (relative to `${binary}` or absolute).
+    /// They are kept on removal unless purging.
+    pub config_files: Option<Vec<String>>,
+    /// Environment variables passed to the build and install commands.
+    /// They override the ones from the config, except for the reserved `source`, `binary` and `jobs`.
+    pub env: Option<BTreeMap<String, String>>,
+}
+
+/// A version control system a package can be cloned with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Vcs {
+    Git,
+}
+
+impl Source {
+    /// Whether `location` (of a tarball or patch) is a URL rather than a local path.
+    pub fn is_remote(location: &str) -> bool {
+        location.starts_with("http://") || location.starts_with("https://")
+    }
+
+    /// Get everything the package runs, labelled and in the order it is used: the environment
+    /// variables, the patches and the commands.
+    pub fn commands(&self) -> Vec<(&'static str, String)> {
+        let mut commands = Vec::new();
+        for (key, value) in self.env.iter().flatten() {
+            commands.push(("Environment variable", format!("{key}={value}")));
+        }
+        for patch in self.patches.iter().flatten() {
+            commands.push(("Patch", patch.clone()));
+        }
+        for (label, command) in [
+            ("Prepare command", &self.prepare),
+            ("Build command", &self.build),
+            ("Install command", &Some(self.install.clone())),
+            ("Post-install command", &self.post_install),
+            ("Uninstall command", &self.uninstall),
+        ] {
+            if let Some(command) = command {
+                commands.push((label, command.clone()));
+            }
+        }
+        commands
+    }
+
+    /// Get the paths of the declared config files. Relative paths are resolved against `binaries`.
+    pub fn config_paths(&self, binaries: impl AsRef<Path>) -> Vec<PathBuf> {
+        self.config_files
+            .iter()
+            .flatten()
+            .map(|file| binaries.as_ref().join(file))
+            .collect()
+    }
 }
 
 /// Info on the local installation of the package.
@@ -64,6 +238,29 @@ pub struct Local {
     pub installed: Installed,
     /// Whether a package was manually added from a package file.
     pub added: bool,
+    /// The files installed by the package.
+    pub files: Vec<PathBuf>,
+    /// The installation state before the package was last removed.
+    /// A reinstall restores whether it was installed manually or automatically.
+    pub last_installed: Installed,
+    /// When the package was installed (seconds since the Unix epoch), if it is installed.
+    pub installed_at: Option<i64>,
+    /// Whether the package is pinned, i.e. excluded from updates.
+    pub pinned: bool,
+}
+
+/// A package that is neither installed, added nor pinned.
+impl Default for Local {
+    fn default() -> Self {
+        Self {
+            installed: Installed::False,
+            added: false,
+            files: Vec::new(),
+            last_installed: Installed::False,
+            installed_at: None,
+            pinned: false,
+        }
+    }
 }
 
 /// Whether a package is installed and if it's the case, whether manually or automatically.
@@ -91,6 +288,22 @@ impl Installed {
         }
     }
 
+    /// Reinstall a removed package that was installed as `self` with the `new` state.
+    /// The version is taken from `new`, but whether it is installed manually or automatically is restored.
+    pub fn restore(self, new: Installed) -> Installed {
+        match (self, new) {
+            (_, Installed::False) => Installed::False,
+            (Installed::False, new) => new,
+            (
+                Installed::Automatically(_),
+                Installed::Automatically(ver) | Installed::Manually(ver),
+            ) => Installed::Automatically(ver),
+            (Installed::Manually(_), Installed::Automatically(ver) | Installed::Manually(ver)) => {
+                Installed::Manually(ver)
+            }
+        }
+    }
+
     /// Get the version of the package if installed.
     pub fn version(&self) -> Option<&Version> {
         match &self {
@@ -108,3 +321,94 @@ impl From<Installed> for bool {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_spec() {
+        assert_eq!(parse_spec("foo").unwrap(), ("foo", None));
+
+        let (name, req) = parse_spec("foo@>=1.2, <2").unwrap();
+        assert_eq!(name, "foo");
+        assert_eq!(req, Some(VersionReq::parse(">=1.2, <2").unwrap()));
+
+        let (_, req) = parse_spec("foo@11.0.0").unwrap();
+        let req = req.unwrap();
+        assert!(req.matches(&Version::from_str("11.0.0").unwrap()));
+        assert!(!req.matches(&Version::from_str("11.1.0").unwrap()));
+
+        assert!(parse_spec("foo@not a version").is_err());
+    }
+
+    #[test]
+    fn test_select_version() {
+        let available: Vec<Version> = ["1.1.0", "1.2.0", "1.9.3", "2.0.0"]
+            .into_iter()
+            .map(|ver| Version::from_str(ver).unwrap())
+            .collect();
+
+        let req = VersionReq::parse(">=1.2, <2").unwrap();
+        assert_eq!(
+            select_version(&available, &req),
+            Some(&Version::from_str("1.9.3").unwrap())
+        );
+
+        let req = VersionReq::parse(">=3").unwrap();
+        assert_eq!(select_version(&available, &req), None);
+    }
+
+    #[test]
+    fn test_dependency() {
+        assert_eq!(
+            Dependency::from_str("openssl").unwrap(),
+            Dependency {
+                name: "openssl".to_owned(),
+                req: VersionReq::STAR,
+            }
+        );
+
+        let dep = Dependency::from_str("openssl >=1.1, <2").unwrap();
+        assert_eq!(dep.name, "openssl");
+        assert!(dep.req.matches(&Version::from_str("1.1.1").unwrap()));
+        assert!(!dep.req.matches(&Version::from_str("3.0.0").unwrap()));
+        assert_eq!(Dependency::name_of("openssl >=1.1, <2"), "openssl");
+
+        assert!(Dependency::from_str("openssl not a version").is_err());
+    }
+
+    #[test]
+    fn test_provides() {
+        let info = |provides: &str| -> PackageInfo {
+            toml::from_str(&format!(
+                "name = \"mawk\"\nversion = \"1.3.4\"\nlicense = \"GPL\"\n{provides}"
+            ))
+            .unwrap()
+        };
+
+        let single = info("provides = \"awk\"");
+        assert_eq!(single.provides, Some(vec!["awk".to_owned()]));
+        let list = info("provides = [\"awk\", \"nawk\"]");
+        assert!(list.provides("awk") && list.provides("nawk"));
+        assert!(!list.provides("mawk"));
+        assert_eq!(info("").provides, None);
+    }
+
+    #[test]
+    fn test_commands() {
+        let source: Source = toml::from_str(
+            "url = \"https://example.com/foo.tar.gz\"\npatches = [\"fix.patch\"]\nbuild = \"make\"\ninstall = \"make install\"\nuninstall = \"rm -r cache\"",
+        )
+        .unwrap();
+        assert_eq!(
+            source.commands(),
+            [
+                ("Patch", "fix.patch".to_owned()),
+                ("Build command", "make".to_owned()),
+                ("Install command", "make install".to_owned()),
+                ("Uninstall command", "rm -r cache".to_owned()),
+            ]
+        );
+    }
+}
@@ -1,8 +1,11 @@
+use redb::{Database, WriteTransaction};
+use semver::Version;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
-use crate::db::Db;
-use crate::pkg::{Installed, Local, Package, PackageInfo, Source};
-use crate::{DynResult, ALL_PKGS, DB};
+use crate::db::{version_key, DbPackage};
+use crate::pkg::{Local, Package, PackageInfo, Source, Vcs};
+use crate::{DynResult, ALL_PKGS, PKG_VERSIONS};
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub struct PackageFile {
@@ -12,40 +15,313 @@ pub struct PackageFile {
 }
 
 impl PackageFile {
+    /// Start building a package file programmatically.
+    pub fn builder() -> PackageFileBuilder {
+        PackageFileBuilder::default()
+    }
+
+    /// Serialize the package file into the TOML pkgfile format.
+    pub fn to_toml(&self) -> Result<String, toml::ser::Error> {
+        toml::to_string(self)
+    }
+
     /// Adds the package file to the database.
     ///
     /// The package is marked as `added`. If it is not already in the database, it is also markes as not installed.
-    pub fn add_to_db(self) -> DynResult<()> {
-        let db = DB.get().unwrap();
-        let name = self.info.name.clone();
-
-        db.modify(ALL_PKGS, name.as_str(), |pkg| {
-            let local = match pkg {
-                Some(pkg) => {
-                    let mut local = pkg.local;
-                    local.added = true;
-                    local
-                }
-                None => Local {
-                    installed: Installed::False,
+    /// Every version is kept in `PKG_VERSIONS`, but `ALL_PKGS` keeps a newer version if it already has one.
+    pub fn add_to_db(self, db: &Database) -> DynResult<()> {
+        let write_txn = db.begin_write()?;
+        self.add_in(&write_txn)?;
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    /// Adds the package file to the database as part of `write_txn`, see [`PackageFile::add_to_db`].
+    pub fn add_in(&self, write_txn: &WriteTransaction) -> Result<(), redb::Error> {
+        let name = self.info.name.as_str();
+        let not_installed = Local {
+            added: true,
+            ..Default::default()
+        };
+
+        let mut all_table = write_txn.open_table(ALL_PKGS)?;
+        let existing = all_table.get(name)?.map(|pkg| pkg.value());
+        let pkg = match existing.map(Package::try_from).transpose()? {
+            Some(mut pkg) if pkg.info.version > self.info.version => {
+                pkg.local.added = true;
+                pkg
+            }
+            Some(pkg) => Package::from_file(
+                self.clone(),
+                Local {
                     added: true,
+                    ..pkg.local
                 },
-            };
+            ),
+            None => Package::from_file(self.clone(), not_installed.clone()),
+        };
+        all_table.insert(name, DbPackage::from(pkg))?;
 
-            Some(Package::from_file(self, local))
-        })?;
+        let mut versions_table = write_txn.open_table(PKG_VERSIONS)?;
+        let key = version_key(name, &self.info.version);
+        let existing = versions_table.get(key.as_str())?.map(|pkg| pkg.value());
+        let local = existing
+            .map(Package::try_from)
+            .transpose()?
+            .map_or(not_installed, |pkg| pkg.local);
+        versions_table.insert(
+            key.as_str(),
+            DbPackage::from(Package::from_file(self.clone(), local)),
+        )?;
 
         Ok(())
     }
 }
 
+/// Error building a [`PackageFile`].
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum BuilderError {
+    #[error("missing required field `{0}`")]
+    MissingField(&'static str),
+    #[error("field `{0}` must not be empty")]
+    EmptyField(&'static str),
+}
+
+/// Builder for a [`PackageFile`].
+///
+/// The fields `name`, `version`, `license`, `url` and `install` are required.
+#[derive(Clone, Debug, Default)]
+pub struct PackageFileBuilder {
+    name: Option<String>,
+    version: Option<Version>,
+    license: Option<String>,
+    repository: Option<String>,
+    authors: Option<Vec<String>>,
+    description: Option<String>,
+    dependencies: Option<Vec<String>>,
+    build_dependencies: Option<Vec<String>>,
+    provides: Option<Vec<String>>,
+    url: Option<String>,
+    vcs: Option<Vcs>,
+    rev: Option<String>,
+    mirrors: Option<Vec<String>>,
+    checksum: Option<String>,
+    signature: Option<String>,
+    signing_key: Option<String>,
+    strip_components: Option<usize>,
+    patches: Option<Vec<String>>,
+    prepare: Option<String>,
+    build: Option<String>,
+    install: Option<String>,
+    post_install: Option<String>,
+    uninstall: Option<String>,
+    executable: Option<Vec<String>>,
+    config_files: Option<Vec<String>>,
+}
+
+// Not every setter is used by mercurium itself.
+#[allow(dead_code)]
+impl PackageFileBuilder {
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn version(mut self, version: Version) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    pub fn license(mut self, license: impl Into<String>) -> Self {
+        self.license = Some(license.into());
+        self
+    }
+
+    pub fn repository(mut self, repository: impl Into<String>) -> Self {
+        self.repository = Some(repository.into());
+        self
+    }
+
+    /// Add an author.
+    pub fn author(mut self, author: impl Into<String>) -> Self {
+        self.authors
+            .get_or_insert_with(Vec::new)
+            .push(author.into());
+        self
+    }
+
+    pub fn description(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    /// Add a dependency, optionally followed by a version requirement (e.g. `openssl >=1.1, <2`).
+    pub fn dependency(mut self, dependency: impl Into<String>) -> Self {
+        self.dependencies
+            .get_or_insert_with(Vec::new)
+            .push(dependency.into());
+        self
+    }
+
+    /// Add a build dependency.
+    pub fn build_dependency(mut self, dependency: impl Into<String>) -> Self {
+        self.build_dependencies
+            .get_or_insert_with(Vec::new)
+            .push(dependency.into());
+        self
+    }
+
+    /// Add a virtual package the package provides.
+    pub fn provides(mut self, provides: impl Into<String>) -> Self {
+        self.provides
+            .get_or_insert_with(Vec::new)
+            .push(provides.into());
+        self
+    }
+
+    pub fn url(mut self, url: impl Into<String>) -> Self {
+        self.url = Some(url.into());
+        self
+    }
+
+    /// Clone `url` with `vcs` instead of downloading it as a tarball.
+    pub fn vcs(mut self, vcs: Vcs) -> Self {
+        self.vcs = Some(vcs);
+        self
+    }
+
+    /// Revision of the repository to check out.
+    pub fn rev(mut self, rev: impl Into<String>) -> Self {
+        self.rev = Some(rev.into());
+        self
+    }
+
+    /// Add a mirror of the tarball.
+    pub fn mirror(mut self, url: impl Into<String>) -> Self {
+        self.mirrors.get_or_insert_with(Vec::new).push(url.into());
+        self
+    }
+
+    pub fn checksum(mut self, checksum: impl Into<String>) -> Self {
+        self.checksum = Some(checksum.into());
+        self
+    }
+
+    /// Set the detached minisign signature of the tarball (URL or the signature itself).
+    pub fn signature(mut self, signature: impl Into<String>) -> Self {
+        self.signature = Some(signature.into());
+        self
+    }
+
+    pub fn signing_key(mut self, key: impl Into<String>) -> Self {
+        self.signing_key = Some(key.into());
+        self
+    }
+
+    /// Set the number of leading path components stripped from the tarball entries (`0` to keep them).
+    pub fn strip_components(mut self, strip_components: usize) -> Self {
+        self.strip_components = Some(strip_components);
+        self
+    }
+
+    /// Add a patch (URL or local path) to apply to the source.
+    pub fn patch(mut self, patch: impl Into<String>) -> Self {
+        self.patches.get_or_insert_with(Vec::new).push(patch.into());
+        self
+    }
+
+    pub fn prepare_command(mut self, prepare: impl Into<String>) -> Self {
+        self.prepare = Some(prepare.into());
+        self
+    }
+
+    pub fn build_command(mut self, build: impl Into<String>) -> Self {
+        self.build = Some(build.into());
+        self
+    }
+
+    pub fn install_command(mut self, install: impl Into<String>) -> Self {
+        self.install = Some(install.into());
+        self
+    }
+
+    pub fn post_install_command(mut self, post_install: impl Into<String>) -> Self {
+        self.post_install = Some(post_install.into());
+        self
+    }
+
+    pub fn uninstall_command(mut self, uninstall: impl Into<String>) -> Self {
+        self.uninstall = Some(uninstall.into());
+        self
+    }
+
+    /// Add a file (relative to `${binary}`) to mark as executable.
+    pub fn executable(mut self, file: impl Into<String>) -> Self {
+        self.executable
+            .get_or_insert_with(Vec::new)
+            .push(file.into());
+        self
+    }
+
+    /// Add a config file.
+    pub fn config_file(mut self, file: impl Into<String>) -> Self {
+        self.config_files
+            .get_or_insert_with(Vec::new)
+            .push(file.into());
+        self
+    }
+
+    /// Validate the fields and build the package file.
+    pub fn build(self) -> Result<PackageFile, BuilderError> {
+        fn required(field: Option<String>, name: &'static str) -> Result<String, BuilderError> {
+            match field {
+                Some(field) if field.trim().is_empty() => Err(BuilderError::EmptyField(name)),
+                Some(field) => Ok(field),
+                None => Err(BuilderError::MissingField(name)),
+            }
+        }
+
+        Ok(PackageFile {
+            info: PackageInfo {
+                name: required(self.name, "name")?,
+                version: self.version.ok_or(BuilderError::MissingField("version"))?,
+                license: required(self.license, "license")?,
+                repository: self.repository,
+                authors: self.authors,
+                description: self.description,
+                dependencies: self.dependencies,
+                build_dependencies: self.build_dependencies,
+                provides: self.provides,
+            },
+            source: Source {
+                url: required(self.url, "url")?,
+                vcs: self.vcs,
+                rev: self.rev,
+                mirrors: self.mirrors,
+                checksum: self.checksum,
+                signature: self.signature,
+                signing_key: self.signing_key,
+                strip_components: self.strip_components,
+                patches: self.patches,
+                prepare: self.prepare,
+                build: self.build,
+                install: required(self.install, "install")?,
+                post_install: self.post_install,
+                uninstall: self.uninstall,
+                executable: self.executable,
+                config_files: self.config_files,
+                env: None,
+            },
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs;
     use std::str::FromStr;
 
-    use semver::Version;
-
     use super::*;
 
     #[test]
@@ -92,12 +368,56 @@ mod tests {
                         },
                         source: Source {
                             url: "https://github.com/topgrade-rs/topgrade/releases/download/v12.0.2/topgrade-v12.0.2-x86_64-apple-darwin.tar.gz".to_owned(),
+                            vcs: None,
+                            rev: None,
+                            mirrors: None,
                             checksum: Some("45dfddf13e8f5a5eb4a95dde6743f42f216ed6d3751d7430dae5f9e0dc54e67a400e6572789fb9984ff1c80bdee42a92112a76d5399436e857e723b653b366f1".to_owned()),
+                            signature: None,
+                            signing_key: None,
+                            strip_components: None,
+                            patches: None,
+                            prepare: None,
                             build: None,
                             install: "mv ${source}/topgrade ${binary}".to_owned(),
+                            post_install: None,
+                            uninstall: None,
+                            executable: None,
+                            config_files: None,
+                            env: None,
                         },
                     };
 
         assert_eq!(file, local);
     }
+
+    #[test]
+    fn builder_roundtrip() {
+        let pkgfile = PackageFile::builder()
+            .name("topgrade")
+            .version(Version::from_str("12.0.2").unwrap())
+            .license("GPL-3.0")
+            .author("topgrade-rs")
+            .description("Upgrade all the things")
+            .url("https://github.com/topgrade-rs/topgrade/releases/download/v12.0.2/topgrade-v12.0.2-x86_64-apple-darwin.tar.gz")
+            .install_command("mv ${source}/topgrade ${binary}")
+            .executable("topgrade")
+            .build()
+            .unwrap();
+
+        let toml = pkgfile.to_toml().unwrap();
+        let parsed: PackageFile = toml::from_str(&toml).unwrap();
+        assert_eq!(parsed, pkgfile);
+    }
+
+    #[test]
+    fn builder_missing_field() {
+        let err = PackageFile::builder()
+            .name("topgrade")
+            .version(Version::from_str("12.0.2").unwrap())
+            .license("GPL-3.0")
+            .install_command("mv ${source}/topgrade ${binary}")
+            .build()
+            .unwrap_err();
+        assert_eq!(err, BuilderError::MissingField("url"));
+    }
 }